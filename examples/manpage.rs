@@ -1,5 +1,4 @@
 //! Port of the example from the `userfaultfd` manpage.
-use libc::{self, c_void};
 use nix::poll::{poll, PollFd, PollFlags};
 use nix::sys::mman::{mmap, MapFlags, ProtFlags};
 use nix::unistd::{sysconf, SysconfVar};
@@ -63,7 +62,7 @@ fn fault_handler_thread(uffd: Uffd) {
             }
             fault_cnt += 1;
 
-            let dst = (addr as usize & !(page_size - 1)) as *mut c_void;
+            let dst = addr.align_down(page_size).as_ptr();
             let copy = unsafe { uffd.copy(page, dst, page_size, true).expect("uffd copy") };
 
             println!("        (uffdio_copy.copy returned {})", copy);
@@ -0,0 +1,160 @@
+use libc::c_void;
+use std::fmt;
+use std::ops::{Add, Sub};
+
+/// An address in the memory of the process whose faults are handled, as reported by events.
+///
+/// Unlike a raw pointer, an address is `Send` and `Sync`, so events can be passed to other
+/// threads, and it can be compared, ordered and hashed. Use [`as_ptr`](UffdAddr::as_ptr) to pass
+/// it to the methods of [`Uffd`](crate::Uffd).
+#[derive(Clone, Copy, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(transparent)
+)]
+#[repr(transparent)]
+pub struct UffdAddr(usize);
+
+impl UffdAddr {
+    /// Create an address from an integer.
+    pub const fn new(addr: usize) -> Self {
+        UffdAddr(addr)
+    }
+
+    /// Create an address from a pointer.
+    pub fn from_ptr<T>(ptr: *const T) -> Self {
+        UffdAddr(ptr as usize)
+    }
+
+    /// The address as an integer.
+    pub const fn as_usize(self) -> usize {
+        self.0
+    }
+
+    /// The address as a pointer.
+    pub fn as_ptr(self) -> *mut c_void {
+        self.0 as *mut c_void
+    }
+
+    /// The start of the page containing this address. `page_size` must be a power of two.
+    pub const fn align_down(self, page_size: usize) -> Self {
+        UffdAddr(self.0 & !(page_size - 1))
+    }
+
+    /// The start of the first page at or after this address. `page_size` must be a power of two.
+    pub const fn align_up(self, page_size: usize) -> Self {
+        UffdAddr((self.0 + page_size - 1) & !(page_size - 1))
+    }
+
+    /// Whether this address is at the start of a page. `page_size` must be a power of two.
+    pub const fn is_aligned(self, page_size: usize) -> bool {
+        self.0 & (page_size - 1) == 0
+    }
+
+    /// The offset of this address within its page. `page_size` must be a power of two.
+    pub const fn page_offset(self, page_size: usize) -> usize {
+        self.0 & (page_size - 1)
+    }
+
+    /// The number of bytes from `base` to this address, or `None` if this address is below
+    /// `base`.
+    pub const fn checked_offset_from(self, base: UffdAddr) -> Option<usize> {
+        self.0.checked_sub(base.0)
+    }
+
+    /// The index of the page containing this address, counting pages of `page_size` bytes from
+    /// `base`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if this address is below `base`.
+    pub fn page_index(self, base: UffdAddr, page_size: usize) -> usize {
+        (self - base) / page_size
+    }
+}
+
+impl fmt::Debug for UffdAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "UffdAddr({:#x})", self.0)
+    }
+}
+
+impl fmt::LowerHex for UffdAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::LowerHex::fmt(&self.0, f)
+    }
+}
+
+impl From<usize> for UffdAddr {
+    fn from(addr: usize) -> Self {
+        UffdAddr(addr)
+    }
+}
+
+impl From<UffdAddr> for usize {
+    fn from(addr: UffdAddr) -> Self {
+        addr.0
+    }
+}
+
+impl From<*mut c_void> for UffdAddr {
+    fn from(ptr: *mut c_void) -> Self {
+        UffdAddr::from_ptr(ptr)
+    }
+}
+
+impl Add<usize> for UffdAddr {
+    type Output = UffdAddr;
+
+    fn add(self, bytes: usize) -> UffdAddr {
+        UffdAddr(self.0 + bytes)
+    }
+}
+
+impl Sub<usize> for UffdAddr {
+    type Output = UffdAddr;
+
+    fn sub(self, bytes: usize) -> UffdAddr {
+        UffdAddr(self.0 - bytes)
+    }
+}
+
+/// The number of bytes between two addresses.
+impl Sub for UffdAddr {
+    type Output = usize;
+
+    fn sub(self, base: UffdAddr) -> usize {
+        self.0 - base.0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Event;
+
+    #[test]
+    fn test_page_arithmetic() {
+        const PAGE_SIZE: usize = 4096;
+        let base = UffdAddr::new(0x10_0000);
+        let addr = base + 2 * PAGE_SIZE + 8;
+
+        assert_eq!(addr.align_down(PAGE_SIZE), base + 2 * PAGE_SIZE);
+        assert_eq!(addr.align_up(PAGE_SIZE), base + 3 * PAGE_SIZE);
+        assert_eq!(base.align_up(PAGE_SIZE), base);
+        assert!(base.is_aligned(PAGE_SIZE) && !addr.is_aligned(PAGE_SIZE));
+        assert_eq!(addr.page_offset(PAGE_SIZE), 8);
+        assert_eq!(addr.page_index(base, PAGE_SIZE), 2);
+        assert_eq!(addr - base, 2 * PAGE_SIZE + 8);
+        assert_eq!(base.checked_offset_from(addr), None);
+        assert_eq!(UffdAddr::from(base.as_ptr()), base);
+        assert_eq!(format!("{:?}", base), "UffdAddr(0x100000)");
+    }
+
+    #[test]
+    fn test_event_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Event>();
+    }
+}
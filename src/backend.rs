@@ -0,0 +1,311 @@
+//! A trait over the operations of a userfaultfd object.
+//!
+//! Fault handlers that are generic over [`UffdBackend`] can run against a real [`Uffd`], an
+//! [`InstrumentedUffd`], or the in-memory [`FakeUffd`](crate::fake::FakeUffd), which lets their
+//! logic be unit tested where `userfaultfd` is unavailable.
+//!
+//! Only the `_with_mode` variants of the resolving operations need to be implemented; the
+//! `wake: bool` variants are provided in terms of them, like on [`Uffd`].
+
+use crate::error::Result;
+use crate::stats::InstrumentedUffd;
+#[cfg(feature = "linux5_13")]
+use crate::ContinueMode;
+#[cfg(feature = "linux6_6")]
+use crate::PoisonMode;
+#[cfg(feature = "linux5_7")]
+use crate::WriteProtectMode;
+use crate::{CopyMode, Event, EventBuffer, IoctlFlags, RegisterMode, Uffd, ZeropageMode};
+use libc::c_void;
+
+/// The operations of a userfaultfd object.
+///
+/// See the methods of the same name on [`Uffd`] for their semantics.
+pub trait UffdBackend {
+    /// See [`Uffd::register`].
+    fn register(&self, start: *mut c_void, len: usize) -> Result<IoctlFlags> {
+        self.register_with_mode(start, len, RegisterMode::MISSING)
+    }
+
+    /// See [`Uffd::register_with_mode`].
+    fn register_with_mode(
+        &self,
+        start: *mut c_void,
+        len: usize,
+        mode: RegisterMode,
+    ) -> Result<IoctlFlags>;
+
+    /// See [`Uffd::unregister`].
+    fn unregister(&self, start: *mut c_void, len: usize) -> Result<()>;
+
+    /// See [`Uffd::copy`].
+    ///
+    /// # Safety
+    ///
+    /// See [`Uffd::copy_with_mode`].
+    unsafe fn copy(
+        &self,
+        src: *const c_void,
+        dst: *mut c_void,
+        len: usize,
+        wake: bool,
+    ) -> Result<usize> {
+        let mode = if wake {
+            CopyMode::empty()
+        } else {
+            CopyMode::DONTWAKE
+        };
+        self.copy_with_mode(src, dst, len, mode)
+    }
+
+    /// See [`Uffd::copy_with_mode`].
+    ///
+    /// # Safety
+    ///
+    /// See [`Uffd::copy_with_mode`].
+    unsafe fn copy_with_mode(
+        &self,
+        src: *const c_void,
+        dst: *mut c_void,
+        len: usize,
+        mode: CopyMode,
+    ) -> Result<usize>;
+
+    /// See [`Uffd::zeropage`].
+    ///
+    /// # Safety
+    ///
+    /// See [`Uffd::zeropage_with_mode`].
+    unsafe fn zeropage(&self, start: *mut c_void, len: usize, wake: bool) -> Result<usize> {
+        let mode = if wake {
+            ZeropageMode::empty()
+        } else {
+            ZeropageMode::DONTWAKE
+        };
+        self.zeropage_with_mode(start, len, mode)
+    }
+
+    /// See [`Uffd::zeropage_with_mode`].
+    ///
+    /// # Safety
+    ///
+    /// See [`Uffd::zeropage_with_mode`].
+    unsafe fn zeropage_with_mode(
+        &self,
+        start: *mut c_void,
+        len: usize,
+        mode: ZeropageMode,
+    ) -> Result<usize>;
+
+    /// See [`Uffd::wake`].
+    fn wake(&self, start: *mut c_void, len: usize) -> Result<()>;
+
+    /// See [`Uffd::write_protect`].
+    #[cfg(feature = "linux5_7")]
+    fn write_protect(&self, start: *mut c_void, len: usize) -> Result<()> {
+        self.write_protect_with_mode(start, len, WriteProtectMode::WRITE_PROTECT)
+    }
+
+    /// See [`Uffd::remove_write_protection`].
+    #[cfg(feature = "linux5_7")]
+    fn remove_write_protection(&self, start: *mut c_void, len: usize, wake: bool) -> Result<()> {
+        let mode = if wake {
+            WriteProtectMode::empty()
+        } else {
+            WriteProtectMode::DONTWAKE
+        };
+        self.write_protect_with_mode(start, len, mode)
+    }
+
+    /// See [`Uffd::write_protect_with_mode`].
+    #[cfg(feature = "linux5_7")]
+    fn write_protect_with_mode(
+        &self,
+        start: *mut c_void,
+        len: usize,
+        mode: WriteProtectMode,
+    ) -> Result<()>;
+
+    /// See [`Uffd::r#continue`].
+    #[cfg(feature = "linux5_13")]
+    fn r#continue(&self, start: *mut c_void, len: usize, wake: bool) -> Result<u64> {
+        let mode = if wake {
+            ContinueMode::empty()
+        } else {
+            ContinueMode::DONTWAKE
+        };
+        self.continue_with_mode(start, len, mode)
+    }
+
+    /// See [`Uffd::continue_with_mode`].
+    #[cfg(feature = "linux5_13")]
+    fn continue_with_mode(&self, start: *mut c_void, len: usize, mode: ContinueMode)
+        -> Result<u64>;
+
+    /// See [`Uffd::poison`].
+    #[cfg(feature = "linux6_6")]
+    fn poison(&self, start: *mut c_void, len: usize, wake: bool) -> Result<u64> {
+        let mode = if wake {
+            PoisonMode::empty()
+        } else {
+            PoisonMode::DONTWAKE
+        };
+        self.poison_with_mode(start, len, mode)
+    }
+
+    /// See [`Uffd::poison_with_mode`].
+    #[cfg(feature = "linux6_6")]
+    fn poison_with_mode(&self, start: *mut c_void, len: usize, mode: PoisonMode) -> Result<u64>;
+
+    /// See [`Uffd::read_event`].
+    fn read_event(&self) -> Result<Option<Event>>;
+
+    /// Read up to as many events as `buf` holds. See [`Uffd::read_events`].
+    ///
+    /// Unlike [`Uffd::read_events`], the events are collected, and the first event that fails to
+    /// decode fails the whole call.
+    fn read_events(&self, buf: &mut EventBuffer) -> Result<Vec<Event>>;
+}
+
+impl UffdBackend for Uffd {
+    fn register_with_mode(
+        &self,
+        start: *mut c_void,
+        len: usize,
+        mode: RegisterMode,
+    ) -> Result<IoctlFlags> {
+        Uffd::register_with_mode(self, start, len, mode)
+    }
+
+    fn unregister(&self, start: *mut c_void, len: usize) -> Result<()> {
+        Uffd::unregister(self, start, len)
+    }
+
+    unsafe fn copy_with_mode(
+        &self,
+        src: *const c_void,
+        dst: *mut c_void,
+        len: usize,
+        mode: CopyMode,
+    ) -> Result<usize> {
+        Uffd::copy_with_mode(self, src, dst, len, mode)
+    }
+
+    unsafe fn zeropage_with_mode(
+        &self,
+        start: *mut c_void,
+        len: usize,
+        mode: ZeropageMode,
+    ) -> Result<usize> {
+        Uffd::zeropage_with_mode(self, start, len, mode)
+    }
+
+    fn wake(&self, start: *mut c_void, len: usize) -> Result<()> {
+        Uffd::wake(self, start, len)
+    }
+
+    #[cfg(feature = "linux5_7")]
+    fn write_protect_with_mode(
+        &self,
+        start: *mut c_void,
+        len: usize,
+        mode: WriteProtectMode,
+    ) -> Result<()> {
+        Uffd::write_protect_with_mode(self, start, len, mode)
+    }
+
+    #[cfg(feature = "linux5_13")]
+    fn continue_with_mode(
+        &self,
+        start: *mut c_void,
+        len: usize,
+        mode: ContinueMode,
+    ) -> Result<u64> {
+        Uffd::continue_with_mode(self, start, len, mode)
+    }
+
+    #[cfg(feature = "linux6_6")]
+    fn poison_with_mode(&self, start: *mut c_void, len: usize, mode: PoisonMode) -> Result<u64> {
+        Uffd::poison_with_mode(self, start, len, mode)
+    }
+
+    fn read_event(&self) -> Result<Option<Event>> {
+        Uffd::read_event(self)
+    }
+
+    fn read_events(&self, buf: &mut EventBuffer) -> Result<Vec<Event>> {
+        Uffd::read_events(self, buf)?.collect()
+    }
+}
+
+impl UffdBackend for InstrumentedUffd {
+    fn register_with_mode(
+        &self,
+        start: *mut c_void,
+        len: usize,
+        mode: RegisterMode,
+    ) -> Result<IoctlFlags> {
+        InstrumentedUffd::register_with_mode(self, start, len, mode)
+    }
+
+    fn unregister(&self, start: *mut c_void, len: usize) -> Result<()> {
+        InstrumentedUffd::unregister(self, start, len)
+    }
+
+    unsafe fn copy_with_mode(
+        &self,
+        src: *const c_void,
+        dst: *mut c_void,
+        len: usize,
+        mode: CopyMode,
+    ) -> Result<usize> {
+        InstrumentedUffd::copy_with_mode(self, src, dst, len, mode)
+    }
+
+    unsafe fn zeropage_with_mode(
+        &self,
+        start: *mut c_void,
+        len: usize,
+        mode: ZeropageMode,
+    ) -> Result<usize> {
+        InstrumentedUffd::zeropage_with_mode(self, start, len, mode)
+    }
+
+    fn wake(&self, start: *mut c_void, len: usize) -> Result<()> {
+        InstrumentedUffd::wake(self, start, len)
+    }
+
+    #[cfg(feature = "linux5_7")]
+    fn write_protect_with_mode(
+        &self,
+        start: *mut c_void,
+        len: usize,
+        mode: WriteProtectMode,
+    ) -> Result<()> {
+        InstrumentedUffd::write_protect_with_mode(self, start, len, mode)
+    }
+
+    #[cfg(feature = "linux5_13")]
+    fn continue_with_mode(
+        &self,
+        start: *mut c_void,
+        len: usize,
+        mode: ContinueMode,
+    ) -> Result<u64> {
+        InstrumentedUffd::continue_with_mode(self, start, len, mode)
+    }
+
+    // Poisoning is rare enough not to be instrumented.
+    #[cfg(feature = "linux6_6")]
+    fn poison_with_mode(&self, start: *mut c_void, len: usize, mode: PoisonMode) -> Result<u64> {
+        self.inner().poison_with_mode(start, len, mode)
+    }
+
+    fn read_event(&self) -> Result<Option<Event>> {
+        InstrumentedUffd::read_event(self)
+    }
+
+    fn read_events(&self, buf: &mut EventBuffer) -> Result<Vec<Event>> {
+        InstrumentedUffd::read_events(self, buf)?.collect()
+    }
+}
@@ -0,0 +1,436 @@
+//! Report why userfaultfd does or doesn't work on this host.
+//!
+//! Usage: `uffd-probe [--json]`
+
+use libc::{self, c_void};
+use std::env;
+use std::ffi::CStr;
+use std::fmt::Write as _;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::process;
+use std::ptr;
+use userfaultfd::{Error, FeatureFlags, IoctlFlags, RegisterMode, UffdBuilder, CAP_SYS_PTRACE};
+
+/// The value of a probe: either what was found, or why it couldn't be determined.
+type Probe<T> = std::result::Result<T, String>;
+
+struct Device {
+    exists: bool,
+    mode: Option<u32>,
+    accessible: Probe<()>,
+}
+
+struct Memory {
+    kind: &'static str,
+    // One result per register mode, or why the memory couldn't be mapped.
+    modes: Probe<Vec<(&'static str, Probe<()>)>>,
+}
+
+struct Report {
+    kernel: Probe<String>,
+    device: Device,
+    unprivileged_userfaultfd: Probe<String>,
+    cap_sys_ptrace: Probe<bool>,
+    seccomp: Probe<String>,
+    api: Probe<(FeatureFlags, IoctlFlags)>,
+    memory: Vec<Memory>,
+}
+
+fn read_trimmed(path: &str) -> Probe<String> {
+    fs::read_to_string(path)
+        .map(|s| s.trim().to_string())
+        .map_err(|e| e.to_string())
+}
+
+fn status_field(name: &str) -> Probe<String> {
+    let status = read_trimmed("/proc/self/status")?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+        .map(|value| value.trim().to_string())
+        .ok_or_else(|| format!("no {} in /proc/self/status", name))
+}
+
+fn probe_device() -> Device {
+    let metadata = fs::metadata("/dev/userfaultfd");
+    let accessible = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/userfaultfd")
+        .map(drop)
+        .map_err(|e| e.to_string());
+    match metadata {
+        Ok(metadata) => Device {
+            exists: metadata.file_type().is_char_device(),
+            mode: Some(metadata.permissions().mode() & 0o7777),
+            accessible,
+        },
+        Err(_) => Device {
+            exists: false,
+            mode: None,
+            accessible,
+        },
+    }
+}
+
+fn probe_cap_sys_ptrace() -> Probe<bool> {
+    let caps = status_field("CapEff")?;
+    let caps = u64::from_str_radix(&caps, 16).map_err(|e| e.to_string())?;
+    Ok(caps & (1 << CAP_SYS_PTRACE) != 0)
+}
+
+fn probe_seccomp() -> Probe<String> {
+    let mode = match status_field("Seccomp")?.as_str() {
+        "0" => "disabled".to_string(),
+        "1" => "strict".to_string(),
+        "2" => "filter".to_string(),
+        other => format!("unknown ({})", other),
+    };
+    match status_field("Seccomp_filters") {
+        Ok(filters) if mode == "filter" => Ok(format!("{}, {} filters", mode, filters)),
+        _ => Ok(mode),
+    }
+}
+
+fn describe(err: Error) -> String {
+    match err {
+        // Permission errors explain what to fix.
+        Error::PermissionDenied { .. } => err.to_string(),
+        err => format!("{:?}", err),
+    }
+}
+
+fn last_error() -> String {
+    io::Error::last_os_error().to_string()
+}
+
+/// The size of the default huge page, from `/proc/meminfo`.
+fn huge_page_size() -> Probe<usize> {
+    let meminfo = read_trimmed("/proc/meminfo")?;
+    meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("Hugepagesize:"))
+        .and_then(|value| {
+            value
+                .trim()
+                .strip_suffix("kB")?
+                .trim()
+                .parse::<usize>()
+                .ok()
+        })
+        .map(|kb| kb * 1024)
+        .ok_or_else(|| "no Hugepagesize in /proc/meminfo".to_string())
+}
+
+/// Map `len` bytes of memory of the given kind. Shared memory is backed by a memfd created with
+/// `memfd_flags`.
+fn map(len: usize, memfd_flags: Option<libc::c_uint>) -> Probe<*mut c_void> {
+    let (fd, flags) = match memfd_flags {
+        None => (-1, libc::MAP_PRIVATE | libc::MAP_ANONYMOUS),
+        Some(memfd_flags) => {
+            let name = CStr::from_bytes_with_nul(b"uffd-probe\0").unwrap();
+            let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC | memfd_flags) };
+            if fd < 0 {
+                return Err(format!("memfd_create: {}", last_error()));
+            }
+            if unsafe { libc::ftruncate(fd, len as libc::off_t) } != 0 {
+                let err = format!("ftruncate: {}", last_error());
+                unsafe { libc::close(fd) };
+                return Err(err);
+            }
+            (fd, libc::MAP_SHARED)
+        }
+    };
+
+    let addr = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            flags,
+            fd,
+            0,
+        )
+    };
+    let err = last_error();
+    if fd >= 0 {
+        unsafe { libc::close(fd) };
+    }
+    if addr == libc::MAP_FAILED {
+        return Err(format!("mmap: {}", err));
+    }
+    Ok(addr)
+}
+
+fn register_modes() -> Vec<(&'static str, RegisterMode)> {
+    #[allow(unused_mut)]
+    let mut modes = vec![("MISSING", RegisterMode::MISSING)];
+    #[cfg(feature = "linux5_7")]
+    modes.push(("WRITE_PROTECT", RegisterMode::WRITE_PROTECT));
+    #[cfg(feature = "linux5_13")]
+    modes.push(("MINOR", RegisterMode::MINOR));
+    modes
+}
+
+fn probe_memory(
+    kind: &'static str,
+    len: Probe<usize>,
+    memfd_flags: Option<libc::c_uint>,
+    features: FeatureFlags,
+) -> Memory {
+    let modes = len.and_then(|len| {
+        let addr = map(len, memfd_flags)?;
+        let register = |mode| {
+            // Some modes only work with the features that enable them, so request all of
+            // them, and fall back to none if that fails.
+            let uffd = UffdBuilder::new()
+                .require_features(features)
+                .create()
+                .or_else(|_| UffdBuilder::new().create())
+                .map_err(describe)?;
+            uffd.register_with_mode(addr, len, mode).map_err(describe)?;
+            uffd.unregister(addr, len).map_err(describe)
+        };
+        let modes = register_modes()
+            .into_iter()
+            .map(|(name, mode)| (name, register(mode)))
+            .collect();
+        unsafe { libc::munmap(addr, len) };
+        Ok(modes)
+    });
+    Memory { kind, modes }
+}
+
+fn probe() -> Report {
+    let api = UffdBuilder::new().probe().map_err(describe);
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let features = api
+        .as_ref()
+        .map(|(features, _)| *features)
+        .unwrap_or_else(|_| FeatureFlags::empty());
+    let memory = if api.is_ok() {
+        vec![
+            probe_memory("anonymous", Ok(page_size), None, features),
+            probe_memory("shmem", Ok(page_size), Some(0), features),
+            probe_memory(
+                "hugetlbfs",
+                huge_page_size(),
+                Some(libc::MFD_HUGETLB),
+                features,
+            ),
+        ]
+    } else {
+        Vec::new()
+    };
+
+    Report {
+        kernel: read_trimmed("/proc/sys/kernel/osrelease"),
+        device: probe_device(),
+        unprivileged_userfaultfd: read_trimmed("/proc/sys/vm/unprivileged_userfaultfd"),
+        cap_sys_ptrace: probe_cap_sys_ptrace(),
+        seccomp: probe_seccomp(),
+        api,
+        memory,
+    }
+}
+
+/// The names of the flags in `bits`, with unknown bits as a hexadecimal number.
+fn flag_names<B: bitflags::Flags<Bits = u64>>(flags: B) -> Vec<String> {
+    let mut unnamed = flags.bits();
+    let mut names = Vec::new();
+    for (name, flag) in flags.iter_names() {
+        unnamed &= !flag.bits();
+        names.push(name.to_string());
+    }
+    if unnamed != 0 {
+        names.push(format!("{:#x}", unnamed));
+    }
+    names
+}
+
+fn text<T: std::fmt::Display>(probe: &Probe<T>) -> String {
+    match probe {
+        Ok(value) => value.to_string(),
+        Err(e) => format!("unknown ({})", e),
+    }
+}
+
+fn print_text(report: &Report) {
+    println!("kernel: {}", text(&report.kernel));
+
+    let device = &report.device;
+    let mode = device
+        .mode
+        .map(|m| format!(", mode {:o}", m))
+        .unwrap_or_default();
+    let access = match &device.accessible {
+        Ok(()) => "accessible".to_string(),
+        Err(e) => format!("not accessible ({})", e),
+    };
+    if device.exists {
+        println!("/dev/userfaultfd: present{}, {}", mode, access);
+    } else {
+        println!("/dev/userfaultfd: absent");
+    }
+
+    println!(
+        "vm.unprivileged_userfaultfd: {}",
+        text(&report.unprivileged_userfaultfd)
+    );
+    println!(
+        "CAP_SYS_PTRACE: {}",
+        text(
+            &report
+                .cap_sys_ptrace
+                .clone()
+                .map(|c| if c { "yes" } else { "no" })
+        )
+    );
+    println!("seccomp: {}", text(&report.seccomp));
+
+    match &report.api {
+        Ok((features, ioctls)) => {
+            println!("userfaultfd: available");
+            println!("features: {}", flag_names(*features).join(" "));
+            println!("ioctls: {}", flag_names(*ioctls).join(" "));
+        }
+        Err(e) => println!("userfaultfd: unavailable ({})", e),
+    }
+
+    if !report.memory.is_empty() {
+        println!("register modes:");
+    }
+    for memory in &report.memory {
+        match &memory.modes {
+            Ok(modes) => {
+                let modes = modes
+                    .iter()
+                    .map(|(name, result)| match result {
+                        Ok(()) => format!("{} ok", name),
+                        Err(e) => format!("{} failed ({})", name, e),
+                    })
+                    .collect::<Vec<_>>();
+                println!("  {}: {}", memory.kind, modes.join(", "));
+            }
+            Err(e) => println!("  {}: unavailable ({})", memory.kind, e),
+        }
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// A probe as `{"ok": value}` or `{"error": message}`.
+fn json_probe<T>(probe: &Probe<T>, value: impl FnOnce(&T) -> String) -> String {
+    match probe {
+        Ok(v) => format!("{{\"ok\":{}}}", value(v)),
+        Err(e) => format!("{{\"error\":{}}}", json_string(e)),
+    }
+}
+
+fn json_list(names: Vec<String>) -> String {
+    let names = names.iter().map(|n| json_string(n)).collect::<Vec<_>>();
+    format!("[{}]", names.join(","))
+}
+
+fn print_json(report: &Report) {
+    let device = &report.device;
+    let memory = report
+        .memory
+        .iter()
+        .map(|memory| {
+            let modes = json_probe(&memory.modes, |modes| {
+                let modes = modes
+                    .iter()
+                    .map(|(name, result)| {
+                        format!(
+                            "{}:{}",
+                            json_string(name),
+                            json_probe(result, |_| "true".into())
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                format!("{{{}}}", modes.join(","))
+            });
+            format!("{}:{}", json_string(memory.kind), modes)
+        })
+        .collect::<Vec<_>>();
+
+    let fields = [
+        ("kernel", json_probe(&report.kernel, |k| json_string(k))),
+        (
+            "dev_userfaultfd",
+            format!(
+                "{{\"exists\":{},\"mode\":{},\"accessible\":{}}}",
+                device.exists,
+                device
+                    .mode
+                    .map(|m| json_string(&format!("{:o}", m)))
+                    .unwrap_or_else(|| "null".into()),
+                json_probe(&device.accessible, |_| "true".into()),
+            ),
+        ),
+        (
+            "unprivileged_userfaultfd",
+            json_probe(&report.unprivileged_userfaultfd, |v| json_string(v)),
+        ),
+        (
+            "cap_sys_ptrace",
+            json_probe(&report.cap_sys_ptrace, |c| c.to_string()),
+        ),
+        ("seccomp", json_probe(&report.seccomp, |s| json_string(s))),
+        (
+            "api",
+            json_probe(&report.api, |(features, ioctls)| {
+                format!(
+                    "{{\"features\":{},\"ioctls\":{}}}",
+                    json_list(flag_names(*features)),
+                    json_list(flag_names(*ioctls))
+                )
+            }),
+        ),
+        ("register_modes", format!("{{{}}}", memory.join(","))),
+    ];
+    let fields = fields
+        .iter()
+        .map(|(name, value)| format!("{}:{}", json_string(name), value))
+        .collect::<Vec<_>>();
+    println!("{{{}}}", fields.join(","));
+}
+
+fn main() {
+    let mut json = false;
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--json" => json = true,
+            "-h" | "--help" => {
+                println!("Usage: uffd-probe [--json]");
+                return;
+            }
+            other => {
+                eprintln!("uffd-probe: unknown argument {}", other);
+                process::exit(2);
+            }
+        }
+    }
+
+    let report = probe();
+    if json {
+        print_json(&report);
+    } else {
+        print_text(&report);
+    }
+}
@@ -1,13 +1,26 @@
-use crate::error::{Error, Result};
+#[cfg(feature = "emulation")]
+use crate::emulation::{AnyUffd, EmulatedUffd};
+use crate::error::{Error, PermissionReason, Result};
 use crate::raw;
 use crate::{IoctlFlags, Uffd};
 use bitflags::bitflags;
 use nix::errno::Errno;
-use std::fs::{File, OpenOptions};
+use std::fs::{self, File, OpenOptions};
 use std::io::ErrorKind;
-use std::os::fd::AsRawFd;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
 
 const UFFD_DEVICE_PATH: &str = "/dev/userfaultfd";
+const UNPRIVILEGED_USERFAULTFD_PATH: &str = "/proc/sys/vm/unprivileged_userfaultfd";
+
+/// The number of the `CAP_SYS_PTRACE` capability, which `libc` doesn't define. Bit
+/// `1 << CAP_SYS_PTRACE` of the `CapEff` field of `/proc/<pid>/status` tells whether a process has
+/// it.
+///
+/// This is only public for the `uffd-probe` binary, and is not part of the API.
+#[doc(hidden)]
+pub const CAP_SYS_PTRACE: u32 = 19;
 
 cfg_if::cfg_if! {
     if #[cfg(any(feature = "linux5_7", feature = "linux4_14"))] {
@@ -42,6 +55,33 @@ cfg_if::cfg_if! {
         }
     }
 }
+
+/// How [`UffdBuilder`] obtains the userfaultfd file descriptor.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum BackendPolicy {
+    /// Use `/dev/userfaultfd` if it exists, and the system call otherwise. If the device exists
+    /// but can't be used, creation fails.
+    #[default]
+    Auto,
+    /// Only use `/dev/userfaultfd`.
+    DeviceOnly,
+    /// Only use the `userfaultfd(2)` system call.
+    SyscallOnly,
+    /// Use `/dev/userfaultfd`, and fall back to the system call if that fails for any reason.
+    DeviceThenSyscall,
+    /// Only use the device at the given path, such as a device bind-mounted into a container.
+    Device(PathBuf),
+}
+
+/// The backend that produced a userfaultfd file descriptor.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DescriptorBackend {
+    /// The `USERFAULTFD_IOC_NEW` ioctl on the device at the given path.
+    Device(PathBuf),
+    /// The `userfaultfd(2)` system call.
+    Syscall,
+}
+
 /// A builder for initializing `Uffd` objects.
 ///
 /// ```
@@ -54,17 +94,20 @@ cfg_if::cfg_if! {
 ///     .create();
 /// assert!(uffd.is_ok());
 /// ```
+#[derive(Clone, Debug)]
 pub struct UffdBuilder {
     close_on_exec: bool,
     non_blocking: bool,
     user_mode_only: bool,
     req_features: FeatureFlags,
     req_ioctls: IoctlFlags,
+    backend_policy: BackendPolicy,
 }
 
 impl UffdBuilder {
     /// Create a new builder with no required features or ioctls, `close_on_exec` and
-    /// `non_blocking` both set to `false`, and `user_mode_only` set to `true`.
+    /// `non_blocking` both set to `false`, `user_mode_only` set to `true`, and the
+    /// `BackendPolicy::Auto` backend policy.
     pub fn new() -> UffdBuilder {
         UffdBuilder {
             close_on_exec: false,
@@ -72,6 +115,7 @@ impl UffdBuilder {
             user_mode_only: true,
             req_features: FeatureFlags::empty(),
             req_ioctls: IoctlFlags::empty(),
+            backend_policy: BackendPolicy::Auto,
         }
     }
 
@@ -120,7 +164,13 @@ impl UffdBuilder {
         self
     }
 
-    fn uffd_from_dev(&self, file: &mut File, flags: i32) -> Result<Uffd> {
+    /// Select how the userfaultfd file descriptor is obtained.
+    pub fn backend_policy(&mut self, policy: BackendPolicy) -> &mut Self {
+        self.backend_policy = policy;
+        self
+    }
+
+    fn uffd_from_dev(&self, file: &File, flags: i32) -> Result<Uffd> {
         match unsafe { raw::new_uffd(file.as_raw_fd(), flags) } {
             Err(err) => Err(err.into()),
             Ok(fd) => Ok(Uffd { fd }),
@@ -128,14 +178,18 @@ impl UffdBuilder {
     }
 
     fn uffd_from_syscall(&self, flags: i32) -> Result<Uffd> {
-        let fd = match Errno::result(unsafe { raw::userfaultfd(flags) }) {
-            Ok(fd) => fd,
-            // setting the USER_MODE_ONLY flag on kernel pre-5.11 causes it to return EINVAL.
-            // If the user asks for the flag, we first try with it set, and if kernel gives
-            // EINVAL we try again without the flag set.
-            Err(Errno::EINVAL) if self.user_mode_only => Errno::result(unsafe {
+        let mut result = Errno::result(unsafe { raw::userfaultfd(flags) });
+        // setting the USER_MODE_ONLY flag on kernel pre-5.11 causes it to return EINVAL.
+        // If the user asks for the flag, we first try with it set, and if kernel gives
+        // EINVAL we try again without the flag set.
+        if result == Err(Errno::EINVAL) && self.user_mode_only {
+            result = Errno::result(unsafe {
                 raw::userfaultfd(flags & !raw::UFFD_USER_MODE_ONLY as i32)
-            })?,
+            });
+        }
+        let fd = match result {
+            Ok(fd) => fd,
+            Err(Errno::EPERM) => return Err(self.syscall_denied()),
             Err(e) => return Err(e.into()),
         };
 
@@ -143,27 +197,77 @@ impl UffdBuilder {
         Ok(Uffd { fd })
     }
 
-    // Try to get a UFFD file descriptor using `/dev/userfaultfd`. If that fails
-    // fall back to calling the system call.
-    fn open_file_descriptor(&self, flags: i32) -> Result<Uffd> {
-        // If `/dev/userfaultfd` exists we'll try to get the file descriptor from it. If the file
-        // doesn't exist we will fall back to calling the system call. This means, that if the
-        // device exists but the calling process does not have access rights to it, this will fail,
-        // i.e. we will not fall back to calling the system call.
-        match OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(UFFD_DEVICE_PATH)
-        {
-            Ok(mut file) => self.uffd_from_dev(&mut file, flags),
-            Err(err) if err.kind() == ErrorKind::NotFound => self.uffd_from_syscall(flags),
+    // Find out why the system call failed with EPERM.
+    fn syscall_denied(&self) -> Error {
+        let status = fs::read_to_string("/proc/self/status").unwrap_or_default();
+        let field = |name: &str| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+                .map(str::trim)
+        };
+        // If the sysctl doesn't exist, the kernel doesn't restrict unprivileged users.
+        let unprivileged = fs::read_to_string(UNPRIVILEGED_USERFAULTFD_PATH)
+            .map_or(true, |value| value.trim() != "0");
+        let cap_sys_ptrace = field("CapEff")
+            .and_then(|caps| u64::from_str_radix(caps, 16).ok())
+            .map_or(false, |caps| caps & (1 << CAP_SYS_PTRACE) != 0);
+        let seccomp_filter = field("Seccomp") == Some("2");
+
+        let reason = syscall_denial_reason(
+            self.user_mode_only,
+            unprivileged,
+            cap_sys_ptrace,
+            seccomp_filter,
+        );
+        Error::PermissionDenied { reason }
+    }
+
+    fn uffd_from_path(&self, path: &Path, flags: i32) -> Result<Uffd> {
+        match OpenOptions::new().read(true).write(true).open(path) {
+            Ok(file) => self.uffd_from_dev(&file, flags),
+            Err(err) if err.kind() == ErrorKind::PermissionDenied => Err(Error::PermissionDenied {
+                reason: device_denial_reason(path),
+            }),
             Err(err) => Err(Error::OpenDevUserfaultfd(err)),
         }
     }
 
-    /// Create a `Uffd` object with the current settings of this builder.
-    pub fn create(&self) -> Result<Uffd> {
-        // first do the syscall to get the file descriptor
+    // Get a UFFD file descriptor as selected by `policy`.
+    fn open_file_descriptor(
+        &self,
+        flags: i32,
+        policy: &BackendPolicy,
+    ) -> Result<(Uffd, DescriptorBackend)> {
+        let device = || {
+            let path = PathBuf::from(UFFD_DEVICE_PATH);
+            let uffd = self.uffd_from_path(&path, flags)?;
+            Ok((uffd, DescriptorBackend::Device(path)))
+        };
+        let syscall = || Ok((self.uffd_from_syscall(flags)?, DescriptorBackend::Syscall));
+
+        match policy {
+            // If `/dev/userfaultfd` exists we'll try to get the file descriptor from it. If the
+            // file doesn't exist we will fall back to calling the system call. This means, that if
+            // the device exists but the calling process does not have access rights to it, this
+            // will fail, i.e. we will not fall back to calling the system call.
+            BackendPolicy::Auto => match device() {
+                Err(Error::OpenDevUserfaultfd(err)) if err.kind() == ErrorKind::NotFound => {
+                    syscall()
+                }
+                result => result,
+            },
+            BackendPolicy::DeviceOnly => device(),
+            BackendPolicy::SyscallOnly => syscall(),
+            BackendPolicy::DeviceThenSyscall => device().or_else(|_| syscall()),
+            BackendPolicy::Device(path) => {
+                let uffd = self.uffd_from_path(path, flags)?;
+                Ok((uffd, DescriptorBackend::Device(path.clone())))
+            }
+        }
+    }
+
+    fn flags(&self) -> i32 {
         let mut flags = 0;
         if self.close_on_exec {
             flags |= libc::O_CLOEXEC;
@@ -175,10 +279,11 @@ impl UffdBuilder {
         if self.user_mode_only {
             flags |= raw::UFFD_USER_MODE_ONLY as i32;
         }
+        flags
+    }
 
-        let uffd = self.open_file_descriptor(flags)?;
-
-        // then do the UFFDIO_API ioctl to set up and ensure features and other ioctls are available
+    // Do the UFFDIO_API ioctl to set up and ensure features and other ioctls are available.
+    fn handshake(&self, uffd: Uffd) -> Result<Uffd> {
         let mut api = raw::uffdio_api {
             api: raw::UFFD_API,
             features: self.req_features.bits(),
@@ -194,4 +299,319 @@ impl UffdBuilder {
             Ok(uffd)
         }
     }
+
+    /// Create a `Uffd` object with the current settings of this builder.
+    ///
+    /// Use [`create_with_backend`](Self::create_with_backend) to also learn which backend
+    /// produced the file descriptor.
+    pub fn create(&self) -> Result<Uffd> {
+        self.create_with_backend().map(|(uffd, _)| uffd)
+    }
+
+    /// Create a `Uffd` object with the current settings of this builder, and return the backend
+    /// that produced its file descriptor.
+    pub fn create_with_backend(&self) -> Result<(Uffd, DescriptorBackend)> {
+        let (uffd, backend) = self.open_file_descriptor(self.flags(), &self.backend_policy)?;
+        Ok((self.handshake(uffd)?, backend))
+    }
+
+    /// Create a userfaultfd object with the current settings of this builder, and return all the
+    /// features and ioctls that the kernel supports, instead of checking the required ones.
+    ///
+    /// Features and ioctls unknown to this crate are kept as unnamed bits.
+    pub fn probe(&self) -> Result<(FeatureFlags, IoctlFlags)> {
+        let (uffd, _) = self.open_file_descriptor(self.flags(), &self.backend_policy)?;
+        let mut api = raw::uffdio_api {
+            api: raw::UFFD_API,
+            features: 0,
+            ioctls: 0,
+        };
+        unsafe {
+            raw::api(uffd.fd, &mut api as *mut raw::uffdio_api)?;
+        }
+        Ok((
+            FeatureFlags::from_bits_retain(api.features),
+            IoctlFlags::from_bits_retain(api.ioctls),
+        ))
+    }
+
+    /// Create a factory that creates `Uffd` objects with the current settings of this builder
+    /// from `device`, an already opened `/dev/userfaultfd`.
+    ///
+    /// The device may also have been opened by another process and received over a socket.
+    pub fn factory(&self, device: File) -> UffdFactory {
+        UffdFactory {
+            device,
+            builder: self.clone(),
+        }
+    }
+
+    /// Open the userfaultfd device, and return a factory for it as with
+    /// [`factory`](Self::factory).
+    ///
+    /// This opens the path of a `BackendPolicy::Device` policy, and `/dev/userfaultfd` otherwise.
+    /// Call it before the process is sandboxed, after which the device may no longer be opened.
+    pub fn open_factory(&self) -> Result<UffdFactory> {
+        let path = match &self.backend_policy {
+            BackendPolicy::Device(path) => path.as_path(),
+            _ => Path::new(UFFD_DEVICE_PATH),
+        };
+        let device = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(|err| match err.kind() {
+                ErrorKind::PermissionDenied => Error::PermissionDenied {
+                    reason: device_denial_reason(path),
+                },
+                _ => Error::OpenDevUserfaultfd(err),
+            })?;
+        Ok(self.factory(device))
+    }
+
+    /// Create an emulated userfaultfd object with the current settings of this builder, without
+    /// using userfaultfd at all. See the [`emulation`](crate::emulation) module for its limits.
+    ///
+    /// Only the `PAGEFAULT_FLAG_WP` and `THREAD_ID` features, and the `WAKE`, `COPY`,
+    /// `ZEROPAGE` and `WRITE_PROTECT` ioctls can be required; other requirements fail with
+    /// `EINVAL` and `Error::UnsupportedIoctls` respectively.
+    ///
+    /// This requires the `emulation` feature.
+    #[cfg(feature = "emulation")]
+    pub fn emulate(&self) -> Result<EmulatedUffd> {
+        #[allow(unused_mut)]
+        let mut features = FeatureFlags::PAGEFAULT_FLAG_WP;
+        #[cfg(any(feature = "linux5_7", feature = "linux4_14"))]
+        {
+            features |= FeatureFlags::THREAD_ID;
+        }
+        if !features.contains(self.req_features) {
+            return Err(Errno::EINVAL.into());
+        }
+
+        #[allow(unused_mut)]
+        let mut supported = IoctlFlags::WAKE | IoctlFlags::COPY | IoctlFlags::ZEROPAGE;
+        #[cfg(feature = "linux5_7")]
+        {
+            supported |= IoctlFlags::WRITE_PROTECT;
+        }
+        if !supported.contains(self.req_ioctls) {
+            return Err(Error::UnsupportedIoctls(supported));
+        }
+
+        EmulatedUffd::new(self.close_on_exec, self.non_blocking)
+    }
+
+    /// Create a userfaultfd object with the current settings of this builder, falling back to
+    /// an emulated one if userfaultfd is unavailable.
+    ///
+    /// With the default `BackendPolicy::Auto`, this behaves like `BackendPolicy::DeviceThenSyscall`,
+    /// so that the system call is also tried when `/dev/userfaultfd` exists but can't be opened.
+    /// Userfaultfd is emulated when the last backend tried fails with `EPERM`, `EACCES` or
+    /// `ENOSYS`. Other errors, such as a missing feature, are returned as is.
+    ///
+    /// This requires the `emulation` feature.
+    #[cfg(feature = "emulation")]
+    pub fn create_or_emulate(&self) -> Result<AnyUffd> {
+        fn unavailable(err: &Error) -> bool {
+            let errno = match err {
+                Error::PermissionDenied { .. } => return true,
+                Error::OpenDevUserfaultfd(err) => err.raw_os_error().map(Errno::from_i32),
+                Error::SystemError(errno) => Some(*errno),
+                _ => None,
+            };
+            matches!(errno, Some(Errno::EPERM | Errno::EACCES | Errno::ENOSYS))
+        }
+
+        let policy = match self.backend_policy {
+            BackendPolicy::Auto => &BackendPolicy::DeviceThenSyscall,
+            ref policy => policy,
+        };
+        match self.open_file_descriptor(self.flags(), policy) {
+            Ok((uffd, _)) => self.handshake(uffd).map(AnyUffd::Kernel),
+            Err(err) if unavailable(&err) => self.emulate().map(AnyUffd::Emulated),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl Default for UffdBuilder {
+    fn default() -> Self {
+        UffdBuilder::new()
+    }
+}
+
+/// Creates `Uffd` objects from an already opened `/dev/userfaultfd`, with the `USERFAULTFD_IOC_NEW`
+/// ioctl.
+///
+/// This lets a process create userfaultfd objects after a sandbox, such as landlock or seccomp,
+/// prevents it from opening the device. Create one with [`UffdBuilder::factory`] or
+/// [`UffdBuilder::open_factory`]; the backend policy of the builder is ignored.
+///
+/// ```no_run
+/// use userfaultfd::UffdBuilder;
+///
+/// let factory = UffdBuilder::new().close_on_exec(true).open_factory()?;
+/// // ... apply the sandbox ...
+/// let uffd = factory.create()?;
+/// # Ok::<(), userfaultfd::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct UffdFactory {
+    device: File,
+    builder: UffdBuilder,
+}
+
+impl UffdFactory {
+    /// Create a `Uffd` object with the settings of the builder this factory was created from,
+    /// including the API handshake and its required features and ioctls.
+    pub fn create(&self) -> Result<Uffd> {
+        let uffd = self
+            .builder
+            .uffd_from_dev(&self.device, self.builder.flags())?;
+        self.builder.handshake(uffd)
+    }
+
+    /// Return the device, for example to send it to another process.
+    pub fn into_device(self) -> File {
+        self.device
+    }
+}
+
+impl AsFd for UffdFactory {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.device.as_fd()
+    }
+}
+
+fn device_denial_reason(path: &Path) -> PermissionReason {
+    match fs::metadata(path) {
+        Ok(metadata) => PermissionReason::DeviceAccess {
+            mode: metadata.mode() & 0o7777,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+        },
+        Err(_) => PermissionReason::Unknown,
+    }
+}
+
+/// Explain why the system call failed with EPERM, given the `vm.unprivileged_userfaultfd` sysctl,
+/// whether the process has `CAP_SYS_PTRACE`, and whether a seccomp filter is installed.
+fn syscall_denial_reason(
+    user_mode_only: bool,
+    unprivileged: bool,
+    cap_sys_ptrace: bool,
+    seccomp_filter: bool,
+) -> PermissionReason {
+    let privileged = unprivileged || cap_sys_ptrace;
+    if !user_mode_only && !privileged {
+        PermissionReason::KernelFaultsRequireCapSysPtrace
+    } else if seccomp_filter {
+        // Since Linux 5.11, user-mode only objects are always allowed, so a filter is the likelier
+        // culprit than an old kernel.
+        PermissionReason::Seccomp
+    } else if !privileged {
+        PermissionReason::UnprivilegedUserfaultfdDisabled
+    } else {
+        PermissionReason::Unknown
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Whether `/dev/userfaultfd` can be opened. Not all kernels and CI hosts provide it.
+    fn device_accessible() -> bool {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(UFFD_DEVICE_PATH)
+            .is_ok()
+    }
+
+    #[test]
+    fn test_backend_policy() -> Result<()> {
+        let device = PathBuf::from(UFFD_DEVICE_PATH);
+        let mut cases = vec![(BackendPolicy::SyscallOnly, DescriptorBackend::Syscall)];
+        if device_accessible() {
+            cases.extend([
+                (
+                    BackendPolicy::Auto,
+                    DescriptorBackend::Device(device.clone()),
+                ),
+                (
+                    BackendPolicy::DeviceOnly,
+                    DescriptorBackend::Device(device.clone()),
+                ),
+                (
+                    BackendPolicy::Device(device.clone()),
+                    DescriptorBackend::Device(device.clone()),
+                ),
+            ]);
+        } else if !device.exists() {
+            cases.push((BackendPolicy::Auto, DescriptorBackend::Syscall));
+        }
+        for (policy, expected) in cases {
+            let (_, backend) = UffdBuilder::new()
+                .backend_policy(policy)
+                .create_with_backend()?;
+            assert_eq!(backend, expected);
+        }
+
+        let missing = PathBuf::from("/nonexistent/userfaultfd");
+        assert!(UffdBuilder::new()
+            .backend_policy(BackendPolicy::Device(missing.clone()))
+            .open_factory()
+            .is_err());
+        assert!(matches!(
+            UffdBuilder::new()
+                .backend_policy(BackendPolicy::Device(missing))
+                .create(),
+            Err(Error::OpenDevUserfaultfd(err)) if err.kind() == ErrorKind::NotFound
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_factory() -> Result<()> {
+        if !device_accessible() {
+            return Ok(());
+        }
+        let device = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(UFFD_DEVICE_PATH)?;
+        let factory = UffdBuilder::new()
+            .non_blocking(true)
+            .require_ioctls(IoctlFlags::API | IoctlFlags::REGISTER)
+            .factory(device);
+
+        let uffds = (0..3)
+            .map(|_| factory.create())
+            .collect::<Result<Vec<_>>>()?;
+        for uffd in &uffds {
+            // The builder settings are applied to every object.
+            assert!(uffd.read_event()?.is_none());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_syscall_denial_reason() {
+        use PermissionReason::*;
+
+        assert_eq!(
+            syscall_denial_reason(false, false, false, true),
+            KernelFaultsRequireCapSysPtrace
+        );
+        assert_eq!(syscall_denial_reason(false, false, true, true), Seccomp);
+        assert_eq!(syscall_denial_reason(true, false, false, true), Seccomp);
+        assert_eq!(
+            syscall_denial_reason(true, false, false, false),
+            UnprivilegedUserfaultfdDisabled
+        );
+        assert_eq!(syscall_denial_reason(true, true, false, false), Unknown);
+        assert_eq!(syscall_denial_reason(false, false, true, false), Unknown);
+    }
 }
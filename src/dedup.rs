@@ -0,0 +1,546 @@
+//! A content-addressed page store shared between many snapshots.
+//!
+//! Snapshots of similar workloads share most of their pages. A [`BlobStore`] keeps every distinct
+//! page once, as a file named after the SHA-256 hash of its contents, and each snapshot is a
+//! [`Manifest`] listing the hashes of its pages in order. All-zero pages are not stored; the
+//! manifest records them as [`PageHash::ZERO`] and they are installed with
+//! [`Uffd::zeropage`](crate::Uffd::zeropage).
+//!
+//! [`DedupSource`] resolves faults of one snapshot through the blob store. Sources can share a
+//! [`PageCache`] that keeps recently used pages in memory, so that restoring several snapshots
+//! which share pages reads each of them from disk only once.
+//!
+//! This module requires the `dedup` feature.
+//!
+//! # Format
+//!
+//! A blob directory contains one file per page, at `<first two hex digits>/<remaining hex
+//! digits>` of its hash. All integers of a manifest are little-endian:
+//!
+//! | Size | Field                                       |
+//! |------|---------------------------------------------|
+//! | 8    | magic, `b"UFFDMNFT"`                        |
+//! | 4    | format version, currently 1                 |
+//! | 4    | page size in bytes                          |
+//! | 8    | number of pages `n`                         |
+//! | 32n  | `n` SHA-256 page hashes, all zeros for zero pages |
+
+use crate::format::{self, invalid_data, u32_at, u64_at};
+use crate::page_source::{PageContents, PageSource};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// The magic bytes at the start of every manifest.
+pub const MAGIC: [u8; 8] = *b"UFFDMNFT";
+
+/// The manifest format version written by [`Manifest::write_to`].
+pub const VERSION: u32 = 1;
+
+/// The SHA-256 hash of the contents of a page.
+#[derive(Clone, Copy, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct PageHash(pub [u8; 32]);
+
+impl PageHash {
+    /// The hash recorded for pages that are all zeros.
+    pub const ZERO: PageHash = PageHash([0; 32]);
+
+    /// Hash the contents of a page. Returns [`PageHash::ZERO`] if the page is all zeros.
+    pub fn of(page: &[u8]) -> Self {
+        if page.iter().all(|&b| b == 0) {
+            return PageHash::ZERO;
+        }
+        PageHash(Sha256::digest(page).into())
+    }
+
+    /// Whether this is the hash recorded for zero pages.
+    pub fn is_zero(&self) -> bool {
+        *self == PageHash::ZERO
+    }
+}
+
+impl fmt::Display for PageHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|b| write!(f, "{:02x}", b))
+    }
+}
+
+impl fmt::Debug for PageHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PageHash({})", self)
+    }
+}
+
+/// The list of page hashes that make up a snapshot.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Manifest {
+    page_size: usize,
+    pages: Vec<PageHash>,
+}
+
+impl Manifest {
+    /// Create a manifest from the hashes of its pages, in order.
+    pub fn new(page_size: usize, pages: Vec<PageHash>) -> Self {
+        Manifest { page_size, pages }
+    }
+
+    /// The size of the pages of the snapshot.
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    /// The hashes of the pages of the snapshot, in order.
+    pub fn pages(&self) -> &[PageHash] {
+        &self.pages
+    }
+
+    /// Write the manifest to `w`.
+    pub fn write_to<W: Write>(&self, mut w: W) -> io::Result<()> {
+        w.write_all(&format::preamble(&MAGIC, VERSION))?;
+        w.write_all(&(self.page_size as u32).to_le_bytes())?;
+        w.write_all(&(self.pages.len() as u64).to_le_bytes())?;
+        for hash in &self.pages {
+            w.write_all(&hash.0)?;
+        }
+        w.flush()
+    }
+
+    /// Read a manifest from `r`.
+    pub fn read_from<R: Read>(mut r: R) -> io::Result<Self> {
+        let mut header = [0; 24];
+        r.read_exact(&mut header)?;
+        format::check_preamble(&header, &MAGIC, VERSION, "page manifest")?;
+        let page_size = u32_at(&header, 12) as usize;
+        if !page_size.is_power_of_two() {
+            return Err(invalid_data(format!("invalid page size {}", page_size)));
+        }
+        let count = u64_at(&header, 16);
+
+        let mut pages = Vec::new();
+        for _ in 0..count {
+            let mut hash = [0; 32];
+            r.read_exact(&mut hash)?;
+            pages.push(PageHash(hash));
+        }
+        Ok(Manifest { page_size, pages })
+    }
+}
+
+/// A directory of pages named by the hash of their contents.
+#[derive(Clone, Debug)]
+pub struct BlobStore {
+    dir: PathBuf,
+}
+
+impl BlobStore {
+    /// Open the blob store in `dir`, creating the directory if it does not exist.
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        fs::create_dir_all(dir.as_ref())?;
+        Ok(BlobStore {
+            dir: dir.as_ref().to_path_buf(),
+        })
+    }
+
+    fn path(&self, hash: &PageHash) -> PathBuf {
+        let hex = hash.to_string();
+        self.dir.join(&hex[..2]).join(&hex[2..])
+    }
+
+    /// Whether the store contains the page with `hash`.
+    pub fn contains(&self, hash: &PageHash) -> bool {
+        hash.is_zero() || self.path(hash).exists()
+    }
+
+    /// Add a page to the store if it is not there yet, and return its hash.
+    pub fn put(&self, page: &[u8]) -> io::Result<PageHash> {
+        let hash = PageHash::of(page);
+        if self.contains(&hash) {
+            return Ok(hash);
+        }
+
+        let path = self.path(&hash);
+        fs::create_dir_all(path.parent().expect("blob path has a parent"))?;
+        // Write to a temporary file first, so that a blob is either complete or absent. The name
+        // is unique to this call, since other threads and processes may store the same page.
+        static NEXT_TMP: AtomicU64 = AtomicU64::new(0);
+        let tmp = path.with_extension(format!(
+            "tmp{}-{}",
+            std::process::id(),
+            NEXT_TMP.fetch_add(1, Ordering::Relaxed)
+        ));
+        let result = fs::write(&tmp, page).and_then(|()| fs::rename(&tmp, &path));
+        if let Err(e) = result {
+            let _ = fs::remove_file(&tmp);
+            // Another writer may have stored the same page in the meantime.
+            if !path.exists() {
+                return Err(e);
+            }
+        }
+        Ok(hash)
+    }
+
+    /// Read the page with `hash` into `buf`, and verify its contents.
+    pub fn get(&self, hash: &PageHash, buf: &mut [u8]) -> io::Result<()> {
+        if hash.is_zero() {
+            buf.fill(0);
+            return Ok(());
+        }
+        let mut file = fs::File::open(self.path(hash))?;
+        file.read_exact(buf)?;
+        if file.read(&mut [0])? != 0 || PageHash::of(buf) != *hash {
+            return Err(invalid_data(format!("blob {} is corrupted", hash)));
+        }
+        Ok(())
+    }
+
+    /// Add every page of `data` to the store, and return the manifest of the snapshot.
+    pub fn put_snapshot(&self, data: &[u8], page_size: usize) -> io::Result<Manifest> {
+        let pages = data.chunks_exact(page_size);
+        if !pages.remainder().is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "data is not a multiple of the page size",
+            ));
+        }
+        let pages = pages
+            .map(|page| self.put(page))
+            .collect::<io::Result<_>>()?;
+        Ok(Manifest::new(page_size, pages))
+    }
+}
+
+struct CacheInner {
+    capacity: usize,
+    tick: u64,
+    pages: HashMap<PageHash, (Arc<[u8]>, u64)>,
+    lru: BTreeMap<u64, PageHash>,
+    hits: u64,
+    misses: u64,
+}
+
+/// A cache of recently used pages, shared between [`DedupSource`]s.
+///
+/// Cloning a `PageCache` returns another handle to the same cache.
+#[derive(Clone)]
+pub struct PageCache {
+    inner: Arc<Mutex<CacheInner>>,
+}
+
+impl PageCache {
+    /// Create a cache that holds up to `capacity` pages, evicting the least recently used ones.
+    pub fn new(capacity: usize) -> Self {
+        PageCache {
+            inner: Arc::new(Mutex::new(CacheInner {
+                capacity,
+                tick: 0,
+                pages: HashMap::new(),
+                lru: BTreeMap::new(),
+                hits: 0,
+                misses: 0,
+            })),
+        }
+    }
+
+    /// The number of lookups that were served from the cache.
+    pub fn hits(&self) -> u64 {
+        self.lock().hits
+    }
+
+    /// The number of lookups that had to read the page from the blob store.
+    pub fn misses(&self) -> u64 {
+        self.lock().misses
+    }
+
+    /// The number of pages currently cached.
+    pub fn len(&self) -> usize {
+        self.lock().pages.len()
+    }
+
+    /// Whether the cache is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, CacheInner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn get(&self, hash: &PageHash) -> Option<Arc<[u8]>> {
+        let mut inner = self.lock();
+        inner.tick += 1;
+        let tick = inner.tick;
+        match inner.pages.get_mut(hash) {
+            Some((page, used)) => {
+                let page = page.clone();
+                let previous = std::mem::replace(used, tick);
+                inner.lru.remove(&previous);
+                inner.lru.insert(tick, *hash);
+                inner.hits += 1;
+                Some(page)
+            }
+            None => {
+                inner.misses += 1;
+                None
+            }
+        }
+    }
+
+    fn insert(&self, hash: PageHash, page: Arc<[u8]>) {
+        let mut inner = self.lock();
+        if inner.capacity == 0 || inner.pages.contains_key(&hash) {
+            return;
+        }
+        while inner.pages.len() >= inner.capacity {
+            let oldest = match inner.lru.keys().next() {
+                Some(&tick) => tick,
+                None => break,
+            };
+            if let Some(evicted) = inner.lru.remove(&oldest) {
+                inner.pages.remove(&evicted);
+            }
+        }
+        inner.tick += 1;
+        let tick = inner.tick;
+        inner.lru.insert(tick, hash);
+        inner.pages.insert(hash, (page, tick));
+    }
+}
+
+impl fmt::Debug for PageCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let inner = self.lock();
+        f.debug_struct("PageCache")
+            .field("capacity", &inner.capacity)
+            .field("len", &inner.pages.len())
+            .field("hits", &inner.hits)
+            .field("misses", &inner.misses)
+            .finish()
+    }
+}
+
+/// The pages of one snapshot, read through a blob store and a shared cache.
+///
+/// Use it with [`resolve_fault`](crate::page_source::resolve_fault) or
+/// [`install_page`](crate::page_source::install_page) to populate a registered range.
+#[derive(Debug)]
+pub struct DedupSource {
+    store: BlobStore,
+    manifest: Manifest,
+    cache: PageCache,
+}
+
+impl DedupSource {
+    /// Create a source for the snapshot described by `manifest`.
+    pub fn new(store: BlobStore, manifest: Manifest, cache: PageCache) -> Self {
+        DedupSource {
+            store,
+            manifest,
+            cache,
+        }
+    }
+
+    /// The manifest of the snapshot.
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+}
+
+impl PageSource for DedupSource {
+    fn read_page(&mut self, index: u64, buf: &mut [u8]) -> io::Result<PageContents> {
+        let hash = *self.manifest.pages.get(index as usize).ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("page {} is out of bounds", index),
+            )
+        })?;
+        if hash.is_zero() {
+            return Ok(PageContents::Zero);
+        }
+
+        let len = buf.len();
+        let buf = buf.get_mut(..self.manifest.page_size).ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("buffer of {} bytes is smaller than the page size", len),
+            )
+        })?;
+        match self.cache.get(&hash) {
+            Some(page) => buf.copy_from_slice(&page),
+            None => {
+                self.store.get(&hash, buf)?;
+                self.cache.insert(hash, Arc::from(&*buf));
+            }
+        }
+        Ok(PageContents::Data)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::page_source::{resolve_fault, PageBuffer};
+    use crate::test_util::Registered;
+    use crate::Result;
+    use std::ptr;
+
+    const PAGE_SIZE: usize = 4096;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("userfaultfd-dedup-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn snapshot(pages: &[u8]) -> Vec<u8> {
+        let mut data = vec![0; pages.len() * PAGE_SIZE];
+        for (page, &fill) in data.chunks_mut(PAGE_SIZE).zip(pages) {
+            page.fill(fill);
+        }
+        data
+    }
+
+    #[test]
+    fn test_shared_pages_read_once() {
+        let dir = temp_dir("shared");
+        let store = BlobStore::open(&dir).unwrap();
+
+        let first = store
+            .put_snapshot(&snapshot(&[1, 2, 0, 3]), PAGE_SIZE)
+            .unwrap();
+        let second = store
+            .put_snapshot(&snapshot(&[1, 2, 4, 3]), PAGE_SIZE)
+            .unwrap();
+        assert_eq!(first.pages()[2], PageHash::ZERO);
+
+        let mut file = Vec::new();
+        second.write_to(&mut file).unwrap();
+        assert_eq!(Manifest::read_from(&file[..]).unwrap(), second);
+
+        let cache = PageCache::new(16);
+        let mut buf = vec![0; PAGE_SIZE];
+        for manifest in [first, second].iter().cloned() {
+            let expected = manifest.clone();
+            let mut source = DedupSource::new(store.clone(), manifest, cache.clone());
+            for (index, hash) in expected.pages().iter().enumerate() {
+                let contents = source.read_page(index as u64, &mut buf).unwrap();
+                if hash.is_zero() {
+                    assert_eq!(contents, PageContents::Zero);
+                } else {
+                    assert_eq!(contents, PageContents::Data);
+                    assert_eq!(PageHash::of(&buf), *hash);
+                }
+            }
+        }
+
+        // Four distinct non-zero pages, three of them shared by both snapshots.
+        assert_eq!(cache.misses(), 4);
+        assert_eq!(cache.hits(), 3);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_put() {
+        let dir = temp_dir("concurrent");
+        let store = BlobStore::open(&dir).unwrap();
+        let page = vec![0x2a; PAGE_SIZE];
+
+        let threads = (0..8)
+            .map(|_| {
+                let (store, page) = (store.clone(), page.clone());
+                std::thread::spawn(move || {
+                    (0..16)
+                        .map(|_| store.put(&page).unwrap())
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            let hashes = thread.join().unwrap();
+            assert!(hashes.iter().all(|hash| *hash == PageHash::of(&page)));
+        }
+
+        let mut buf = vec![0; PAGE_SIZE];
+        store.get(&PageHash::of(&page), &mut buf).unwrap();
+        assert_eq!(buf, page);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_page_size() {
+        let manifest = Manifest::new(3 * PAGE_SIZE, vec![PageHash([1; 32])]);
+        let mut file = Vec::new();
+        manifest.write_to(&mut file).unwrap();
+        assert_eq!(
+            Manifest::read_from(&file[..]).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+
+        let dir = temp_dir("page-size");
+        let manifest = Manifest::new(2 * PAGE_SIZE, vec![PageHash([1; 32])]);
+        let mut source =
+            DedupSource::new(BlobStore::open(&dir).unwrap(), manifest, PageCache::new(1));
+        let mut buf = vec![0; PAGE_SIZE];
+        assert_eq!(
+            source.read_page(0, &mut buf).unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_cache_eviction() {
+        let cache = PageCache::new(2);
+        let page = |b: u8| -> Arc<[u8]> { Arc::from(vec![b; 16]) };
+        let hash = |b: u8| PageHash([b; 32]);
+
+        cache.insert(hash(1), page(1));
+        cache.insert(hash(2), page(2));
+        assert!(cache.get(&hash(1)).is_some());
+        cache.insert(hash(3), page(3));
+
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(&hash(2)).is_none());
+        assert!(cache.get(&hash(1)).is_some());
+        assert!(cache.get(&hash(3)).is_some());
+    }
+
+    #[test]
+    fn test_dedup_fault() -> Result<()> {
+        const PAGES: usize = 2;
+
+        let dir = temp_dir("fault");
+        let store = BlobStore::open(&dir)?;
+        let manifest = store.put_snapshot(&snapshot(&[0, 0x2a]), PAGE_SIZE)?;
+        let mut source = DedupSource::new(store, manifest, PageCache::new(4));
+        let mut buf = PageBuffer::new(PAGE_SIZE, PAGE_SIZE);
+
+        let registered = Registered::new(PAGE_SIZE * PAGES)?;
+        let mapping = registered.mapping;
+        let bytes = registered.serve(
+            PAGES,
+            |ptr| {
+                (0..PAGES)
+                    .map(|i| unsafe { ptr::read_volatile(ptr.add(i * PAGE_SIZE + 1)) })
+                    .collect::<Vec<_>>()
+            },
+            |uffd, addr| {
+                unsafe { resolve_fault(uffd, &mut source, mapping, addr.as_ptr(), &mut buf)? };
+                Ok(())
+            },
+        )?;
+        assert_eq!(bytes, vec![0, 0x2a]);
+
+        fs::remove_dir_all(&dir)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,961 @@
+//! Emulation of userfaultfd with `mprotect` and `SIGSEGV`, for systems without userfaultfd.
+//!
+//! Some sandboxes, such as locked-down containers or gVisor, provide neither `/dev/userfaultfd`
+//! nor the `userfaultfd(2)` system call. [`EmulatedUffd`] implements [`UffdBackend`] without
+//! them, so that fault handlers written against the trait keep working:
+//!
+//! - Missing pages of a registered range are mapped `PROT_NONE`. A process-wide `SIGSEGV` handler
+//!   turns accesses to them into `Event::Pagefault`s, which are read from a pipe, and blocks the
+//!   faulting thread until the range is woken, as the kernel would.
+//! - `copy` and `zeropage` write the page through `/proc/self/mem`, which bypasses its
+//!   protection, and only then make it accessible, so that other threads never observe a partly
+//!   written page.
+//! - Other `SIGSEGV`s, including repeated faults on pages that are already populated, are
+//!   forwarded to the handler that was installed before, or to the default action.
+//! - Write protection is emulated by mapping pages `PROT_READ`.
+//!
+//! Use [`UffdBuilder::emulate`](crate::UffdBuilder::emulate) to create an emulated object, or
+//! [`UffdBuilder::create_or_emulate`](crate::UffdBuilder::create_or_emulate) to fall back to one
+//! only when userfaultfd is unavailable.
+//!
+//! The emulation has limits that the kernel implementation doesn't:
+//!
+//! - Only private anonymous mappings that are readable and writable are supported, and
+//!   unregistering a range makes it readable and writable again.
+//! - Faults are only caught for accesses from user mode. A system call that accesses a missing
+//!   page fails with `EFAULT` instead of blocking.
+//! - Only page faults are reported; there are no fork, remap, remove or unmap events.
+//! - Waking a range wakes every thread that waits in the same registered range.
+//! - At most [`MAX_REGIONS`] ranges can be registered in a process at the same time.
+//!
+//! This module requires the `emulation` feature.
+
+use crate::backend::UffdBackend;
+use crate::error::{Error, Result};
+use crate::signal::{self, ErrnoGuard};
+#[cfg(feature = "linux5_13")]
+use crate::ContinueMode;
+#[cfg(feature = "linux6_6")]
+use crate::PoisonMode;
+#[cfg(feature = "linux5_7")]
+use crate::WriteProtectMode;
+use crate::{
+    CopyMode, Event, EventBuffer, IoctlFlags, PagefaultFlags, RegisterMode, Uffd, UffdAddr,
+    ZeropageMode,
+};
+use libc::{self, c_int, c_void};
+use nix::errno::Errno;
+use std::cell::Cell;
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::mem::{self, MaybeUninit};
+use std::os::fd::{AsFd, BorrowedFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+use std::sync::atomic::Ordering;
+use std::sync::atomic::{AtomicI32, AtomicPtr, AtomicU32, AtomicU64, AtomicU8, AtomicUsize};
+use std::sync::{Mutex, MutexGuard, Once};
+
+/// The maximum number of ranges that can be registered at the same time, across all
+/// [`EmulatedUffd`] objects.
+pub const MAX_REGIONS: usize = 64;
+
+const MISSING: u8 = 0;
+const PRESENT: u8 = 1;
+#[cfg(feature = "linux5_7")]
+const PROTECTED: u8 = 2;
+
+// Not exported by every version of `libc`.
+const FUTEX_WAIT_PRIVATE: c_int = 128;
+const FUTEX_WAKE_PRIVATE: c_int = 129;
+const SEGV_ACCERR: c_int = 2;
+
+const MSG_SIZE: usize = 16;
+const KIND_MISSING: u8 = 0;
+#[cfg(feature = "linux5_7")]
+const KIND_WRITE_PROTECTED: u8 = 1;
+
+struct Slot {
+    start: AtomicUsize,
+    // A length of zero marks the slot as free. It is written last on registration and first on
+    // unregistration, so the signal handler never observes a partially written slot.
+    len: AtomicUsize,
+    owner: AtomicU64,
+    mode: AtomicU64,
+    // The write end of the owner's event pipe.
+    fd: AtomicI32,
+    // One `MISSING`, `PRESENT` or `PROTECTED` state per page.
+    pages: AtomicPtr<AtomicU8>,
+    // Incremented on every wake; waiting threads sleep on it with `FUTEX_WAIT`.
+    wakes: AtomicU32,
+    // Incremented after every change of page states.
+    changes: AtomicU32,
+    // The number of signal handlers using the slot, which must drop to zero before it is freed.
+    users: AtomicUsize,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_SLOT: Slot = Slot {
+    start: AtomicUsize::new(0),
+    len: AtomicUsize::new(0),
+    owner: AtomicU64::new(0),
+    mode: AtomicU64::new(0),
+    fd: AtomicI32::new(-1),
+    pages: AtomicPtr::new(ptr::null_mut()),
+    wakes: AtomicU32::new(0),
+    changes: AtomicU32::new(0),
+    users: AtomicUsize::new(0),
+};
+
+static SLOTS: [Slot; MAX_REGIONS] = [EMPTY_SLOT; MAX_REGIONS];
+
+// Serializes registration and all operations that change page states; the signal handler only
+// ever reads the slots.
+static REGISTRY_LOCK: Mutex<()> = Mutex::new(());
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+static PAGE_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+static INSTALL: Once = Once::new();
+static mut INSTALL_RESULT: c_int = 0;
+static mut PREVIOUS: MaybeUninit<libc::sigaction> = MaybeUninit::uninit();
+
+thread_local! {
+    // The address and the page state change count of the last fault of this thread that was
+    // retried because its page was already present. Constant initialization without a destructor
+    // makes this safe to use from the signal handler.
+    static RETRIED: Cell<(usize, u32)> = const { Cell::new((0, 0)) };
+}
+
+fn install() -> Result<()> {
+    INSTALL.call_once(|| unsafe {
+        PAGE_SIZE.store(
+            libc::sysconf(libc::_SC_PAGESIZE) as usize,
+            Ordering::Relaxed,
+        );
+
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = handler as extern "C" fn(_, _, _) as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_NODEFER | libc::SA_ONSTACK;
+        libc::sigemptyset(&mut action.sa_mask);
+
+        let previous = ptr::addr_of_mut!(PREVIOUS) as *mut libc::sigaction;
+        if libc::sigaction(libc::SIGSEGV, &action, previous) != 0 {
+            INSTALL_RESULT = Errno::last() as c_int;
+        }
+    });
+
+    match unsafe { INSTALL_RESULT } {
+        0 => Ok(()),
+        errno => Err(Errno::from_i32(errno).into()),
+    }
+}
+
+fn lock() -> MutexGuard<'static, ()> {
+    REGISTRY_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn page_size() -> usize {
+    PAGE_SIZE.load(Ordering::Relaxed)
+}
+
+fn futex_wait(word: &AtomicU32, expected: u32) {
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            word as *const AtomicU32,
+            FUTEX_WAIT_PRIVATE,
+            expected,
+            ptr::null::<libc::timespec>(),
+        )
+    };
+}
+
+fn wake_slot(slot: &Slot) {
+    slot.wakes.fetch_add(1, Ordering::Release);
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            &slot.wakes as *const AtomicU32,
+            FUTEX_WAKE_PRIVATE,
+            i32::MAX,
+        )
+    };
+}
+
+unsafe fn page_states<'a>(slot: &Slot, len: usize) -> &'a [AtomicU8] {
+    std::slice::from_raw_parts(slot.pages.load(Ordering::Acquire), len / page_size())
+}
+
+fn protect(start: usize, len: usize, prot: c_int) -> std::result::Result<(), Errno> {
+    Errno::result(unsafe { libc::mprotect(start as *mut c_void, len, prot) }).map(drop)
+}
+
+/// Whether each of the `count` pages at `start` is populated, either present in memory or swapped
+/// out. Unlike `mincore`, `/proc/self/pagemap` reports swapped out pages, which must not be
+/// mistaken for missing ones.
+fn populated_pages(start: usize, count: usize) -> std::io::Result<Vec<bool>> {
+    const PRESENT_OR_SWAPPED: u64 = 3 << 62;
+
+    let mut entries = vec![0u8; count * 8];
+    File::open("/proc/self/pagemap")?
+        .read_exact_at(&mut entries, (start / page_size() * 8) as u64)?;
+    Ok(entries
+        .chunks_exact(8)
+        .map(|entry| u64::from_ne_bytes(entry.try_into().unwrap()) & PRESENT_OR_SWAPPED != 0)
+        .collect())
+}
+
+/// Call `f` with each run of consecutive pages of `states` for which `select` returns `true`, as
+/// a start address and a length.
+fn for_each_run<E>(
+    start: usize,
+    states: &[AtomicU8],
+    select: impl Fn(u8) -> bool,
+    mut f: impl FnMut(usize, usize) -> std::result::Result<(), E>,
+) -> std::result::Result<(), E> {
+    let mut index = 0;
+    while index < states.len() {
+        if !select(states[index].load(Ordering::Acquire)) {
+            index += 1;
+            continue;
+        }
+        let first = index;
+        while index < states.len() && select(states[index].load(Ordering::Acquire)) {
+            index += 1;
+        }
+        f(start + first * page_size(), (index - first) * page_size())?;
+    }
+    Ok(())
+}
+
+extern "C" fn handler(signum: c_int, info: *mut libc::siginfo_t, context: *mut c_void) {
+    let _errno = ErrnoGuard::new();
+    let addr = unsafe { (*info).si_addr() } as usize;
+
+    // Only protection faults can come from the emulation.
+    if unsafe { (*info).si_code } == SEGV_ACCERR {
+        for slot in SLOTS.iter() {
+            let len = slot.len.load(Ordering::Acquire);
+            let start = slot.start.load(Ordering::Acquire);
+            if len == 0 || addr < start || addr - start >= len {
+                continue;
+            }
+
+            slot.users.fetch_add(1, Ordering::SeqCst);
+            // The slot may have been released and reused while it was being looked up.
+            let handled = slot.len.load(Ordering::SeqCst) == len
+                && slot.start.load(Ordering::SeqCst) == start
+                && unsafe { wait_for_resolution(slot, start, len, addr, context) };
+            slot.users.fetch_sub(1, Ordering::SeqCst);
+            if handled {
+                return;
+            }
+            break;
+        }
+    }
+
+    unsafe {
+        let previous = &*(ptr::addr_of!(PREVIOUS) as *const libc::sigaction);
+        signal::forward(previous, signum, info, context)
+    }
+}
+
+/// Report a fault at `addr` in the range of `slot`, and wait until the range is woken. Returns
+/// `false` if the fault wasn't caused by the emulation.
+unsafe fn wait_for_resolution(
+    slot: &Slot,
+    start: usize,
+    len: usize,
+    addr: usize,
+    context: *mut c_void,
+) -> bool {
+    // The counters are read before the page state, so that a page resolved in between is
+    // either seen as present or wakes this thread.
+    let wakes = slot.wakes.load(Ordering::Acquire);
+    let changes = slot.changes.load(Ordering::Acquire);
+    let page = (addr - start) / page_size();
+    let kind = match page_states(slot, len)[page].load(Ordering::Acquire) {
+        MISSING => KIND_MISSING,
+        #[cfg(feature = "linux5_7")]
+        PROTECTED => KIND_WRITE_PROTECTED,
+        // The page may have been resolved after the access faulted, so retry it. If the retry
+        // faults again without any change of page states in between, the fault wasn't caused by
+        // the emulation.
+        _ => return RETRIED.with(|retried| retried.replace((addr, changes)) != (addr, changes)),
+    };
+
+    let mut msg = [0u8; MSG_SIZE];
+    msg[0] = kind;
+    msg[1] = is_write(kind, context) as u8;
+    msg[4..8].copy_from_slice(&(libc::syscall(libc::SYS_gettid) as u32).to_ne_bytes());
+    msg[8..16].copy_from_slice(&((addr & !(page_size() - 1)) as u64).to_ne_bytes());
+    // Writes of up to `PIPE_BUF` bytes are atomic, so messages are never interleaved.
+    if libc::write(
+        slot.fd.load(Ordering::Relaxed),
+        msg.as_ptr() as *const c_void,
+        MSG_SIZE,
+    ) != MSG_SIZE as isize
+    {
+        return false;
+    }
+
+    while slot.wakes.load(Ordering::Acquire) == wakes && slot.len.load(Ordering::Acquire) != 0 {
+        futex_wait(&slot.wakes, wakes);
+    }
+    true
+}
+
+#[cfg(target_arch = "x86_64")]
+unsafe fn is_write(_kind: u8, context: *mut c_void) -> bool {
+    // Bit 1 of the page fault error code is set for writes.
+    let context = &*(context as *const libc::ucontext_t);
+    context.uc_mcontext.gregs[libc::REG_ERR as usize] & 2 != 0
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+unsafe fn is_write(kind: u8, _context: *mut c_void) -> bool {
+    // Without the error code, only faults on write-protected pages are known to be writes.
+    kind != KIND_MISSING
+}
+
+/// A userfaultfd object emulated with `mprotect` and `SIGSEGV`.
+///
+/// Events are read from a pipe, whose read end is returned by `as_raw_fd`, so the object can be
+/// polled like a [`Uffd`].
+#[derive(Debug)]
+pub struct EmulatedUffd {
+    id: u64,
+    events: OwnedFd,
+    notify: OwnedFd,
+    mem: File,
+}
+
+impl EmulatedUffd {
+    pub(crate) fn new(close_on_exec: bool, non_blocking: bool) -> Result<Self> {
+        install()?;
+
+        let mut fds = [0; 2];
+        Errno::result(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) })?;
+        let (events, notify) =
+            unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+
+        let mut flags = 0;
+        if !close_on_exec {
+            // The write end is only used by the signal handler, so it is never inherited.
+            Errno::result(unsafe { libc::fcntl(fds[0], libc::F_SETFD, 0) })?;
+        }
+        if non_blocking {
+            flags |= libc::O_NONBLOCK;
+        }
+        Errno::result(unsafe { libc::fcntl(fds[0], libc::F_SETFL, flags) })?;
+
+        let mem = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/proc/self/mem")?;
+
+        Ok(EmulatedUffd {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            events,
+            notify,
+            mem,
+        })
+    }
+
+    /// Find the slot registered by this object that contains `[start, start + len)`.
+    fn find(&self, start: *mut c_void, len: usize) -> std::result::Result<&'static Slot, Errno> {
+        let (start, page_size) = (start as usize, page_size());
+        if start % page_size != 0 || len % page_size != 0 {
+            return Err(Errno::EINVAL);
+        }
+        SLOTS
+            .iter()
+            .find(|slot| {
+                let slot_len = slot.len.load(Ordering::Acquire);
+                let slot_start = slot.start.load(Ordering::Acquire);
+                slot_len != 0
+                    && slot.owner.load(Ordering::Relaxed) == self.id
+                    && start >= slot_start
+                    && start + len <= slot_start + slot_len
+            })
+            .ok_or(Errno::ENOENT)
+    }
+
+    /// Write `len` bytes from `src`, or zeros, to the missing pages at `dst`, and make them
+    /// accessible.
+    unsafe fn fill(
+        &self,
+        src: Option<*const c_void>,
+        dst: *mut c_void,
+        len: usize,
+        write_protect: bool,
+        wake: bool,
+    ) -> std::result::Result<usize, Errno> {
+        let _guard = lock();
+        let slot = self.find(dst, len)?;
+        let slot_start = slot.start.load(Ordering::Relaxed);
+        let first = (dst as usize - slot_start) / page_size();
+        let states =
+            &page_states(slot, slot.len.load(Ordering::Relaxed))[first..][..len / page_size()];
+        if states
+            .iter()
+            .any(|state| state.load(Ordering::Acquire) != MISSING)
+        {
+            return Err(Errno::EEXIST);
+        }
+
+        let zeros;
+        let data = match src {
+            Some(src) => std::slice::from_raw_parts(src as *const u8, len),
+            None => {
+                zeros = vec![0; len];
+                &zeros
+            }
+        };
+        self.mem
+            .write_all_at(data, dst as u64)
+            .map_err(|e| Errno::from_i32(e.raw_os_error().unwrap_or(libc::EIO)))?;
+
+        #[cfg(feature = "linux5_7")]
+        let (prot, state) = if write_protect {
+            (libc::PROT_READ, PROTECTED)
+        } else {
+            (libc::PROT_READ | libc::PROT_WRITE, PRESENT)
+        };
+        #[cfg(not(feature = "linux5_7"))]
+        let (prot, state) = {
+            let _ = write_protect;
+            (libc::PROT_READ | libc::PROT_WRITE, PRESENT)
+        };
+        protect(dst as usize, len, prot)?;
+        for page in states {
+            page.store(state, Ordering::Release);
+        }
+        slot.changes.fetch_add(1, Ordering::Release);
+        if wake {
+            wake_slot(slot);
+        }
+        Ok(len)
+    }
+
+    /// Make the range readable and writable again, and free its slot.
+    fn release(slot: &Slot) {
+        let start = slot.start.load(Ordering::Relaxed);
+        let len = slot.len.load(Ordering::Relaxed);
+        let _ = protect(start, len, libc::PROT_READ | libc::PROT_WRITE);
+
+        slot.len.store(0, Ordering::SeqCst);
+        while slot.users.load(Ordering::SeqCst) != 0 {
+            wake_slot(slot);
+            std::thread::yield_now();
+        }
+
+        let pages = slot.pages.swap(ptr::null_mut(), Ordering::AcqRel);
+        drop(unsafe { Box::from_raw(ptr::slice_from_raw_parts_mut(pages, len / page_size())) });
+        slot.owner.store(0, Ordering::Relaxed);
+    }
+
+    fn decode(msg: &[u8]) -> Event {
+        let mut flags = PagefaultFlags::empty();
+        #[cfg(feature = "linux5_7")]
+        if msg[0] == KIND_WRITE_PROTECTED {
+            flags |= PagefaultFlags::WRITE_PROTECT;
+        }
+        if msg[1] != 0 {
+            flags |= PagefaultFlags::WRITE;
+        }
+        let addr = u64::from_ne_bytes(msg[8..16].try_into().unwrap());
+        Event::Pagefault {
+            kind: flags.kind(),
+            rw: flags.rw(),
+            flags,
+            addr: UffdAddr::new(addr as usize),
+            #[cfg(feature = "linux4_14")]
+            thread_id: nix::unistd::Pid::from_raw(
+                u32::from_ne_bytes(msg[4..8].try_into().unwrap()) as i32,
+            ),
+        }
+    }
+
+    fn read_messages(&self, count: usize) -> Result<Vec<Event>> {
+        let mut buf = vec![0; count * MSG_SIZE];
+        match nix::unistd::read(self.events.as_raw_fd(), &mut buf) {
+            Err(Errno::EAGAIN) => Ok(Vec::new()),
+            Err(e) => Err(Error::SystemError(e)),
+            Ok(0) => Err(Error::ReadEof),
+            Ok(read) => Ok(buf[..read]
+                .chunks_exact(MSG_SIZE)
+                .map(Self::decode)
+                .collect()),
+        }
+    }
+}
+
+impl Drop for EmulatedUffd {
+    fn drop(&mut self) {
+        let _guard = lock();
+        for slot in SLOTS.iter() {
+            if slot.len.load(Ordering::Acquire) != 0
+                && slot.owner.load(Ordering::Relaxed) == self.id
+            {
+                Self::release(slot);
+            }
+        }
+    }
+}
+
+impl AsFd for EmulatedUffd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.events.as_fd()
+    }
+}
+
+impl AsRawFd for EmulatedUffd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.events.as_raw_fd()
+    }
+}
+
+impl UffdBackend for EmulatedUffd {
+    fn register_with_mode(
+        &self,
+        start: *mut c_void,
+        len: usize,
+        mode: RegisterMode,
+    ) -> Result<IoctlFlags> {
+        #[cfg(feature = "linux5_7")]
+        let supported = RegisterMode::MISSING | RegisterMode::WRITE_PROTECT;
+        #[cfg(not(feature = "linux5_7"))]
+        let supported = RegisterMode::MISSING;
+
+        let (begin, page_size) = (start as usize, page_size());
+        if len == 0
+            || begin % page_size != 0
+            || len % page_size != 0
+            || mode.is_empty()
+            || !supported.contains(mode)
+        {
+            return Err(Errno::EINVAL.into());
+        }
+
+        let _guard = lock();
+        if SLOTS.iter().any(|slot| {
+            let slot_len = slot.len.load(Ordering::Acquire);
+            let slot_start = slot.start.load(Ordering::Acquire);
+            slot_len != 0 && begin < slot_start + slot_len && slot_start < begin + len
+        }) {
+            return Err(Errno::EBUSY.into());
+        }
+        let slot = SLOTS
+            .iter()
+            .find(|slot| slot.len.load(Ordering::Acquire) == 0)
+            .ok_or(Errno::ENOSPC)?;
+
+        // Pages that are already populated aren't missing, as with the kernel implementation.
+        let states = populated_pages(begin, len / page_size)?
+            .into_iter()
+            .map(|populated| {
+                if mode.contains(RegisterMode::MISSING) && !populated {
+                    AtomicU8::new(MISSING)
+                } else {
+                    AtomicU8::new(PRESENT)
+                }
+            })
+            .collect::<Box<[_]>>();
+        for_each_run(
+            begin,
+            &states,
+            |state| state == MISSING,
+            |start, len| protect(start, len, libc::PROT_NONE),
+        )?;
+
+        slot.start.store(begin, Ordering::Release);
+        slot.owner.store(self.id, Ordering::Relaxed);
+        slot.mode.store(mode.bits(), Ordering::Relaxed);
+        slot.fd.store(self.notify.as_raw_fd(), Ordering::Relaxed);
+        slot.pages
+            .store(Box::into_raw(states) as *mut AtomicU8, Ordering::Release);
+        slot.len.store(len, Ordering::Release);
+
+        #[allow(unused_mut)]
+        let mut ioctls = IoctlFlags::WAKE | IoctlFlags::COPY | IoctlFlags::ZEROPAGE;
+        #[cfg(feature = "linux5_7")]
+        if mode.contains(RegisterMode::WRITE_PROTECT) {
+            ioctls |= IoctlFlags::WRITE_PROTECT;
+        }
+        Ok(ioctls)
+    }
+
+    fn unregister(&self, start: *mut c_void, len: usize) -> Result<()> {
+        let _guard = lock();
+        let slot = self.find(start, len)?;
+        if slot.start.load(Ordering::Relaxed) != start as usize
+            || slot.len.load(Ordering::Relaxed) != len
+        {
+            // Splitting registered ranges isn't supported.
+            return Err(Errno::EINVAL.into());
+        }
+        Self::release(slot);
+        Ok(())
+    }
+
+    unsafe fn copy_with_mode(
+        &self,
+        src: *const c_void,
+        dst: *mut c_void,
+        len: usize,
+        mode: CopyMode,
+    ) -> Result<usize> {
+        #[cfg(feature = "linux5_7")]
+        let write_protect = mode.contains(CopyMode::WRITE_PROTECT);
+        #[cfg(not(feature = "linux5_7"))]
+        let write_protect = false;
+        let wake = !mode.contains(CopyMode::DONTWAKE);
+        self.fill(Some(src), dst, len, write_protect, wake)
+            .map_err(Error::CopyFailed)
+    }
+
+    unsafe fn zeropage_with_mode(
+        &self,
+        start: *mut c_void,
+        len: usize,
+        mode: ZeropageMode,
+    ) -> Result<usize> {
+        let wake = !mode.contains(ZeropageMode::DONTWAKE);
+        self.fill(None, start, len, false, wake)
+            .map_err(Error::ZeropageFailed)
+    }
+
+    fn wake(&self, start: *mut c_void, len: usize) -> Result<()> {
+        wake_slot(self.find(start, len)?);
+        Ok(())
+    }
+
+    #[cfg(feature = "linux5_7")]
+    fn write_protect_with_mode(
+        &self,
+        start: *mut c_void,
+        len: usize,
+        mode: WriteProtectMode,
+    ) -> Result<()> {
+        let _guard = lock();
+        let slot = self.find(start, len)?;
+        if !RegisterMode::from_bits_retain(slot.mode.load(Ordering::Relaxed))
+            .contains(RegisterMode::WRITE_PROTECT)
+        {
+            return Err(Errno::ENOENT.into());
+        }
+        let first = (start as usize - slot.start.load(Ordering::Relaxed)) / page_size();
+        let states = &unsafe { page_states(slot, slot.len.load(Ordering::Relaxed)) }[first..]
+            [..len / page_size()];
+
+        // The protection changes before the state, so that a write racing with it is never
+        // missed; a fault that sees the old state is simply retried.
+        let (from, to, prot) = if mode.contains(WriteProtectMode::WRITE_PROTECT) {
+            (PRESENT, PROTECTED, libc::PROT_READ)
+        } else {
+            (PROTECTED, PRESENT, libc::PROT_READ | libc::PROT_WRITE)
+        };
+        for_each_run(
+            start as usize,
+            states,
+            |state| state == from,
+            |begin, len| {
+                protect(begin, len, prot)?;
+                let first = (begin - start as usize) / page_size();
+                for state in &states[first..][..len / page_size()] {
+                    state.store(to, Ordering::Release);
+                }
+                slot.changes.fetch_add(1, Ordering::Release);
+                Ok::<(), Errno>(())
+            },
+        )?;
+
+        if !mode.intersects(WriteProtectMode::WRITE_PROTECT | WriteProtectMode::DONTWAKE) {
+            wake_slot(slot);
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "linux5_13")]
+    fn continue_with_mode(
+        &self,
+        _start: *mut c_void,
+        _len: usize,
+        _mode: ContinueMode,
+    ) -> Result<u64> {
+        // Minor faults can't be registered, so there is never anything to continue.
+        Err(Errno::EINVAL.into())
+    }
+
+    #[cfg(feature = "linux6_6")]
+    fn poison_with_mode(&self, _start: *mut c_void, _len: usize, _mode: PoisonMode) -> Result<u64> {
+        // Poisoned pages would need a `SIGBUS` of their own, which the emulation doesn't raise.
+        Err(Errno::EINVAL.into())
+    }
+
+    fn read_event(&self) -> Result<Option<Event>> {
+        Ok(self.read_messages(1)?.pop())
+    }
+
+    fn read_events(&self, buf: &mut EventBuffer) -> Result<Vec<Event>> {
+        self.read_messages(buf.0.len())
+    }
+}
+
+/// Either a kernel userfaultfd object, or an emulated one.
+///
+/// This is returned by [`UffdBuilder::create_or_emulate`](crate::UffdBuilder::create_or_emulate).
+#[derive(Debug)]
+pub enum AnyUffd {
+    /// A userfaultfd object created by the kernel.
+    Kernel(Uffd),
+    /// An emulated userfaultfd object.
+    Emulated(EmulatedUffd),
+}
+
+impl AnyUffd {
+    /// Whether this object is emulated.
+    pub fn is_emulated(&self) -> bool {
+        matches!(self, AnyUffd::Emulated(_))
+    }
+
+    fn backend(&self) -> &dyn UffdBackend {
+        match self {
+            AnyUffd::Kernel(uffd) => uffd,
+            AnyUffd::Emulated(uffd) => uffd,
+        }
+    }
+}
+
+impl AsFd for AnyUffd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        match self {
+            AnyUffd::Kernel(uffd) => uffd.as_fd(),
+            AnyUffd::Emulated(uffd) => uffd.as_fd(),
+        }
+    }
+}
+
+impl AsRawFd for AnyUffd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.as_fd().as_raw_fd()
+    }
+}
+
+impl UffdBackend for AnyUffd {
+    fn register_with_mode(
+        &self,
+        start: *mut c_void,
+        len: usize,
+        mode: RegisterMode,
+    ) -> Result<IoctlFlags> {
+        self.backend().register_with_mode(start, len, mode)
+    }
+
+    fn unregister(&self, start: *mut c_void, len: usize) -> Result<()> {
+        self.backend().unregister(start, len)
+    }
+
+    unsafe fn copy_with_mode(
+        &self,
+        src: *const c_void,
+        dst: *mut c_void,
+        len: usize,
+        mode: CopyMode,
+    ) -> Result<usize> {
+        self.backend().copy_with_mode(src, dst, len, mode)
+    }
+
+    unsafe fn zeropage_with_mode(
+        &self,
+        start: *mut c_void,
+        len: usize,
+        mode: ZeropageMode,
+    ) -> Result<usize> {
+        self.backend().zeropage_with_mode(start, len, mode)
+    }
+
+    fn wake(&self, start: *mut c_void, len: usize) -> Result<()> {
+        self.backend().wake(start, len)
+    }
+
+    #[cfg(feature = "linux5_7")]
+    fn write_protect_with_mode(
+        &self,
+        start: *mut c_void,
+        len: usize,
+        mode: WriteProtectMode,
+    ) -> Result<()> {
+        self.backend().write_protect_with_mode(start, len, mode)
+    }
+
+    #[cfg(feature = "linux5_13")]
+    fn continue_with_mode(
+        &self,
+        start: *mut c_void,
+        len: usize,
+        mode: ContinueMode,
+    ) -> Result<u64> {
+        self.backend().continue_with_mode(start, len, mode)
+    }
+
+    #[cfg(feature = "linux6_6")]
+    fn poison_with_mode(&self, start: *mut c_void, len: usize, mode: PoisonMode) -> Result<u64> {
+        self.backend().poison_with_mode(start, len, mode)
+    }
+
+    fn read_event(&self) -> Result<Option<Event>> {
+        self.backend().read_event()
+    }
+
+    fn read_events(&self, buf: &mut EventBuffer) -> Result<Vec<Event>> {
+        self.backend().read_events(buf)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{FaultKind, ReadWrite, UffdBuilder};
+    use std::thread;
+
+    const PAGES: usize = 4;
+
+    fn serve(
+        uffd: &EmulatedUffd,
+        thread: &thread::JoinHandle<impl Send>,
+        page: &[u8],
+    ) -> Result<Vec<Event>> {
+        let mut events = Vec::new();
+        while !thread.is_finished() {
+            if let Some(event) = uffd.read_event()? {
+                if let Event::Pagefault {
+                    addr,
+                    kind: FaultKind::Missing,
+                    ..
+                } = event
+                {
+                    match unsafe {
+                        uffd.copy(
+                            page.as_ptr() as *const c_void,
+                            addr.as_ptr(),
+                            page.len(),
+                            true,
+                        )
+                    } {
+                        Ok(_) => {}
+                        Err(Error::CopyFailed(Errno::EEXIST)) => {
+                            uffd.wake(addr.as_ptr(), page.len())?
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+                #[cfg(feature = "linux5_7")]
+                if let Event::Pagefault {
+                    addr,
+                    kind: FaultKind::WriteProtected,
+                    ..
+                } = event
+                {
+                    uffd.remove_write_protection(addr.as_ptr(), page.len(), true)?;
+                }
+                events.push(event);
+            }
+        }
+        Ok(events)
+    }
+
+    #[test]
+    fn test_emulated_faults() -> Result<()> {
+        let uffd = UffdBuilder::new()
+            .close_on_exec(true)
+            .non_blocking(true)
+            .emulate()?;
+        let page_size = page_size();
+        let page = vec![0x2a; page_size];
+
+        unsafe {
+            let mapping = libc::mmap(
+                ptr::null_mut(),
+                page_size * PAGES,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANON,
+                -1,
+                0,
+            );
+
+            assert!(!mapping.is_null());
+
+            // Page 0 is populated before registration, so it never faults.
+            *(mapping as *mut u8) = 7;
+
+            #[cfg(feature = "linux5_7")]
+            let mode = RegisterMode::MISSING | RegisterMode::WRITE_PROTECT;
+            #[cfg(not(feature = "linux5_7"))]
+            let mode = RegisterMode::MISSING;
+            uffd.register_with_mode(mapping, page_size * PAGES, mode)?;
+
+            let ptr = mapping as usize;
+            let reader = thread::spawn(move || {
+                (0..PAGES)
+                    .map(|i| ptr::read_volatile((ptr as *const u8).add(i * page_size + 1)))
+                    .collect::<Vec<_>>()
+            });
+            let events = serve(&uffd, &reader, &page)?;
+            assert_eq!(reader.join().unwrap(), vec![0, 0x2a, 0x2a, 0x2a]);
+            assert_eq!(events.len(), PAGES - 1);
+            for (i, event) in events.iter().enumerate() {
+                match *event {
+                    Event::Pagefault { kind, rw, addr, .. } => {
+                        assert_eq!(kind, FaultKind::Missing);
+                        assert_eq!(rw, ReadWrite::Read);
+                        assert_eq!(addr.as_usize(), ptr + (i + 1) * page_size);
+                    }
+                    _ => panic!("unexpected event"),
+                }
+            }
+            assert!(matches!(
+                uffd.copy(page.as_ptr() as *const c_void, mapping, page_size, true),
+                Err(Error::CopyFailed(Errno::EEXIST))
+            ));
+
+            #[cfg(feature = "linux5_7")]
+            {
+                uffd.write_protect(mapping, page_size * PAGES)?;
+                let writer = thread::spawn(move || {
+                    ptr::write_volatile((ptr as *mut u8).add(2 * page_size), 42);
+                });
+                let events = serve(&uffd, &writer, &page)?;
+                writer.join().unwrap();
+                assert!(matches!(
+                    events[..],
+                    [Event::Pagefault {
+                        kind: FaultKind::WriteProtected,
+                        rw: ReadWrite::Write,
+                        ..
+                    }]
+                ));
+                assert_eq!(*(mapping as *const u8).add(2 * page_size), 42);
+            }
+
+            uffd.unregister(mapping, page_size * PAGES)?;
+            *(mapping as *mut u8).add(page_size) = 1;
+
+            assert_eq!(libc::munmap(mapping, page_size * PAGES), 0);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_create_or_emulate() -> Result<()> {
+        // Emulation is only used where userfaultfd is unavailable.
+        let available = UffdBuilder::new().close_on_exec(true).create().is_ok();
+        let uffd = UffdBuilder::new().close_on_exec(true).create_or_emulate()?;
+        assert_eq!(uffd.is_emulated(), !available);
+        Ok(())
+    }
+}
@@ -0,0 +1,421 @@
+//! An encrypted snapshot format with per-page authenticated decryption.
+//!
+//! [`EncryptedPageWriter`] encrypts every page of a snapshot independently with
+//! XChaCha20-Poly1305, and [`EncryptedPageStore`] decrypts and authenticates a single page in the
+//! fault path, right before it is installed with [`Uffd::copy`](crate::Uffd::copy). The
+//! plaintext never touches the disk, and only stays in memory for as long as it takes to copy it
+//! into place.
+//!
+//! The nonce of a page is derived from a random store ID and the page index, so that no nonce is
+//! ever reused under the same key and a page cannot be moved to another index or another store
+//! without failing authentication. A page that fails authentication is never installed. With the
+//! `linux6_6` feature, [`EncryptedPageStore::resolve_fault`] poisons it instead, so that the
+//! faulting thread receives `SIGBUS`; either way, it reports `Error::PageAuthenticationFailed`.
+//!
+//! This module requires the `encryption` feature.
+//!
+//! # Format
+//!
+//! All integers are little-endian. An encrypted store starts with a header, which is also the
+//! associated data of every page:
+//!
+//! | Size | Field                       |
+//! |------|-----------------------------|
+//! | 8    | magic, `b"UFFDENCR"`        |
+//! | 4    | format version, currently 1 |
+//! | 4    | page size in bytes          |
+//! | 16   | random store ID             |
+//!
+//! followed by the pages, each as its ciphertext followed by a 16-byte authentication tag. The
+//! nonce of page `i` is the store ID followed by `i` as a 64-bit integer.
+
+use crate::backend::UffdBackend;
+use crate::error::{Error, Result};
+use crate::format::{self, invalid_data, u32_at};
+use crate::page_source::{PageBuffer, PageContents, PageSource};
+use crate::CopyMode;
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::{Key, Tag, XChaCha20Poly1305, XNonce};
+use libc::c_void;
+use nix::errno::Errno;
+use std::convert::TryFrom;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+
+/// The magic bytes at the start of every encrypted store.
+pub const MAGIC: [u8; 8] = *b"UFFDENCR";
+
+/// The encrypted store format version written by [`EncryptedPageWriter`].
+pub const VERSION: u32 = 1;
+
+/// The length of an encryption key in bytes.
+pub const KEY_LEN: usize = 32;
+
+const HEADER_SIZE: usize = 32;
+const TAG_SIZE: usize = 16;
+
+fn nonce(store_id: &[u8], index: u64) -> XNonce {
+    let mut nonce = XNonce::default();
+    nonce[..16].copy_from_slice(store_id);
+    nonce[16..].copy_from_slice(&index.to_le_bytes());
+    nonce
+}
+
+fn random_store_id() -> io::Result<[u8; 16]> {
+    let mut id = [0; 16];
+    let mut filled = 0;
+    while filled < id.len() {
+        let r = unsafe {
+            libc::getrandom(
+                id[filled..].as_mut_ptr() as *mut c_void,
+                id.len() - filled,
+                0,
+            )
+        };
+        match Errno::result(r) {
+            Ok(n) => filled += n as usize,
+            Err(Errno::EINTR) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(id)
+}
+
+/// Writes an encrypted store to an underlying writer.
+///
+/// Pages are numbered in the order they are written, starting at zero.
+pub struct EncryptedPageWriter<W: Write> {
+    inner: W,
+    cipher: XChaCha20Poly1305,
+    header: [u8; HEADER_SIZE],
+    page_size: usize,
+    buf: Vec<u8>,
+    next: u64,
+}
+
+impl<W: Write> EncryptedPageWriter<W> {
+    /// Write the header of a store with pages of `page_size` bytes, encrypted with `key`.
+    pub fn new(mut inner: W, key: &[u8; KEY_LEN], page_size: usize) -> io::Result<Self> {
+        let size = u32::try_from(page_size)
+            .ok()
+            .filter(|size| size.is_power_of_two())
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "invalid page size"))?;
+
+        let mut header = [0; HEADER_SIZE];
+        header[..format::PREAMBLE_SIZE].copy_from_slice(&format::preamble(&MAGIC, VERSION));
+        header[12..16].copy_from_slice(&size.to_le_bytes());
+        header[16..32].copy_from_slice(&random_store_id()?);
+        inner.write_all(&header)?;
+
+        Ok(EncryptedPageWriter {
+            inner,
+            cipher: XChaCha20Poly1305::new(Key::from_slice(key)),
+            header,
+            page_size,
+            buf: Vec::with_capacity(page_size),
+            next: 0,
+        })
+    }
+
+    /// Encrypt and append the next page, which must be exactly one page long.
+    ///
+    /// The writer keeps no copy of the plaintext once this returns, so wiping `page` is up to the
+    /// caller.
+    pub fn write_page(&mut self, page: &[u8]) -> io::Result<()> {
+        if page.len() != self.page_size {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("page is {} bytes instead of {}", page.len(), self.page_size),
+            ));
+        }
+
+        self.buf.clear();
+        self.buf.extend_from_slice(page);
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(
+                &nonce(&self.header[16..], self.next),
+                &self.header,
+                &mut self.buf,
+            )
+            .map_err(|_| {
+                // The scratch buffer still holds the plaintext if encryption failed.
+                self.buf.iter_mut().for_each(|b| *b = 0);
+                io::Error::new(ErrorKind::InvalidInput, "page encryption failed")
+            })?;
+        self.inner.write_all(&self.buf)?;
+        self.inner.write_all(&tag)?;
+        self.next += 1;
+        Ok(())
+    }
+
+    /// Encrypt and append every page of `data`, whose length must be a multiple of the page size.
+    pub fn write_pages(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut pages = data.chunks_exact(self.page_size);
+        if !pages.remainder().is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "data is not a multiple of the page size",
+            ));
+        }
+        pages.try_for_each(|page| self.write_page(page))
+    }
+
+    /// Flush the underlying writer and return it.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+/// Random access to the pages of an encrypted store, for resolving faults.
+///
+/// `EncryptedPageStore` also implements [`PageSource`], which reports a page that fails
+/// authentication as an `InvalidData` I/O error wrapping `Error::PageAuthenticationFailed`.
+pub struct EncryptedPageStore<R: Read + Seek> {
+    pages: Pages<R>,
+    buf: PageBuffer,
+}
+
+struct Pages<R> {
+    inner: R,
+    cipher: XChaCha20Poly1305,
+    header: [u8; HEADER_SIZE],
+    page_size: usize,
+    page_count: u64,
+}
+
+impl<R: Read + Seek> Pages<R> {
+    fn decrypt(&mut self, index: u64, buf: &mut [u8]) -> Result<()> {
+        if index >= self.page_count {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("page {} is out of bounds", index),
+            )
+            .into());
+        }
+
+        let buf = &mut buf[..self.page_size];
+        let mut tag = Tag::default();
+        let offset = HEADER_SIZE as u64 + index * (self.page_size + TAG_SIZE) as u64;
+        self.inner.seek(SeekFrom::Start(offset))?;
+        self.inner.read_exact(buf)?;
+        self.inner.read_exact(&mut tag)?;
+
+        self.cipher
+            .decrypt_in_place_detached(&nonce(&self.header[16..], index), &self.header, buf, &tag)
+            .map_err(|_| Error::PageAuthenticationFailed {
+                page: index,
+                poisoned: false,
+            })
+    }
+}
+
+impl<R: Read + Seek> EncryptedPageStore<R> {
+    /// Read and validate the header of a store whose pages are encrypted with `key`.
+    pub fn open(mut inner: R, key: &[u8; KEY_LEN]) -> io::Result<Self> {
+        let mut header = [0; HEADER_SIZE];
+        inner.seek(SeekFrom::Start(0))?;
+        inner.read_exact(&mut header)?;
+        format::check_preamble(&header, &MAGIC, VERSION, "encrypted page store")?;
+        let page_size = u32_at(&header, 12) as usize;
+        if !page_size.is_power_of_two() {
+            return Err(invalid_data(format!("invalid page size {}", page_size)));
+        }
+
+        let len = inner.seek(SeekFrom::End(0))? - HEADER_SIZE as u64;
+        let stride = (page_size + TAG_SIZE) as u64;
+        let page_count = len / stride;
+        if page_count * stride != len {
+            return Err(invalid_data("truncated encrypted page store".to_string()));
+        }
+
+        Ok(EncryptedPageStore {
+            pages: Pages {
+                inner,
+                cipher: XChaCha20Poly1305::new(Key::from_slice(key)),
+                header,
+                page_size,
+                page_count,
+            },
+            buf: PageBuffer::new(page_size, page_size),
+        })
+    }
+
+    /// The size of the pages in this store.
+    pub fn page_size(&self) -> usize {
+        self.pages.page_size
+    }
+
+    /// The number of pages in this store.
+    pub fn page_count(&self) -> u64 {
+        self.pages.page_count
+    }
+
+    /// Read, decrypt and authenticate page `index` into `buf`.
+    ///
+    /// Returns `Error::PageAuthenticationFailed` if the page was tampered with or encrypted with
+    /// another key. The contents of `buf` are unspecified in that case.
+    pub fn decrypt_page(&mut self, index: u64, buf: &mut [u8]) -> Result<()> {
+        self.pages.decrypt(index, buf)
+    }
+
+    /// Resolve a missing fault at `addr` in a region that starts at `base` and holds the pages of
+    /// this store.
+    ///
+    /// The faulting page is decrypted into an internal buffer, installed with `UFFDIO_COPY`, and
+    /// the buffer is wiped again. If another thread installed the page concurrently, the faulting
+    /// thread is woken instead.
+    ///
+    /// If the page fails authentication, it is not installed and
+    /// `Error::PageAuthenticationFailed` is returned. With the `linux6_6` feature the page is
+    /// poisoned first, so that the faulting thread receives `SIGBUS`. Otherwise, the thread is
+    /// left waiting, and it is up to the caller to decide how to proceed.
+    ///
+    /// # Safety
+    ///
+    /// `base` must be the start of a range registered with `uffd` that is at least
+    /// `page_count() * page_size()` bytes long, and `addr` must lie within it.
+    pub unsafe fn resolve_fault<U: UffdBackend + ?Sized>(
+        &mut self,
+        uffd: &U,
+        base: *mut c_void,
+        addr: *mut c_void,
+    ) -> Result<()> {
+        let page_size = self.pages.page_size;
+        let index = ((addr as usize - base as usize) / page_size) as u64;
+        let dst = (base as usize + index as usize * page_size) as *mut c_void;
+
+        let result = self.pages.decrypt(index, &mut self.buf).and_then(|()| {
+            uffd.copy_with_mode(self.buf.as_ptr(), dst, page_size, CopyMode::empty())
+        });
+        self.buf.fill(0);
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(Error::CopyFailed(Errno::EEXIST)) => uffd.wake(dst, page_size),
+            Err(Error::PageAuthenticationFailed { page, .. }) => {
+                #[cfg(feature = "linux6_6")]
+                let poisoned = uffd.poison(dst, page_size, true).is_ok();
+                #[cfg(not(feature = "linux6_6"))]
+                let poisoned = false;
+                Err(Error::PageAuthenticationFailed { page, poisoned })
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl<R: Read + Seek> PageSource for EncryptedPageStore<R> {
+    fn read_page(&mut self, index: u64, buf: &mut [u8]) -> io::Result<PageContents> {
+        match self.decrypt_page(index, buf) {
+            Ok(()) => Ok(PageContents::Data),
+            Err(Error::Io(e)) => Err(e),
+            Err(e) => Err(io::Error::new(ErrorKind::InvalidData, e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fake::{Call, FakeUffd};
+    use crate::test_util::Registered;
+    use std::io::Cursor;
+    use std::ptr;
+
+    const PAGE_SIZE: usize = 4096;
+    const KEY: [u8; KEY_LEN] = [7; KEY_LEN];
+
+    fn encrypted(data: &[u8]) -> Vec<u8> {
+        let mut writer = EncryptedPageWriter::new(Vec::new(), &KEY, PAGE_SIZE).unwrap();
+        writer.write_pages(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn test_round_trip_and_tampering() {
+        let mut data = vec![0; PAGE_SIZE * 3];
+        for (i, page) in data.chunks_mut(PAGE_SIZE).enumerate() {
+            page.fill(i as u8 + 1);
+        }
+        let mut file = encrypted(&data);
+        // The ciphertext doesn't contain the plaintext.
+        assert!(!file.windows(64).any(|w| w == &data[..64]));
+
+        let mut store = EncryptedPageStore::open(Cursor::new(file.clone()), &KEY).unwrap();
+        assert_eq!(store.page_count(), 3);
+        let mut buf = vec![0; PAGE_SIZE];
+        for i in (0..3).rev() {
+            store.decrypt_page(i, &mut buf).unwrap();
+            assert_eq!(&buf[..], &data[i as usize * PAGE_SIZE..][..PAGE_SIZE]);
+        }
+
+        let mut other = EncryptedPageStore::open(Cursor::new(file.clone()), &[8; KEY_LEN]).unwrap();
+        assert!(matches!(
+            other.decrypt_page(0, &mut buf),
+            Err(Error::PageAuthenticationFailed {
+                page: 0,
+                poisoned: false
+            })
+        ));
+
+        file[HEADER_SIZE + (PAGE_SIZE + TAG_SIZE) + 10] ^= 1;
+        let mut store = EncryptedPageStore::open(Cursor::new(file), &KEY).unwrap();
+        store.decrypt_page(0, &mut buf).unwrap();
+        assert!(matches!(
+            store.decrypt_page(1, &mut buf),
+            Err(Error::PageAuthenticationFailed { page: 1, .. })
+        ));
+        let err = store.read_page(1, &mut buf).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_encrypted_fault() -> Result<()> {
+        let data = vec![0x2a; PAGE_SIZE];
+        let mut store = EncryptedPageStore::open(Cursor::new(encrypted(&data)), &KEY)?;
+
+        let registered = Registered::new(PAGE_SIZE)?;
+        let mapping = registered.mapping;
+        let byte = registered.serve(
+            1,
+            |ptr| unsafe { ptr::read_volatile(ptr.add(100)) },
+            |uffd, addr| unsafe { store.resolve_fault(uffd, mapping, addr.as_ptr()) },
+        )?;
+        assert_eq!(byte, 0x2a);
+        assert!(store.buf.iter().all(|&b| b == 0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tampered_fault() -> Result<()> {
+        const BASE: usize = 0x10_0000;
+
+        let mut file = encrypted(&[0x2a; PAGE_SIZE]);
+        file[HEADER_SIZE + 10] ^= 1;
+        let mut store = EncryptedPageStore::open(Cursor::new(file), &KEY)?;
+
+        let uffd = FakeUffd::new();
+        let addr = (BASE + 100) as *mut c_void;
+        let result = unsafe { store.resolve_fault(&uffd, BASE as *mut c_void, addr) };
+        assert!(matches!(
+            result,
+            Err(Error::PageAuthenticationFailed { page: 0, poisoned })
+                if poisoned == cfg!(feature = "linux6_6")
+        ));
+
+        // The page is never installed, only poisoned.
+        #[cfg(feature = "linux6_6")]
+        let expected = vec![Call::Poison {
+            start: BASE,
+            len: PAGE_SIZE,
+            mode: crate::PoisonMode::empty(),
+        }];
+        #[cfg(not(feature = "linux6_6"))]
+        let expected = Vec::<Call>::new();
+        assert_eq!(uffd.calls(), expected);
+
+        Ok(())
+    }
+}
@@ -1,3 +1,4 @@
+use std::fmt;
 use std::io;
 
 use crate::IoctlFlags;
@@ -35,6 +36,10 @@ pub enum Error {
     ReadEof,
 
     /// An unrecognized event code was found in a `uffd_msg` struct.
+    ///
+    /// No longer returned when reading events; unrecognized events are reported as
+    /// [`Event::Unknown`](crate::Event::Unknown) instead.
+    #[deprecated(note = "unrecognized events are reported as `Event::Unknown`")]
     #[error("Unrecognized event in uffd_msg: {0}")]
     UnrecognizedEvent(u8),
 
@@ -49,6 +54,75 @@ pub enum Error {
     /// Could not open /dev/userfaultfd even though it exists
     #[error("Error accessing /dev/userfaultfd: {0}")]
     OpenDevUserfaultfd(io::Error),
+
+    /// The process isn't allowed to create a userfaultfd object, for the given reason.
+    #[error("Permission denied: {reason}")]
+    PermissionDenied { reason: PermissionReason },
+
+    /// A page of an encrypted snapshot failed authentication, so its contents were not
+    /// installed. If `poisoned` is `true`, the page was poisoned instead, so the faulting thread
+    /// receives `SIGBUS` rather than waiting forever.
+    #[error("Page {page} failed authentication")]
+    PageAuthenticationFailed { page: u64, poisoned: bool },
+
+    /// I/O error on a file or stream used by this crate, such as a fault trace.
+    #[error("I/O error")]
+    Io(#[source] io::Error),
+}
+
+/// Why the creation of a userfaultfd object was denied.
+///
+/// The builder determines this after the fact from the device mode, the
+/// `vm.unprivileged_userfaultfd` sysctl, the capabilities and the seccomp status of the process.
+/// The `Display` output says what to change.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PermissionReason {
+    /// `/dev/userfaultfd` exists, but this process can't open it for reading and writing.
+    DeviceAccess { mode: u32, uid: u32, gid: u32 },
+
+    /// `vm.unprivileged_userfaultfd` is 0 and the process lacks `CAP_SYS_PTRACE`. Kernels before
+    /// 5.11 then deny the system call even for user-mode faults.
+    UnprivilegedUserfaultfdDisabled,
+
+    /// Kernel-mode faults were requested with `user_mode_only(false)`, which requires
+    /// `CAP_SYS_PTRACE` unless `vm.unprivileged_userfaultfd` is 1.
+    KernelFaultsRequireCapSysPtrace,
+
+    /// The system call was most likely denied by a seccomp filter.
+    Seccomp,
+
+    /// None of the above explains the denial.
+    Unknown,
+}
+
+impl fmt::Display for PermissionReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PermissionReason::DeviceAccess { mode, uid, gid } => write!(
+                f,
+                "/dev/userfaultfd (mode {:o}, owner {}:{}) is not readable and writable by this \
+                 process; grant it access to the device, for example through the device's group",
+                mode, uid, gid
+            ),
+            PermissionReason::UnprivilegedUserfaultfdDisabled => write!(
+                f,
+                "vm.unprivileged_userfaultfd is 0 and the process lacks CAP_SYS_PTRACE; set the \
+                 sysctl to 1, grant CAP_SYS_PTRACE, or grant access to /dev/userfaultfd"
+            ),
+            PermissionReason::KernelFaultsRequireCapSysPtrace => write!(
+                f,
+                "handling kernel-mode faults requires CAP_SYS_PTRACE or \
+                 vm.unprivileged_userfaultfd=1; enable user_mode_only, grant CAP_SYS_PTRACE, or \
+                 set the sysctl to 1"
+            ),
+            PermissionReason::Seccomp => write!(
+                f,
+                "userfaultfd(2) was denied by a seccomp filter; allow it in the filter, or grant \
+                 access to /dev/userfaultfd"
+            ),
+            PermissionReason::Unknown => write!(f, "userfaultfd(2) failed with EPERM"),
+        }
+    }
 }
 
 impl From<nix::Error> for Error {
@@ -56,3 +130,9 @@ impl From<nix::Error> for Error {
         Error::SystemError(e)
     }
 }
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
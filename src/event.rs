@@ -1,13 +1,19 @@
-use crate::error::{Error, Result};
+use crate::addr::UffdAddr;
+use crate::error::Result;
 use crate::raw;
 use crate::Uffd;
-use libc::c_void;
+use bitflags::bitflags;
 #[cfg(feature = "linux4_14")]
 use nix::unistd::Pid;
+use std::mem;
 use std::os::unix::io::{FromRawFd, RawFd};
 
+/// The size in bytes of a `uffd_msg` struct, as kept by [`Event::Unknown`].
+pub const UFFD_MSG_SIZE: usize = mem::size_of::<raw::uffd_msg>();
+
 /// Whether a page fault event was for a read or write.
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ReadWrite {
     Read,
     Write,
@@ -15,6 +21,7 @@ pub enum ReadWrite {
 
 /// The kind of fault for a page fault event.
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum FaultKind {
     /// The fault was a read or write on a missing page.
     Missing,
@@ -27,17 +34,80 @@ pub enum FaultKind {
     Minor,
 }
 
+bitflags! {
+    /// The raw flags of a page fault event, from which its [`FaultKind`] and [`ReadWrite`] are
+    /// derived.
+    ///
+    /// Flags that this crate doesn't know, such as those of newer kernels, are kept as unnamed
+    /// bits.
+    #[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+    pub struct PagefaultFlags: u64 {
+        /// The fault was a write.
+        const WRITE = raw::UFFD_PAGEFAULT_FLAG_WRITE;
+        /// The fault was a write on a write-protected page.
+        #[cfg(feature = "linux5_7")]
+        const WRITE_PROTECT = raw::UFFD_PAGEFAULT_FLAG_WP;
+        /// The fault was a minor fault.
+        #[cfg(feature = "linux5_13")]
+        const MINOR = raw::UFFD_PAGEFAULT_FLAG_MINOR;
+
+        /// Unknown pagefault flags are allowed to be robust to future kernel changes.
+        const _ = !0;
+    }
+}
+
+impl PagefaultFlags {
+    /// The kind of fault described by these flags.
+    ///
+    /// `MINOR` takes precedence over `WRITE_PROTECT`: the kernel never reports a fault as both
+    /// minor and write-protected, but should both flags be set, the fault is reported as
+    /// [`FaultKind::Minor`], and both remain visible in the flags. Without either flag, the fault
+    /// is [`FaultKind::Missing`].
+    pub fn kind(self) -> FaultKind {
+        #[cfg(feature = "linux5_13")]
+        if self.contains(PagefaultFlags::MINOR) {
+            return FaultKind::Minor;
+        }
+        #[cfg(feature = "linux5_7")]
+        if self.contains(PagefaultFlags::WRITE_PROTECT) {
+            return FaultKind::WriteProtected;
+        }
+        FaultKind::Missing
+    }
+
+    /// Whether the fault described by these flags was a read or a write.
+    pub fn rw(self) -> ReadWrite {
+        if self.contains(PagefaultFlags::WRITE) {
+            ReadWrite::Write
+        } else {
+            ReadWrite::Read
+        }
+    }
+}
+
 /// Events from the userfaultfd object that are read by `Uffd::read_event()`.
+///
+/// Addresses are reported as [`UffdAddr`]s, so events can be sent to other threads.
+///
+/// With the `serde` feature, events can be serialized, except for `Event::Fork`, whose file
+/// descriptor has to be passed to another process over a Unix socket instead.
+///
+/// Newer kernels may add events, so matches on this enum need a wildcard arm. Events this crate
+/// doesn't know are reported as [`Event::Unknown`].
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
 pub enum Event {
     /// A pagefault event.
     Pagefault {
-        /// The kind of fault.
+        /// The kind of fault, derived from `flags`.
         kind: FaultKind,
-        /// Whether the fault is on a read or a write.
+        /// Whether the fault is on a read or a write, derived from `flags`.
         rw: ReadWrite,
+        /// The raw flags of the fault, including any that this crate doesn't know.
+        flags: PagefaultFlags,
         /// The address that triggered the fault.
-        addr: *mut c_void,
+        addr: UffdAddr,
         /// The thread that triggered the fault, if [`FeatureFlags::THREAD_ID`] is enabled.
         ///
         /// If the thread ID feature is not enabled, the value of this field is undefined. It would
@@ -46,10 +116,12 @@ pub enum Event {
         ///
         /// This requires this crate to be compiled with the `linux4_14` feature.
         #[cfg(feature = "linux4_14")]
+        #[cfg_attr(feature = "serde", serde(with = "crate::serde_impls::pid"))]
         thread_id: Pid,
     },
     /// Generated when the faulting process invokes `fork(2)` (or `clone(2)` without the `CLONE_VM`
     /// flag).
+    #[cfg_attr(feature = "serde", serde(skip))]
     Fork {
         /// The `Uffd` object created for the child by `fork(2)`
         uffd: Uffd,
@@ -57,9 +129,9 @@ pub enum Event {
     /// Generated when the faulting process invokes `mremap(2)`.
     Remap {
         /// The original address of the memory range that was remapped.
-        from: *mut c_void,
+        from: UffdAddr,
         /// The new address of the memory range that was remapped.
-        to: *mut c_void,
+        to: UffdAddr,
         /// The original length of the memory range that was remapped.
         len: usize,
     },
@@ -67,17 +139,24 @@ pub enum Event {
     /// `MADV_REMOVE` advice.
     Remove {
         /// The start address of the memory range that was freed.
-        start: *mut c_void,
+        start: UffdAddr,
         /// The end address of the memory range that was freed.
-        end: *mut c_void,
+        end: UffdAddr,
     },
     /// Generated when the faulting process unmaps a meomry range, either explicitly using
     /// `munmap(2)` or implicitly during `mmap(2)` or `mremap(2)`.
     Unmap {
         /// The start address of the memory range that was unmapped.
-        start: *mut c_void,
+        start: UffdAddr,
         /// The end address of the memory range that was unmapped.
-        end: *mut c_void,
+        end: UffdAddr,
+    },
+    /// An event that this crate doesn't recognize, such as one added by a newer kernel.
+    Unknown {
+        /// The event code of the message.
+        event: u8,
+        /// The full `uffd_msg` struct, as read from the userfaultfd object.
+        raw: [u8; UFFD_MSG_SIZE],
     },
 }
 
@@ -87,26 +166,7 @@ impl Event {
             raw::UFFD_EVENT_PAGEFAULT => {
                 let pagefault = unsafe { msg.arg.pagefault };
 
-                #[allow(unused_mut)]
-                let mut kind = FaultKind::Missing;
-
-                // The below two flags are mutually exclusive (it does not make sense
-                // to have a minor fault that is a write-protect fault at the same time.
-                #[cfg(feature = "linux5_7")]
-                if pagefault.flags & raw::UFFD_PAGEFAULT_FLAG_WP != 0 {
-                    kind = FaultKind::WriteProtected;
-                }
-
-                #[cfg(feature = "linux5_13")]
-                if pagefault.flags & raw::UFFD_PAGEFAULT_FLAG_MINOR != 0 {
-                    kind = FaultKind::Minor
-                }
-
-                let rw = if pagefault.flags & raw::UFFD_PAGEFAULT_FLAG_WRITE == 0 {
-                    ReadWrite::Read
-                } else {
-                    ReadWrite::Write
-                };
+                let flags = PagefaultFlags::from_bits_retain(pagefault.flags);
                 // Converting the ptid to i32 is safe because the maximum pid in
                 // Linux is 2^22, which is about 4 million.
                 //
@@ -115,9 +175,10 @@ impl Event {
                 #[cfg(feature = "linux4_14")]
                 let thread_id = Pid::from_raw(unsafe { pagefault.feat.ptid } as i32);
                 Ok(Event::Pagefault {
-                    kind,
-                    rw,
-                    addr: pagefault.address as *mut c_void,
+                    kind: flags.kind(),
+                    rw: flags.rw(),
+                    flags,
+                    addr: UffdAddr::new(pagefault.address as usize),
                     #[cfg(feature = "linux4_14")]
                     thread_id,
                 })
@@ -131,26 +192,50 @@ impl Event {
             raw::UFFD_EVENT_REMAP => {
                 let remap = unsafe { msg.arg.remap };
                 Ok(Event::Remap {
-                    from: remap.from as *mut c_void,
-                    to: remap.to as *mut c_void,
+                    from: UffdAddr::new(remap.from as usize),
+                    to: UffdAddr::new(remap.to as usize),
                     len: remap.len as usize,
                 })
             }
             raw::UFFD_EVENT_REMOVE => {
                 let remove = unsafe { msg.arg.remove };
                 Ok(Event::Remove {
-                    start: remove.start as *mut c_void,
-                    end: remove.end as *mut c_void,
+                    start: UffdAddr::new(remove.start as usize),
+                    end: UffdAddr::new(remove.end as usize),
                 })
             }
             raw::UFFD_EVENT_UNMAP => {
                 let remove = unsafe { msg.arg.remove };
                 Ok(Event::Unmap {
-                    start: remove.start as *mut c_void,
-                    end: remove.end as *mut c_void,
+                    start: UffdAddr::new(remove.start as usize),
+                    end: UffdAddr::new(remove.end as usize),
                 })
             }
-            _ => Err(Error::UnrecognizedEvent(msg.event)),
+            event => Ok(Event::Unknown {
+                event,
+                raw: unsafe { mem::transmute_copy(msg) },
+            }),
+        }
+    }
+
+    /// The event code of the `uffd_msg` struct this event was read from, one of the
+    /// `UFFD_EVENT_*` constants.
+    pub fn event_code(&self) -> u8 {
+        match self {
+            Event::Pagefault { .. } => raw::UFFD_EVENT_PAGEFAULT,
+            Event::Fork { .. } => raw::UFFD_EVENT_FORK,
+            Event::Remap { .. } => raw::UFFD_EVENT_REMAP,
+            Event::Remove { .. } => raw::UFFD_EVENT_REMOVE,
+            Event::Unmap { .. } => raw::UFFD_EVENT_UNMAP,
+            Event::Unknown { event, .. } => *event,
+        }
+    }
+
+    /// The raw `uffd_msg` struct of an [`Event::Unknown`], or `None` for a recognized event.
+    pub fn raw_msg(&self) -> Option<&[u8; UFFD_MSG_SIZE]> {
+        match self {
+            Event::Unknown { raw, .. } => Some(raw),
+            _ => None,
         }
     }
 }
@@ -0,0 +1,627 @@
+//! Pressure-driven eviction of clean pages restored from a snapshot.
+//!
+//! Pages that were restored lazily from a snapshot and never modified can be dropped at any time,
+//! since the fault path can always restore them again. [`EvictionController`] serves the faults
+//! of a range registered with `RegisterMode::MISSING | RegisterMode::WRITE_PROTECT`: it installs
+//! pages from a [`PageSource`] write-protected, and the first write to a page raises a
+//! write-protect fault that marks it dirty. When a [`PressureMonitor`] reports memory pressure,
+//! the clean pages are dropped with `MADV_DONTNEED`, and are refilled on their next access.
+//!
+//! Memory pressure is read either from the system-wide or a cgroup's PSI file, such as
+//! `/proc/pressure/memory`, or from the `memory.events` file of a cgroup v2.
+//!
+//! The `Uffd` object should not be created with `FeatureFlags::EVENT_REMOVE`: the controller
+//! reads events on the same thread that evicts pages, and `MADV_DONTNEED` would block until its
+//! `Event::Remove` was read.
+//!
+//! This module requires the `linux5_7` feature.
+
+use crate::backend::UffdBackend;
+use crate::error::{Error, Result};
+use crate::page_source::{PageBuffer, PageContents, PageSource};
+use crate::{CopyMode, Event, EventBuffer, FaultKind, ReadWrite, UffdAddr};
+use libc::c_void;
+use nix::errno::Errno;
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::time::Duration;
+
+/// The system-wide memory PSI file.
+pub const PSI_MEMORY: &str = "/proc/pressure/memory";
+
+/// Pressure stall averages of one line of a PSI file.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PsiAverages {
+    /// The percentage of time stalled over the last 10 seconds.
+    pub avg10: f64,
+    /// The percentage of time stalled over the last 60 seconds.
+    pub avg60: f64,
+    /// The percentage of time stalled over the last 300 seconds.
+    pub avg300: f64,
+    /// The total stall time in microseconds.
+    pub total: u64,
+}
+
+/// The contents of a PSI file.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Psi {
+    /// Stalls where at least some tasks were waiting for memory.
+    pub some: PsiAverages,
+    /// Stalls where all non-idle tasks were waiting for memory.
+    pub full: PsiAverages,
+}
+
+impl Psi {
+    /// Parse the contents of a PSI file.
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut psi = Psi::default();
+        for line in text.lines() {
+            let mut fields = line.split_whitespace();
+            let averages = match fields.next() {
+                Some("some") => &mut psi.some,
+                Some("full") => &mut psi.full,
+                _ => continue,
+            };
+            for field in fields {
+                let (key, value) = field
+                    .split_once('=')
+                    .ok_or_else(|| invalid_data(format!("malformed PSI field {:?}", field)))?;
+                let parsed = match key {
+                    "avg10" => value.parse().map(|v| averages.avg10 = v).is_ok(),
+                    "avg60" => value.parse().map(|v| averages.avg60 = v).is_ok(),
+                    "avg300" => value.parse().map(|v| averages.avg300 = v).is_ok(),
+                    "total" => value.parse().map(|v| averages.total = v).is_ok(),
+                    _ => true,
+                };
+                if !parsed {
+                    return Err(invalid_data(format!("malformed PSI field {:?}", field)));
+                }
+            }
+        }
+        Ok(psi)
+    }
+
+    /// Read and parse a PSI file, such as [`PSI_MEMORY`].
+    pub fn read<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Psi::parse(&std::fs::read_to_string(path)?)
+    }
+}
+
+/// The counters of a cgroup v2 `memory.events` file that indicate pressure.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct MemoryEvents {
+    /// The number of times the cgroup was throttled because it exceeded `memory.high`.
+    pub high: u64,
+    /// The number of times the cgroup was about to exceed `memory.max`.
+    pub max: u64,
+    /// The number of times the cgroup ran out of memory.
+    pub oom: u64,
+}
+
+impl MemoryEvents {
+    /// Parse the contents of a `memory.events` file.
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut events = MemoryEvents::default();
+        for line in text.lines() {
+            let mut fields = line.split_whitespace();
+            let counter = match fields.next() {
+                Some("high") => &mut events.high,
+                Some("max") => &mut events.max,
+                Some("oom") => &mut events.oom,
+                _ => continue,
+            };
+            *counter = fields
+                .next()
+                .and_then(|value| value.parse().ok())
+                .ok_or_else(|| invalid_data(format!("malformed memory event {:?}", line)))?;
+        }
+        Ok(events)
+    }
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+enum Watch {
+    Psi,
+    Events(MemoryEvents),
+}
+
+/// Watches a PSI trigger or a cgroup `memory.events` file for memory pressure.
+///
+/// The file descriptor becomes ready for `POLLPRI` when pressure may have changed; see
+/// [`check`](PressureMonitor::check).
+pub struct PressureMonitor {
+    file: File,
+    watch: Watch,
+}
+
+impl PressureMonitor {
+    /// Register a PSI trigger on `path`, such as [`PSI_MEMORY`] or the `memory.pressure` file of
+    /// a cgroup, that fires when some tasks were stalled on memory for `stall` within `window`.
+    pub fn psi<P: AsRef<Path>>(path: P, stall: Duration, window: Duration) -> io::Result<Self> {
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        let trigger = format!("some {} {}\0", stall.as_micros(), window.as_micros());
+        file.write_all(trigger.as_bytes())?;
+        Ok(PressureMonitor {
+            file,
+            watch: Watch::Psi,
+        })
+    }
+
+    /// Watch the `memory.events` file of a cgroup v2, which reports pressure whenever its `high`,
+    /// `max` or `oom` counters increase.
+    pub fn cgroup_events<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut monitor = PressureMonitor {
+            file: File::open(path)?,
+            watch: Watch::Events(MemoryEvents::default()),
+        };
+        let events = monitor.read_events()?;
+        monitor.watch = Watch::Events(events);
+        Ok(monitor)
+    }
+
+    fn read_events(&mut self) -> io::Result<MemoryEvents> {
+        let mut text = String::new();
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_to_string(&mut text)?;
+        MemoryEvents::parse(&text)
+    }
+
+    /// Check whether the last notification indicates memory pressure.
+    ///
+    /// Call this once the file descriptor is ready for `POLLPRI`. For a PSI trigger this is
+    /// always the case. For `memory.events`, the counters are re-read and compared to the
+    /// previous ones.
+    pub fn check(&mut self) -> io::Result<bool> {
+        match self.watch {
+            Watch::Psi => Ok(true),
+            Watch::Events(previous) => {
+                let current = self.read_events()?;
+                self.watch = Watch::Events(current);
+                Ok(current.high > previous.high
+                    || current.max > previous.max
+                    || current.oom > previous.oom)
+            }
+        }
+    }
+}
+
+impl AsRawFd for PressureMonitor {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum PageState {
+    Missing,
+    Clean,
+    Dirty,
+}
+
+/// Serves the faults of a snapshot-backed range and evicts its clean pages under pressure.
+pub struct EvictionController<'a, U: UffdBackend + ?Sized, S: PageSource> {
+    uffd: &'a U,
+    base: *mut c_void,
+    page_size: usize,
+    source: S,
+    pages: Vec<PageState>,
+    buf: PageBuffer,
+    events: EventBuffer,
+    batch: usize,
+}
+
+impl<'a, U: UffdBackend + ?Sized, S: PageSource> EvictionController<'a, U, S> {
+    /// Create a controller for the range of `len` bytes at `base`, which must be registered with
+    /// `uffd` with `RegisterMode::MISSING | RegisterMode::WRITE_PROTECT` and not populated yet.
+    pub fn new(uffd: &'a U, base: *mut c_void, len: usize, page_size: usize, source: S) -> Self {
+        EvictionController {
+            uffd,
+            base,
+            page_size,
+            source,
+            pages: vec![PageState::Missing; len / page_size],
+            buf: PageBuffer::new(page_size, page_size),
+            events: EventBuffer::new(16),
+            batch: usize::MAX,
+        }
+    }
+
+    /// Set the maximum number of pages evicted per pressure notification. The default is to
+    /// evict all clean pages.
+    pub fn batch_size(&mut self, batch: usize) -> &mut Self {
+        self.batch = batch;
+        self
+    }
+
+    /// The number of pages that are resident and unmodified.
+    pub fn clean_pages(&self) -> usize {
+        self.count(PageState::Clean)
+    }
+
+    /// The number of pages that were modified since they were restored.
+    pub fn dirty_pages(&self) -> usize {
+        self.count(PageState::Dirty)
+    }
+
+    fn count(&self, state: PageState) -> usize {
+        self.pages.iter().filter(|&&page| page == state).count()
+    }
+
+    fn page_addr(&self, index: usize) -> *mut c_void {
+        (self.base as usize + index * self.page_size) as *mut c_void
+    }
+
+    fn index_of(&self, addr: UffdAddr) -> Option<usize> {
+        let offset = addr.checked_offset_from(UffdAddr::from(self.base))?;
+        Some(offset / self.page_size).filter(|&index| index < self.pages.len())
+    }
+
+    /// Handle an event read from the `Uffd` object, and return whether it concerned this range.
+    pub fn handle_event(&mut self, event: &Event) -> Result<bool> {
+        match *event {
+            Event::Pagefault { kind, rw, addr, .. } => {
+                let index = match self.index_of(addr) {
+                    Some(index) => index,
+                    None => return Ok(false),
+                };
+                match kind {
+                    FaultKind::Missing => self.restore(index, rw)?,
+                    FaultKind::WriteProtected => self.mark_dirty(index)?,
+                    #[allow(unreachable_patterns)]
+                    _ => return Ok(false),
+                }
+                Ok(true)
+            }
+            Event::Remove { start, end } | Event::Unmap { start, end } => {
+                // The range may extend beyond this one on either side.
+                let base = self.base as usize;
+                let len = self.pages.len();
+                let first = start.as_usize().saturating_sub(base);
+                let first = ((first + self.page_size - 1) / self.page_size).min(len);
+                let last = (end.as_usize().saturating_sub(base) / self.page_size).min(len);
+                for page in &mut self.pages[first..last.max(first)] {
+                    *page = PageState::Missing;
+                }
+                Ok(first < last)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn restore(&mut self, index: usize, rw: ReadWrite) -> Result<()> {
+        let dst = self.page_addr(index);
+        // A page restored for a write is dirty right away; there's no point in protecting it.
+        let (mode, state) = match rw {
+            ReadWrite::Read => (CopyMode::WRITE_PROTECT, PageState::Clean),
+            ReadWrite::Write => (CopyMode::empty(), PageState::Dirty),
+        };
+        // Zero pages are copied too, since `UFFDIO_ZEROPAGE` can't install them write-protected.
+        if let PageContents::Zero = self.source.read_page(index as u64, &mut self.buf)? {
+            self.buf.fill(0);
+        }
+        let result = unsafe {
+            self.uffd
+                .copy_with_mode(self.buf.as_ptr(), dst, self.page_size, mode)
+        };
+        match result {
+            Ok(_) => {
+                self.pages[index] = state;
+                Ok(())
+            }
+            Err(Error::CopyFailed(Errno::EEXIST)) => {
+                // The page was populated behind our back, so its contents may differ from the
+                // source. Treat it as dirty so that it is never evicted.
+                if self.pages[index] == PageState::Missing {
+                    self.pages[index] = PageState::Dirty;
+                }
+                self.uffd.wake(dst, self.page_size)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn mark_dirty(&mut self, index: usize) -> Result<()> {
+        // The page may have been evicted while the writer was waiting; it is then refilled
+        // through a missing fault once woken up.
+        if self.pages[index] == PageState::Clean {
+            self.pages[index] = PageState::Dirty;
+        }
+        self.uffd
+            .remove_write_protection(self.page_addr(index), self.page_size, true)
+    }
+
+    /// Drop up to the batch size of clean pages from memory, and return how many were dropped.
+    pub fn evict_clean(&mut self) -> Result<usize> {
+        let mut evicted = 0;
+        let mut index = 0;
+        while index < self.pages.len() && evicted < self.batch {
+            if self.pages[index] != PageState::Clean {
+                index += 1;
+                continue;
+            }
+            // Drop runs of clean pages with a single call.
+            let start = index;
+            while index < self.pages.len()
+                && self.pages[index] == PageState::Clean
+                && evicted + (index - start) < self.batch
+            {
+                index += 1;
+            }
+            let len = (index - start) * self.page_size;
+            if unsafe { libc::madvise(self.page_addr(start), len, libc::MADV_DONTNEED) } != 0 {
+                return Err(Errno::last().into());
+            }
+            for page in &mut self.pages[start..index] {
+                *page = PageState::Missing;
+            }
+            evicted += index - start;
+        }
+        Ok(evicted)
+    }
+}
+
+impl<'a, U: UffdBackend + AsRawFd + ?Sized, S: PageSource> EvictionController<'a, U, S> {
+    /// Wait up to `timeout` for page faults or memory pressure, and handle them.
+    ///
+    /// The `Uffd` object must be non-blocking. Returns the number of pages evicted.
+    pub fn poll(&mut self, monitor: &mut PressureMonitor, timeout: Duration) -> Result<usize> {
+        let mut fds = [
+            libc::pollfd {
+                fd: self.uffd.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: monitor.as_raw_fd(),
+                events: libc::POLLPRI,
+                revents: 0,
+            },
+        ];
+        let timeout = timeout.as_millis().min(i32::MAX as u128) as i32;
+        match Errno::result(unsafe { libc::poll(fds.as_mut_ptr(), 2, timeout) }) {
+            Ok(_) | Err(Errno::EINTR) => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        if fds[0].revents & libc::POLLIN != 0 {
+            let events = self.uffd.read_events(&mut self.events)?;
+            for event in &events {
+                self.handle_event(event)?;
+            }
+        }
+        if fds[1].revents & (libc::POLLPRI | libc::POLLERR) != 0 && monitor.check()? {
+            return self.evict_clean();
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fake::{Call, FakeUffd};
+    use crate::{FeatureFlags, PagefaultFlags, RegisterMode, UffdBuilder, WriteProtectMode};
+    use std::ptr;
+    use std::thread;
+
+    const PAGE_SIZE: usize = 4096;
+
+    #[test]
+    fn test_parse_pressure() {
+        let psi = Psi::parse(
+            "some avg10=1.50 avg60=0.25 avg300=0.00 total=1234\n\
+             full avg10=0.00 avg60=0.00 avg300=0.00 total=5\n",
+        )
+        .unwrap();
+        assert_eq!(psi.some.avg10, 1.5);
+        assert_eq!(psi.some.total, 1234);
+        assert_eq!(psi.full.total, 5);
+        assert!(Psi::parse("some avg10=x\n").is_err());
+
+        let events = MemoryEvents::parse("low 0\nhigh 3\nmax 1\noom 0\noom_kill 0\n").unwrap();
+        assert_eq!(
+            events,
+            MemoryEvents {
+                high: 3,
+                max: 1,
+                oom: 0
+            }
+        );
+    }
+
+    #[test]
+    fn test_evict_clean_pages() -> Result<()> {
+        const PAGES: usize = 4;
+
+        unsafe {
+            let uffd = UffdBuilder::new()
+                .require_features(FeatureFlags::PAGEFAULT_FLAG_WP)
+                .close_on_exec(true)
+                .non_blocking(true)
+                .create()?;
+
+            let mapping = libc::mmap(
+                ptr::null_mut(),
+                PAGE_SIZE * PAGES,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANON,
+                -1,
+                0,
+            );
+
+            assert!(!mapping.is_null());
+
+            uffd.register_with_mode(
+                mapping,
+                PAGE_SIZE * PAGES,
+                RegisterMode::MISSING | RegisterMode::WRITE_PROTECT,
+            )?;
+
+            let source = |index: u64, buf: &mut [u8]| {
+                buf.fill(index as u8 + 1);
+                Ok(PageContents::Data)
+            };
+            let mut controller =
+                EvictionController::new(&uffd, mapping, PAGE_SIZE * PAGES, PAGE_SIZE, source);
+
+            let run = |controller: &mut EvictionController<_, _>, thread: thread::JoinHandle<_>| {
+                while !thread.is_finished() {
+                    if let Some(event) = uffd.read_event()? {
+                        assert!(controller.handle_event(&event)?);
+                    }
+                }
+                Ok::<Vec<u8>, Error>(thread.join().unwrap())
+            };
+
+            // Read every page, then write to page 1.
+            let ptr = mapping as usize;
+            let thread = thread::spawn(move || {
+                let ptr = ptr as *mut u8;
+                let values = (0..PAGES)
+                    .map(|i| ptr::read_volatile(ptr.add(i * PAGE_SIZE)))
+                    .collect();
+                ptr::write_volatile(ptr.add(PAGE_SIZE), 42);
+                values
+            });
+            assert_eq!(run(&mut controller, thread)?, vec![1, 2, 3, 4]);
+            assert_eq!(controller.clean_pages(), PAGES - 1);
+            assert_eq!(controller.dirty_pages(), 1);
+
+            assert_eq!(controller.evict_clean()?, PAGES - 1);
+            assert_eq!(controller.clean_pages(), 0);
+
+            // The clean pages are refilled from the source; the dirty page kept its contents.
+            let thread = thread::spawn(move || {
+                let ptr = ptr as *mut u8;
+                (0..PAGES)
+                    .map(|i| ptr::read_volatile(ptr.add(i * PAGE_SIZE)))
+                    .collect()
+            });
+            assert_eq!(run(&mut controller, thread)?, vec![1, 42, 3, 4]);
+            assert_eq!(controller.clean_pages(), PAGES - 1);
+
+            uffd.unregister(mapping, PAGE_SIZE * PAGES)?;
+
+            assert_eq!(libc::munmap(mapping, PAGE_SIZE * PAGES), 0);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_protect_fault() -> Result<()> {
+        const BASE: usize = 0x10_0000;
+
+        let uffd = FakeUffd::new();
+        let source = |_: u64, buf: &mut [u8]| {
+            buf.fill(1);
+            Ok(PageContents::Data)
+        };
+        let mut controller =
+            EvictionController::new(&uffd, BASE as *mut c_void, PAGE_SIZE * 2, PAGE_SIZE, source);
+
+        let fault = |kind| Event::Pagefault {
+            kind,
+            rw: ReadWrite::Read,
+            flags: PagefaultFlags::empty(),
+            addr: UffdAddr::new(BASE + PAGE_SIZE + 8),
+            #[cfg(feature = "linux4_14")]
+            thread_id: nix::unistd::Pid::from_raw(1),
+        };
+        assert!(controller.handle_event(&fault(FaultKind::Missing))?);
+        assert_eq!(controller.clean_pages(), 1);
+        assert!(controller.handle_event(&fault(FaultKind::WriteProtected))?);
+        assert_eq!(controller.dirty_pages(), 1);
+
+        // Read faults install the page write-protected, and the first write lifts the protection.
+        assert_eq!(
+            uffd.calls(),
+            vec![
+                Call::Copy {
+                    dst: BASE + PAGE_SIZE,
+                    data: vec![1; PAGE_SIZE],
+                    mode: CopyMode::WRITE_PROTECT,
+                },
+                Call::WriteProtect {
+                    start: BASE + PAGE_SIZE,
+                    len: PAGE_SIZE,
+                    mode: WriteProtectMode::empty(),
+                },
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_and_present_pages() -> Result<()> {
+        const PAGES: usize = 4;
+
+        unsafe {
+            let uffd = UffdBuilder::new()
+                .require_features(FeatureFlags::PAGEFAULT_FLAG_WP)
+                .close_on_exec(true)
+                .non_blocking(true)
+                .create()?;
+
+            let mapping = libc::mmap(
+                ptr::null_mut(),
+                PAGE_SIZE * PAGES,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANON,
+                -1,
+                0,
+            );
+
+            assert!(!mapping.is_null());
+
+            uffd.register_with_mode(
+                mapping,
+                PAGE_SIZE * PAGES,
+                RegisterMode::MISSING | RegisterMode::WRITE_PROTECT,
+            )?;
+
+            let source = |_: u64, buf: &mut [u8]| {
+                buf.fill(1);
+                Ok(PageContents::Data)
+            };
+            let mut controller =
+                EvictionController::new(&uffd, mapping, PAGE_SIZE * PAGES, PAGE_SIZE, source);
+
+            // Page 0 is populated without the controller knowing; restoring it finds it present.
+            uffd.zeropage(mapping, PAGE_SIZE, false)?;
+            let fault = Event::Pagefault {
+                kind: FaultKind::Missing,
+                rw: ReadWrite::Read,
+                flags: PagefaultFlags::empty(),
+                addr: UffdAddr::from(mapping),
+                #[cfg(feature = "linux4_14")]
+                thread_id: nix::unistd::Pid::from_raw(0),
+            };
+            assert!(controller.handle_event(&fault)?);
+            assert_eq!(controller.pages[0], PageState::Dirty);
+
+            // An unmap that covers the start of the range and more forgets the pages it covers.
+            controller.pages = vec![PageState::Clean; PAGES];
+            let base = UffdAddr::from(mapping);
+            let unmap = Event::Unmap {
+                start: base - PAGE_SIZE,
+                end: base + 2 * PAGE_SIZE,
+            };
+            assert!(controller.handle_event(&unmap)?);
+            assert_eq!(controller.clean_pages(), PAGES - 2);
+            assert_eq!(controller.pages[1], PageState::Missing);
+
+            uffd.unregister(mapping, PAGE_SIZE * PAGES)?;
+
+            assert_eq!(libc::munmap(mapping, PAGE_SIZE * PAGES), 0);
+        }
+
+        Ok(())
+    }
+}
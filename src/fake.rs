@@ -0,0 +1,402 @@
+//! An in-memory fake of a userfaultfd object for unit tests.
+//!
+//! [`FakeUffd`] implements [`UffdBackend`] without a kernel: it records every call made through
+//! it, returns the events that a test pushed with [`FakeUffd::push_event`], and lets a test choose
+//! the outcome of upcoming calls with [`FakeUffd::inject`]. Fault handlers written against
+//! `UffdBackend` can then be tested in environments where `userfaultfd` is blocked.
+//!
+//! The fake never touches memory: `copy` records the bytes it was given, but doesn't write them to
+//! the destination, so tests may use addresses that aren't mapped.
+
+use crate::backend::UffdBackend;
+use crate::error::Result;
+#[cfg(feature = "linux5_13")]
+use crate::ContinueMode;
+#[cfg(feature = "linux6_6")]
+use crate::PoisonMode;
+#[cfg(feature = "linux5_7")]
+use crate::WriteProtectMode;
+use crate::{CopyMode, Event, EventBuffer, IoctlFlags, RegisterMode, ZeropageMode};
+use libc::c_void;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+/// The operations of a [`FakeUffd`] whose outcome can be injected.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Op {
+    /// `register` and `register_with_mode`.
+    Register,
+    /// `unregister`.
+    Unregister,
+    /// `copy` and `copy_with_mode`.
+    Copy,
+    /// `zeropage` and `zeropage_with_mode`.
+    Zeropage,
+    /// `wake`.
+    Wake,
+    /// `write_protect`, `remove_write_protection` and `write_protect_with_mode`.
+    #[cfg(feature = "linux5_7")]
+    WriteProtect,
+    /// `r#continue` and `continue_with_mode`.
+    #[cfg(feature = "linux5_13")]
+    Continue,
+    /// `poison` and `poison_with_mode`.
+    #[cfg(feature = "linux6_6")]
+    Poison,
+    /// `read_event` and `read_events`.
+    ReadEvents,
+}
+
+/// A call recorded by a [`FakeUffd`]. Addresses are recorded as integers.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Call {
+    /// A range was registered.
+    Register {
+        start: usize,
+        len: usize,
+        mode: RegisterMode,
+    },
+    /// A range was unregistered.
+    Unregister { start: usize, len: usize },
+    /// Bytes were copied to `dst`.
+    Copy {
+        dst: usize,
+        data: Vec<u8>,
+        mode: CopyMode,
+    },
+    /// A range was zeroed.
+    Zeropage {
+        start: usize,
+        len: usize,
+        mode: ZeropageMode,
+    },
+    /// A range was woken.
+    Wake { start: usize, len: usize },
+    /// The write protection of a range was changed.
+    #[cfg(feature = "linux5_7")]
+    WriteProtect {
+        start: usize,
+        len: usize,
+        mode: WriteProtectMode,
+    },
+    /// Minor faults were resolved for a range.
+    #[cfg(feature = "linux5_13")]
+    Continue {
+        start: usize,
+        len: usize,
+        mode: ContinueMode,
+    },
+    /// A range was poisoned.
+    #[cfg(feature = "linux6_6")]
+    Poison {
+        start: usize,
+        len: usize,
+        mode: PoisonMode,
+    },
+    /// Events were read.
+    ReadEvents,
+}
+
+struct State {
+    calls: Vec<Call>,
+    events: VecDeque<Event>,
+    outcomes: HashMap<Op, VecDeque<Result<usize>>>,
+    ioctls: IoctlFlags,
+}
+
+/// A userfaultfd object that records calls instead of performing them.
+///
+/// Unless an outcome was injected, every call succeeds: resolving operations report the whole
+/// length as done, and registering reports the ioctls set with [`FakeUffd::set_ioctls`].
+pub struct FakeUffd {
+    state: Mutex<State>,
+}
+
+impl Default for FakeUffd {
+    fn default() -> Self {
+        FakeUffd::new()
+    }
+}
+
+impl FakeUffd {
+    /// Create a fake with no pending events, whose registrations report the `WAKE`, `COPY` and
+    /// `ZEROPAGE` ioctls.
+    pub fn new() -> Self {
+        FakeUffd {
+            state: Mutex::new(State {
+                calls: Vec::new(),
+                events: VecDeque::new(),
+                outcomes: HashMap::new(),
+                ioctls: IoctlFlags::WAKE | IoctlFlags::COPY | IoctlFlags::ZEROPAGE,
+            }),
+        }
+    }
+
+    /// Set the ioctls reported by successful registrations.
+    pub fn set_ioctls(&self, ioctls: IoctlFlags) {
+        self.state.lock().unwrap().ioctls = ioctls;
+    }
+
+    /// Queue an event to be returned by the next reads.
+    pub fn push_event(&self, event: Event) {
+        self.state.lock().unwrap().events.push_back(event);
+    }
+
+    /// The number of events that were pushed but not read yet.
+    pub fn pending_events(&self) -> usize {
+        self.state.lock().unwrap().events.len()
+    }
+
+    /// Queue the outcome of the next call of `op` that has no earlier outcome queued.
+    ///
+    /// An `Ok` value is the number of bytes reported by `copy`, `zeropage` and `r#continue`, and
+    /// is ignored by the other operations. An `Err` is returned as is, so tests can inject exactly
+    /// what a kernel would report, such as `Error::CopyFailed(Errno::EEXIST)` or
+    /// `Error::PartiallyCopied(n)`. Failed calls are recorded too.
+    pub fn inject(&self, op: Op, outcome: Result<usize>) {
+        self.state
+            .lock()
+            .unwrap()
+            .outcomes
+            .entry(op)
+            .or_default()
+            .push_back(outcome);
+    }
+
+    /// The calls recorded so far, oldest first.
+    pub fn calls(&self) -> Vec<Call> {
+        self.state.lock().unwrap().calls.clone()
+    }
+
+    /// Return the calls recorded so far, and forget them.
+    pub fn take_calls(&self) -> Vec<Call> {
+        std::mem::take(&mut self.state.lock().unwrap().calls)
+    }
+
+    /// Record `call`, and return the outcome of `op`, or `default` if none was injected.
+    fn record(&self, op: Op, call: Call, default: usize) -> Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        state.calls.push(call);
+        state
+            .outcomes
+            .get_mut(&op)
+            .and_then(VecDeque::pop_front)
+            .unwrap_or(Ok(default))
+    }
+}
+
+impl UffdBackend for FakeUffd {
+    fn register_with_mode(
+        &self,
+        start: *mut c_void,
+        len: usize,
+        mode: RegisterMode,
+    ) -> Result<IoctlFlags> {
+        let call = Call::Register {
+            start: start as usize,
+            len,
+            mode,
+        };
+        self.record(Op::Register, call, 0)?;
+        Ok(self.state.lock().unwrap().ioctls)
+    }
+
+    fn unregister(&self, start: *mut c_void, len: usize) -> Result<()> {
+        let call = Call::Unregister {
+            start: start as usize,
+            len,
+        };
+        self.record(Op::Unregister, call, 0).map(drop)
+    }
+
+    unsafe fn copy_with_mode(
+        &self,
+        src: *const c_void,
+        dst: *mut c_void,
+        len: usize,
+        mode: CopyMode,
+    ) -> Result<usize> {
+        let call = Call::Copy {
+            dst: dst as usize,
+            data: std::slice::from_raw_parts(src as *const u8, len).to_vec(),
+            mode,
+        };
+        self.record(Op::Copy, call, len)
+    }
+
+    unsafe fn zeropage_with_mode(
+        &self,
+        start: *mut c_void,
+        len: usize,
+        mode: ZeropageMode,
+    ) -> Result<usize> {
+        let call = Call::Zeropage {
+            start: start as usize,
+            len,
+            mode,
+        };
+        self.record(Op::Zeropage, call, len)
+    }
+
+    fn wake(&self, start: *mut c_void, len: usize) -> Result<()> {
+        let call = Call::Wake {
+            start: start as usize,
+            len,
+        };
+        self.record(Op::Wake, call, 0).map(drop)
+    }
+
+    #[cfg(feature = "linux5_7")]
+    fn write_protect_with_mode(
+        &self,
+        start: *mut c_void,
+        len: usize,
+        mode: WriteProtectMode,
+    ) -> Result<()> {
+        let call = Call::WriteProtect {
+            start: start as usize,
+            len,
+            mode,
+        };
+        self.record(Op::WriteProtect, call, 0).map(drop)
+    }
+
+    #[cfg(feature = "linux5_13")]
+    fn continue_with_mode(
+        &self,
+        start: *mut c_void,
+        len: usize,
+        mode: ContinueMode,
+    ) -> Result<u64> {
+        let call = Call::Continue {
+            start: start as usize,
+            len,
+            mode,
+        };
+        self.record(Op::Continue, call, len).map(|n| n as u64)
+    }
+
+    #[cfg(feature = "linux6_6")]
+    fn poison_with_mode(&self, start: *mut c_void, len: usize, mode: PoisonMode) -> Result<u64> {
+        let call = Call::Poison {
+            start: start as usize,
+            len,
+            mode,
+        };
+        self.record(Op::Poison, call, len).map(|n| n as u64)
+    }
+
+    fn read_event(&self) -> Result<Option<Event>> {
+        self.record(Op::ReadEvents, Call::ReadEvents, 0)?;
+        Ok(self.state.lock().unwrap().events.pop_front())
+    }
+
+    fn read_events(&self, buf: &mut EventBuffer) -> Result<Vec<Event>> {
+        self.record(Op::ReadEvents, Call::ReadEvents, 0)?;
+        let mut state = self.state.lock().unwrap();
+        let count = buf.0.len().min(state.events.len());
+        Ok(state.events.drain(..count).collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::Error;
+    use crate::page_source::{resolve_fault, PageBuffer, PageContents};
+    use crate::{FaultKind, PagefaultFlags, ReadWrite, UffdAddr};
+    use nix::errno::Errno;
+
+    const PAGE_SIZE: usize = 4096;
+    const BASE: usize = 0x10_0000;
+
+    fn pagefault(addr: usize) -> Event {
+        Event::Pagefault {
+            kind: FaultKind::Missing,
+            rw: ReadWrite::Read,
+            flags: PagefaultFlags::empty(),
+            addr: UffdAddr::new(addr),
+            #[cfg(feature = "linux4_14")]
+            thread_id: nix::unistd::Pid::from_raw(1),
+        }
+    }
+
+    fn source(index: u64, buf: &mut [u8]) -> std::io::Result<PageContents> {
+        if index == 0 {
+            return Ok(PageContents::Zero);
+        }
+        buf.fill(index as u8);
+        Ok(PageContents::Data)
+    }
+
+    #[test]
+    fn test_events() -> Result<()> {
+        let uffd = FakeUffd::new();
+        for i in 0..3 {
+            uffd.push_event(pagefault(BASE + i * PAGE_SIZE));
+        }
+
+        let mut buf = EventBuffer::new(2);
+        assert_eq!(uffd.read_events(&mut buf)?.len(), 2);
+        assert_eq!(uffd.pending_events(), 1);
+        assert!(uffd.read_event()?.is_some());
+        assert!(uffd.read_event()?.is_none());
+
+        uffd.inject(Op::ReadEvents, Err(Error::ReadEof));
+        assert!(matches!(uffd.read_events(&mut buf), Err(Error::ReadEof)));
+        assert_eq!(uffd.calls(), vec![Call::ReadEvents; 4]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_fault() -> Result<()> {
+        let uffd = FakeUffd::new();
+        let mut buf = PageBuffer::new(PAGE_SIZE, PAGE_SIZE);
+        let base = BASE as *mut c_void;
+        let addr = |offset: usize| (BASE + offset) as *mut c_void;
+
+        unsafe {
+            resolve_fault(&uffd, &mut source, base, addr(PAGE_SIZE + 8), &mut buf)?;
+            resolve_fault(&uffd, &mut source, base, addr(8), &mut buf)?;
+
+            // A concurrent install is reported as EEXIST, and the faulting thread is woken.
+            uffd.inject(Op::Copy, Err(Error::CopyFailed(Errno::EEXIST)));
+            resolve_fault(&uffd, &mut source, base, addr(2 * PAGE_SIZE), &mut buf)?;
+
+            uffd.inject(Op::Copy, Err(Error::CopyFailed(Errno::ENOMEM)));
+            assert!(resolve_fault(&uffd, &mut source, base, addr(PAGE_SIZE), &mut buf).is_err());
+        }
+
+        assert_eq!(
+            uffd.take_calls(),
+            vec![
+                Call::Copy {
+                    dst: BASE + PAGE_SIZE,
+                    data: vec![1; PAGE_SIZE],
+                    mode: CopyMode::empty(),
+                },
+                Call::Zeropage {
+                    start: BASE,
+                    len: PAGE_SIZE,
+                    mode: ZeropageMode::empty(),
+                },
+                Call::Copy {
+                    dst: BASE + 2 * PAGE_SIZE,
+                    data: vec![2; PAGE_SIZE],
+                    mode: CopyMode::empty(),
+                },
+                Call::Wake {
+                    start: BASE + 2 * PAGE_SIZE,
+                    len: PAGE_SIZE,
+                },
+                Call::Copy {
+                    dst: BASE + PAGE_SIZE,
+                    data: vec![1; PAGE_SIZE],
+                    mode: CopyMode::empty(),
+                },
+            ]
+        );
+        assert!(uffd.calls().is_empty());
+        Ok(())
+    }
+}
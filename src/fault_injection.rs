@@ -0,0 +1,403 @@
+//! Fault injection for exercising the error paths of fault handlers.
+//!
+//! Some errors are too rare to trigger on purpose against a real kernel: `EAGAIN` when the memory
+//! layout changes during a copy, `EEXIST` when another thread resolved the same page, `ESRCH`
+//! after the faulting process exited, or a truncated read from the descriptor. [`FaultInjector`]
+//! wraps any [`UffdBackend`] and makes a configurable fraction of `copy`, `zeropage`,
+//! `r#continue` and event reads fail in these ways, so that the retry and shutdown logic of a
+//! handler can be soak tested.
+//!
+//! Faults are drawn from a seeded pseudo-random generator, so a failing run can be reproduced.
+
+use crate::backend::UffdBackend;
+use crate::error::{Error, Result};
+#[cfg(feature = "linux5_13")]
+use crate::ContinueMode;
+#[cfg(feature = "linux6_6")]
+use crate::PoisonMode;
+#[cfg(feature = "linux5_7")]
+use crate::WriteProtectMode;
+use crate::{CopyMode, Event, EventBuffer, IoctlFlags, RegisterMode, ZeropageMode};
+use libc::c_void;
+use nix::errno::Errno;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// A fault injected into `copy`, `zeropage` or `r#continue`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ResolveFault {
+    /// Resolve only the first half of the pages, and report `EAGAIN` the way the operation does:
+    /// `Error::PartiallyCopied` for `copy`, `Error::ZeropageFailed(EAGAIN)` for `zeropage`, and a
+    /// short count for `r#continue`. When the range is a single page, nothing is resolved, and
+    /// `copy` fails with `Error::PartiallyCopied(0)` while `r#continue` fails with
+    /// `Error::SystemError(EAGAIN)`, as they do when the kernel copies nothing.
+    Again,
+    /// Resolve nothing, and fail with `EEXIST`, as if another thread resolved the page first.
+    Exists,
+    /// Resolve nothing, and fail with `ESRCH`, as if the faulting process exited.
+    NoProcess,
+}
+
+/// A fault injected into `read_event` or `read_events`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ReadFault {
+    /// Fail with `Error::ReadEof`.
+    Eof,
+    /// Fail with `Error::IncompleteMsg`.
+    IncompleteMsg,
+}
+
+/// The faults injected into one operation, each with the fraction of calls it affects.
+#[derive(Clone, Debug, PartialEq)]
+struct Policy<F> {
+    faults: Vec<(F, f64)>,
+}
+
+impl<F: Copy> Policy<F> {
+    fn add(&mut self, fault: F, rate: f64) {
+        let total = self.faults.iter().map(|&(_, rate)| rate).sum::<f64>() + rate;
+        assert!(
+            (0.0..=1.0).contains(&rate) && total <= 1.0,
+            "fault rates must be in [0, 1] and add up to at most 1, got {} (total {})",
+            rate,
+            total
+        );
+        self.faults.push((fault, rate));
+    }
+
+    fn pick(&self, draw: f64) -> Option<F> {
+        let mut threshold = 0.0;
+        for &(fault, rate) in &self.faults {
+            threshold += rate;
+            if draw < threshold {
+                return Some(fault);
+            }
+        }
+        None
+    }
+}
+
+impl<F> Default for Policy<F> {
+    fn default() -> Self {
+        Policy { faults: Vec::new() }
+    }
+}
+
+/// Wraps a [`UffdBackend`] and makes some of its calls fail.
+///
+/// Operations without injected faults, and calls that aren't selected, are passed through.
+pub struct FaultInjector<B> {
+    inner: B,
+    page_size: usize,
+    copy: Policy<ResolveFault>,
+    zeropage: Policy<ResolveFault>,
+    #[cfg(feature = "linux5_13")]
+    r#continue: Policy<ResolveFault>,
+    read: Policy<ReadFault>,
+    rng: Mutex<u64>,
+    injected: AtomicU64,
+}
+
+impl<B: UffdBackend> FaultInjector<B> {
+    /// Wrap `inner`, whose registered ranges use pages of `page_size` bytes. No faults are
+    /// injected until they are configured.
+    pub fn new(inner: B, page_size: usize) -> Self {
+        FaultInjector {
+            inner,
+            page_size,
+            copy: Policy::default(),
+            zeropage: Policy::default(),
+            #[cfg(feature = "linux5_13")]
+            r#continue: Policy::default(),
+            read: Policy::default(),
+            rng: Mutex::new(0x2545_f491_4f6c_dd1d),
+            injected: AtomicU64::new(0),
+        }
+    }
+
+    /// Seed the generator that selects the calls to fail.
+    pub fn seed(self, seed: u64) -> Self {
+        // Xorshift gets stuck at zero.
+        *self.rng.lock().unwrap() = seed.max(1);
+        self
+    }
+
+    /// Make a `rate` fraction of `copy` calls fail with `fault`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `rate` is not in `[0, 1]`, or if the rates of the faults injected into `copy`
+    /// add up to more than 1. The same holds for the other `inject_*` methods.
+    pub fn inject_copy(mut self, fault: ResolveFault, rate: f64) -> Self {
+        self.copy.add(fault, rate);
+        self
+    }
+
+    /// Make a `rate` fraction of `zeropage` calls fail with `fault`.
+    pub fn inject_zeropage(mut self, fault: ResolveFault, rate: f64) -> Self {
+        self.zeropage.add(fault, rate);
+        self
+    }
+
+    /// Make a `rate` fraction of `r#continue` calls fail with `fault`.
+    #[cfg(feature = "linux5_13")]
+    pub fn inject_continue(mut self, fault: ResolveFault, rate: f64) -> Self {
+        self.r#continue.add(fault, rate);
+        self
+    }
+
+    /// Make a `rate` fraction of event reads fail with `fault`.
+    pub fn inject_read(mut self, fault: ReadFault, rate: f64) -> Self {
+        self.read.add(fault, rate);
+        self
+    }
+
+    /// The wrapped backend.
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    /// Unwrap the backend.
+    pub fn into_inner(self) -> B {
+        self.inner
+    }
+
+    /// The number of faults injected so far.
+    pub fn injected(&self) -> u64 {
+        self.injected.load(Ordering::Relaxed)
+    }
+
+    /// Draw a number in `[0, 1)`, and select a fault of `policy` with it.
+    fn pick<F: Copy>(&self, policy: &Policy<F>) -> Option<F> {
+        if policy.faults.is_empty() {
+            return None;
+        }
+        let draw = {
+            let mut state = self.rng.lock().unwrap();
+            *state ^= *state << 13;
+            *state ^= *state >> 7;
+            *state ^= *state << 17;
+            (*state >> 11) as f64 / (1u64 << 53) as f64
+        };
+        let fault = policy.pick(draw);
+        if fault.is_some() {
+            self.injected.fetch_add(1, Ordering::Relaxed);
+        }
+        fault
+    }
+
+    /// The length of the part of a `len` byte range that is resolved before `EAGAIN`.
+    fn partial_len(&self, len: usize) -> usize {
+        len / self.page_size / 2 * self.page_size
+    }
+
+    fn read_fault(&self) -> Result<()> {
+        match self.pick(&self.read) {
+            None => Ok(()),
+            Some(ReadFault::Eof) => Err(Error::ReadEof),
+            Some(ReadFault::IncompleteMsg) => {
+                let expected = std::mem::size_of::<crate::raw::uffd_msg>();
+                Err(Error::IncompleteMsg {
+                    read: expected / 2,
+                    expected,
+                })
+            }
+        }
+    }
+}
+
+impl<B: UffdBackend> UffdBackend for FaultInjector<B> {
+    fn register_with_mode(
+        &self,
+        start: *mut c_void,
+        len: usize,
+        mode: RegisterMode,
+    ) -> Result<IoctlFlags> {
+        self.inner.register_with_mode(start, len, mode)
+    }
+
+    fn unregister(&self, start: *mut c_void, len: usize) -> Result<()> {
+        self.inner.unregister(start, len)
+    }
+
+    unsafe fn copy_with_mode(
+        &self,
+        src: *const c_void,
+        dst: *mut c_void,
+        len: usize,
+        mode: CopyMode,
+    ) -> Result<usize> {
+        match self.pick(&self.copy) {
+            None => self.inner.copy_with_mode(src, dst, len, mode),
+            Some(ResolveFault::Again) => {
+                let partial = self.partial_len(len);
+                if partial > 0 {
+                    self.inner.copy_with_mode(src, dst, partial, mode)?;
+                }
+                Err(Error::PartiallyCopied(partial))
+            }
+            Some(ResolveFault::Exists) => Err(Error::CopyFailed(Errno::EEXIST)),
+            Some(ResolveFault::NoProcess) => Err(Error::CopyFailed(Errno::ESRCH)),
+        }
+    }
+
+    unsafe fn zeropage_with_mode(
+        &self,
+        start: *mut c_void,
+        len: usize,
+        mode: ZeropageMode,
+    ) -> Result<usize> {
+        match self.pick(&self.zeropage) {
+            None => self.inner.zeropage_with_mode(start, len, mode),
+            Some(ResolveFault::Again) => {
+                let partial = self.partial_len(len);
+                if partial > 0 {
+                    self.inner.zeropage_with_mode(start, partial, mode)?;
+                }
+                Err(Error::ZeropageFailed(Errno::EAGAIN))
+            }
+            Some(ResolveFault::Exists) => Err(Error::ZeropageFailed(Errno::EEXIST)),
+            Some(ResolveFault::NoProcess) => Err(Error::ZeropageFailed(Errno::ESRCH)),
+        }
+    }
+
+    fn wake(&self, start: *mut c_void, len: usize) -> Result<()> {
+        self.inner.wake(start, len)
+    }
+
+    #[cfg(feature = "linux5_7")]
+    fn write_protect_with_mode(
+        &self,
+        start: *mut c_void,
+        len: usize,
+        mode: WriteProtectMode,
+    ) -> Result<()> {
+        self.inner.write_protect_with_mode(start, len, mode)
+    }
+
+    #[cfg(feature = "linux5_13")]
+    fn continue_with_mode(
+        &self,
+        start: *mut c_void,
+        len: usize,
+        mode: ContinueMode,
+    ) -> Result<u64> {
+        match self.pick(&self.r#continue) {
+            None => self.inner.continue_with_mode(start, len, mode),
+            Some(ResolveFault::Again) => match self.partial_len(len) {
+                0 => Err(Error::SystemError(Errno::EAGAIN)),
+                partial => self.inner.continue_with_mode(start, partial, mode),
+            },
+            Some(ResolveFault::Exists) => Err(Error::SystemError(Errno::EEXIST)),
+            Some(ResolveFault::NoProcess) => Err(Error::SystemError(Errno::ESRCH)),
+        }
+    }
+
+    #[cfg(feature = "linux6_6")]
+    fn poison_with_mode(&self, start: *mut c_void, len: usize, mode: PoisonMode) -> Result<u64> {
+        self.inner.poison_with_mode(start, len, mode)
+    }
+
+    fn read_event(&self) -> Result<Option<Event>> {
+        self.read_fault()?;
+        self.inner.read_event()
+    }
+
+    fn read_events(&self, buf: &mut EventBuffer) -> Result<Vec<Event>> {
+        self.read_fault()?;
+        self.inner.read_events(buf)
+    }
+}
+
+impl<B: AsRawFd> AsRawFd for FaultInjector<B> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fake::{Call, FakeUffd};
+
+    const PAGE_SIZE: usize = 4096;
+
+    #[test]
+    fn test_injected_errors() {
+        let uffd = FaultInjector::new(FakeUffd::new(), PAGE_SIZE)
+            .inject_copy(ResolveFault::Again, 1.0)
+            .inject_zeropage(ResolveFault::NoProcess, 1.0)
+            .inject_read(ReadFault::Eof, 1.0);
+        let src = vec![7u8; 4 * PAGE_SIZE];
+        let dst = 0x10_0000 as *mut c_void;
+
+        unsafe {
+            assert!(matches!(
+                uffd.copy(src.as_ptr() as *const c_void, dst, 4 * PAGE_SIZE, true),
+                Err(Error::PartiallyCopied(n)) if n == 2 * PAGE_SIZE
+            ));
+            assert!(matches!(
+                uffd.zeropage(dst, PAGE_SIZE, true),
+                Err(Error::ZeropageFailed(Errno::ESRCH))
+            ));
+        }
+        assert!(matches!(uffd.read_event(), Err(Error::ReadEof)));
+        assert_eq!(uffd.injected(), 3);
+
+        // Only the first half of the copy reached the backend.
+        assert_eq!(
+            uffd.inner().calls(),
+            vec![Call::Copy {
+                dst: dst as usize,
+                data: vec![7; 2 * PAGE_SIZE],
+                mode: CopyMode::empty(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_injection_rate() {
+        let uffd = FaultInjector::new(FakeUffd::new(), PAGE_SIZE)
+            .seed(42)
+            .inject_zeropage(ResolveFault::Exists, 0.2)
+            .inject_zeropage(ResolveFault::NoProcess, 0.1);
+        let dst = 0x10_0000 as *mut c_void;
+
+        let (mut exists, mut no_process) = (0, 0);
+        for _ in 0..10_000 {
+            match unsafe { uffd.zeropage(dst, PAGE_SIZE, true) } {
+                Ok(n) => assert_eq!(n, PAGE_SIZE),
+                Err(Error::ZeropageFailed(Errno::EEXIST)) => exists += 1,
+                Err(Error::ZeropageFailed(Errno::ESRCH)) => no_process += 1,
+                Err(e) => panic!("unexpected error {:?}", e),
+            }
+        }
+        assert!((1_800..2_200).contains(&exists), "{}", exists);
+        assert!((800..1_200).contains(&no_process), "{}", no_process);
+        assert_eq!(uffd.injected(), exists + no_process);
+        assert_eq!(uffd.inner().calls().len() as u64, 10_000 - uffd.injected());
+    }
+
+    #[test]
+    fn test_single_page_again() {
+        let uffd =
+            FaultInjector::new(FakeUffd::new(), PAGE_SIZE).inject_copy(ResolveFault::Again, 1.0);
+        let src = vec![7u8; PAGE_SIZE];
+        let dst = 0x10_0000 as *mut c_void;
+
+        assert!(matches!(
+            unsafe { uffd.copy(src.as_ptr() as *const c_void, dst, PAGE_SIZE, true) },
+            Err(Error::PartiallyCopied(0))
+        ));
+        assert!(uffd.inner().calls().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "fault rates")]
+    fn test_rates_over_one() {
+        let _ = FaultInjector::new(FakeUffd::new(), PAGE_SIZE)
+            .inject_read(ReadFault::Eof, 0.6)
+            .inject_read(ReadFault::IncompleteMsg, 0.6);
+    }
+}
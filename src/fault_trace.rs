@@ -0,0 +1,443 @@
+//! Recording page faults to a compact binary trace.
+//!
+//! A fault trace captures which pages a workload touched and in what order, which is useful to
+//! prefetch the working set of a snapshot on restore. [`Recorder`] wraps [`Uffd::read_events`] and
+//! appends a [`TraceRecord`] for every `Event::Pagefault` that falls into one of its regions.
+//! [`TraceReader`] iterates the records of a trace file back.
+//!
+//! # Format
+//!
+//! All integers are little-endian. A trace starts with a header:
+//!
+//! | Size | Field                                   |
+//! |------|-----------------------------------------|
+//! | 8    | magic, `b"UFFDTRCE"`                    |
+//! | 4    | format version, currently 1             |
+//! | 4    | number of regions `n`                   |
+//! | 16n  | `n` regions as `(start: u64, len: u64)` |
+//!
+//! followed by fixed-size records until the end of the file:
+//!
+//! | Size | Field                                                       |
+//! |------|-------------------------------------------------------------|
+//! | 8    | nanoseconds since the recording started                     |
+//! | 4    | index of the region containing the fault                    |
+//! | 8    | offset of the faulting address from the start of the region |
+//! | 1    | fault kind: 0 missing, 1 write-protected, 2 minor           |
+//! | 1    | 0 for a read fault, 1 for a write fault                     |
+//! | 2    | reserved, zero                                              |
+//! | 4    | faulting thread ID, or 0 if unknown                         |
+
+use crate::error::Result;
+use crate::format::{self, invalid_data, u32_at, u64_at};
+use crate::{Event, EventBuffer, FaultKind, ReadWrite, Uffd};
+use libc::c_void;
+use std::convert::TryFrom;
+use std::io::{self, ErrorKind, Read, Write};
+use std::time::{Duration, Instant};
+
+/// The magic bytes at the start of every trace.
+pub const MAGIC: [u8; 8] = *b"UFFDTRCE";
+
+/// The trace format version written by [`TraceWriter`].
+pub const VERSION: u32 = 1;
+
+const RECORD_SIZE: usize = 28;
+
+/// A memory region that faults are recorded relative to.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TraceRegion {
+    /// The start address of the region when it was recorded.
+    pub start: u64,
+    /// The length of the region in bytes.
+    pub len: u64,
+}
+
+impl TraceRegion {
+    /// Create a region from a mapping.
+    pub fn new(start: *mut c_void, len: usize) -> Self {
+        TraceRegion {
+            start: start as u64,
+            len: len as u64,
+        }
+    }
+
+    fn offset_of(&self, addr: u64) -> Option<u64> {
+        addr.checked_sub(self.start).filter(|&off| off < self.len)
+    }
+}
+
+/// A single recorded page fault.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TraceRecord {
+    /// The time since the recording started.
+    pub timestamp: Duration,
+    /// The index of the region containing the fault.
+    pub region: u32,
+    /// The offset of the faulting address from the start of the region.
+    pub offset: u64,
+    /// The kind of fault.
+    pub kind: FaultKind,
+    /// Whether the fault was a read or a write.
+    pub rw: ReadWrite,
+    /// The thread that triggered the fault, or 0 if unknown.
+    pub thread_id: u32,
+}
+
+impl TraceRecord {
+    fn encode(&self) -> [u8; RECORD_SIZE] {
+        let mut buf = [0; RECORD_SIZE];
+        let nanos = u64::try_from(self.timestamp.as_nanos()).unwrap_or(u64::MAX);
+        buf[0..8].copy_from_slice(&nanos.to_le_bytes());
+        buf[8..12].copy_from_slice(&self.region.to_le_bytes());
+        buf[12..20].copy_from_slice(&self.offset.to_le_bytes());
+        buf[20] = match self.kind {
+            FaultKind::Missing => 0,
+            #[cfg(feature = "linux5_7")]
+            FaultKind::WriteProtected => 1,
+            #[cfg(feature = "linux5_13")]
+            FaultKind::Minor => 2,
+        };
+        buf[21] = match self.rw {
+            ReadWrite::Read => 0,
+            ReadWrite::Write => 1,
+        };
+        buf[24..28].copy_from_slice(&self.thread_id.to_le_bytes());
+        buf
+    }
+
+    fn decode(buf: &[u8; RECORD_SIZE]) -> io::Result<Self> {
+        let kind = match buf[20] {
+            0 => FaultKind::Missing,
+            #[cfg(feature = "linux5_7")]
+            1 => FaultKind::WriteProtected,
+            #[cfg(feature = "linux5_13")]
+            2 => FaultKind::Minor,
+            k => return Err(invalid_data(format!("unsupported fault kind {}", k))),
+        };
+        let rw = match buf[21] {
+            0 => ReadWrite::Read,
+            1 => ReadWrite::Write,
+            rw => return Err(invalid_data(format!("invalid read/write value {}", rw))),
+        };
+        Ok(TraceRecord {
+            timestamp: Duration::from_nanos(u64_at(buf, 0)),
+            region: u32_at(buf, 8),
+            offset: u64_at(buf, 12),
+            kind,
+            rw,
+            thread_id: u32_at(buf, 24),
+        })
+    }
+}
+
+/// Writes a trace to an underlying writer.
+///
+/// Records are written as they are appended, so the writer should usually be buffered.
+#[derive(Debug)]
+pub struct TraceWriter<W: Write> {
+    inner: W,
+    regions: Vec<TraceRegion>,
+}
+
+impl<W: Write> TraceWriter<W> {
+    /// Write the trace header for `regions` and return a writer for the records.
+    pub fn new(mut inner: W, regions: &[TraceRegion]) -> io::Result<Self> {
+        let count = u32::try_from(regions.len())
+            .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "too many regions"))?;
+        inner.write_all(&format::preamble(&MAGIC, VERSION))?;
+        inner.write_all(&count.to_le_bytes())?;
+        for region in regions {
+            inner.write_all(&region.start.to_le_bytes())?;
+            inner.write_all(&region.len.to_le_bytes())?;
+        }
+        Ok(TraceWriter {
+            inner,
+            regions: regions.to_vec(),
+        })
+    }
+
+    /// The regions this trace was created with.
+    pub fn regions(&self) -> &[TraceRegion] {
+        &self.regions
+    }
+
+    /// Append a record.
+    pub fn write_record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        self.inner.write_all(&record.encode())
+    }
+
+    /// Flush the underlying writer and return it.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+/// Reads a trace back, yielding its records in the order they were recorded.
+#[derive(Debug)]
+pub struct TraceReader<R: Read> {
+    inner: R,
+    version: u32,
+    regions: Vec<TraceRegion>,
+}
+
+impl<R: Read> TraceReader<R> {
+    /// Read and validate the trace header.
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let mut header = [0; 16];
+        inner.read_exact(&mut header)?;
+        format::check_preamble(&header, &MAGIC, VERSION, "fault trace")?;
+        let count = u32_at(&header, 12);
+        let mut regions = Vec::new();
+        for _ in 0..count {
+            let mut region = [0; 16];
+            inner.read_exact(&mut region)?;
+            regions.push(TraceRegion {
+                start: u64_at(&region, 0),
+                len: u64_at(&region, 8),
+            });
+        }
+        Ok(TraceReader {
+            inner,
+            version: VERSION,
+            regions,
+        })
+    }
+
+    /// The format version of the trace.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// The regions the trace was recorded with.
+    pub fn regions(&self) -> &[TraceRegion] {
+        &self.regions
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = io::Result<TraceRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut buf = [0; RECORD_SIZE];
+        let mut filled = 0;
+        while filled < RECORD_SIZE {
+            match self.inner.read(&mut buf[filled..]) {
+                Ok(0) if filled == 0 => return None,
+                Ok(0) => {
+                    return Some(Err(io::Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "truncated trace record",
+                    )))
+                }
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        Some(TraceRecord::decode(&buf))
+    }
+}
+
+/// Records the page faults read from a `Uffd` object into a trace.
+#[derive(Debug)]
+pub struct Recorder<W: Write> {
+    writer: TraceWriter<W>,
+    start: Instant,
+}
+
+impl<W: Write> Recorder<W> {
+    /// Start a recording of faults within `regions`, writing the trace to `inner`.
+    pub fn new(inner: W, regions: &[TraceRegion]) -> io::Result<Self> {
+        Ok(Recorder {
+            writer: TraceWriter::new(inner, regions)?,
+            start: Instant::now(),
+        })
+    }
+
+    /// Record `event` if it is a page fault within one of the regions of this recording.
+    ///
+    /// Returns whether the event was recorded.
+    pub fn record(&mut self, event: &Event) -> io::Result<bool> {
+        let (kind, rw, addr, thread_id) = match event {
+            Event::Pagefault {
+                kind,
+                rw,
+                addr,
+                #[cfg(feature = "linux4_14")]
+                thread_id,
+                ..
+            } => {
+                #[cfg(feature = "linux4_14")]
+                let thread_id = thread_id.as_raw() as u32;
+                #[cfg(not(feature = "linux4_14"))]
+                let thread_id = 0;
+                (*kind, *rw, addr.as_usize() as u64, thread_id)
+            }
+            _ => return Ok(false),
+        };
+
+        let timestamp = self.start.elapsed();
+        let location = self
+            .writer
+            .regions()
+            .iter()
+            .enumerate()
+            .find_map(|(i, region)| region.offset_of(addr).map(|offset| (i as u32, offset)));
+        match location {
+            Some((region, offset)) => {
+                self.writer.write_record(&TraceRecord {
+                    timestamp,
+                    region,
+                    offset,
+                    kind,
+                    rw,
+                    thread_id,
+                })?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Read events from `uffd` like [`Uffd::read_events`], recording every page fault.
+    ///
+    /// Events are recorded as the returned iterator is advanced. A failure to write the trace is
+    /// reported as `Error::Io` in place of the event that could not be recorded.
+    pub fn read_events<'a>(
+        &'a mut self,
+        uffd: &Uffd,
+        buf: &'a mut EventBuffer,
+    ) -> Result<impl Iterator<Item = Result<Event>> + 'a> {
+        let events = uffd.read_events(buf)?;
+        Ok(events.map(move |event| {
+            let event = event?;
+            self.record(&event)?;
+            Ok(event)
+        }))
+    }
+
+    /// Finish the recording, flushing and returning the underlying writer.
+    pub fn finish(self) -> io::Result<W> {
+        self.writer.finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::Registered;
+    use std::ptr;
+    use std::thread;
+
+    #[test]
+    fn test_trace_roundtrip() {
+        let regions = [
+            TraceRegion {
+                start: 0x1000,
+                len: 0x4000,
+            },
+            TraceRegion {
+                start: 0x10000,
+                len: 0x1000,
+            },
+        ];
+        let records = [
+            TraceRecord {
+                timestamp: Duration::from_nanos(5),
+                region: 0,
+                offset: 0x2000,
+                kind: FaultKind::Missing,
+                rw: ReadWrite::Read,
+                thread_id: 42,
+            },
+            TraceRecord {
+                timestamp: Duration::from_micros(7),
+                region: 1,
+                offset: 0x10,
+                kind: FaultKind::Missing,
+                rw: ReadWrite::Write,
+                thread_id: 0,
+            },
+        ];
+
+        let mut writer = TraceWriter::new(Vec::new(), &regions).unwrap();
+        for record in &records {
+            writer.write_record(record).unwrap();
+        }
+        let bytes = writer.finish().unwrap();
+        assert_eq!(
+            bytes.len(),
+            16 + 16 * regions.len() + RECORD_SIZE * records.len()
+        );
+
+        let reader = TraceReader::new(&bytes[..]).unwrap();
+        assert_eq!(reader.version(), VERSION);
+        assert_eq!(reader.regions(), &regions);
+        let read = reader.collect::<io::Result<Vec<_>>>().unwrap();
+        assert_eq!(read, records);
+
+        let truncated = TraceReader::new(&bytes[..bytes.len() - 1]).unwrap();
+        assert!(truncated.last().unwrap().is_err());
+        assert!(TraceReader::new(&bytes[1..]).is_err());
+    }
+
+    #[test]
+    fn test_recorder() -> Result<()> {
+        const PAGE_SIZE: usize = 4096;
+        const PAGES: usize = 4;
+
+        let registered = Registered::new(PAGE_SIZE * PAGES)?;
+        let uffd = &registered.uffd;
+
+        // Touch the pages in reverse order, writing to the odd ones.
+        let ptr = registered.mapping as usize;
+        let thread = thread::spawn(move || unsafe {
+            let ptr = ptr as *mut u8;
+            for i in (0..PAGES).rev() {
+                let page = ptr.add(i * PAGE_SIZE);
+                if i % 2 == 1 {
+                    *page = 1;
+                } else {
+                    ptr::read_volatile(page);
+                }
+            }
+        });
+
+        let mut recorder = Recorder::new(
+            Vec::new(),
+            &[TraceRegion::new(registered.mapping, PAGE_SIZE * PAGES)],
+        )?;
+        let mut buf = EventBuffer::new(1);
+        let mut seen = 0;
+        while seen < PAGES {
+            for event in recorder.read_events(uffd, &mut buf)? {
+                if let Event::Pagefault { addr, .. } = event? {
+                    unsafe { uffd.zeropage(addr.as_ptr(), PAGE_SIZE, true)? };
+                    seen += 1;
+                }
+            }
+        }
+
+        thread.join().expect("failed to join thread");
+
+        let trace = recorder.finish()?;
+        let records = TraceReader::new(&trace[..])?.collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(records.len(), PAGES);
+        for (record, i) in records.iter().zip((0..PAGES).rev()) {
+            assert_eq!(record.region, 0);
+            assert_eq!(record.offset, (i * PAGE_SIZE) as u64);
+            assert_eq!(record.kind, FaultKind::Missing);
+            let rw = if i % 2 == 1 {
+                ReadWrite::Write
+            } else {
+                ReadWrite::Read
+            };
+            assert_eq!(record.rw, rw);
+        }
+        assert!(records.windows(2).all(|w| w[0].timestamp <= w[1].timestamp));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,53 @@
+//! Framing shared by the file formats of this crate.
+//!
+//! Every format starts with 8 magic bytes followed by a little-endian `u32` format version. All
+//! other integers are little-endian too.
+
+use std::io::{self, ErrorKind};
+
+/// The size of the magic bytes and version at the start of every format.
+pub(crate) const PREAMBLE_SIZE: usize = 12;
+
+/// Encode the magic bytes and version of a format.
+pub(crate) fn preamble(magic: &[u8; 8], version: u32) -> [u8; PREAMBLE_SIZE] {
+    let mut preamble = [0; PREAMBLE_SIZE];
+    preamble[0..8].copy_from_slice(magic);
+    preamble[8..12].copy_from_slice(&version.to_le_bytes());
+    preamble
+}
+
+/// Check the magic bytes and version at the start of `header`, which holds a `what`.
+pub(crate) fn check_preamble(
+    header: &[u8],
+    magic: &[u8; 8],
+    version: u32,
+    what: &str,
+) -> io::Result<()> {
+    if header[0..8] != magic[..] {
+        return Err(invalid_data(format!("not a valid {}", what)));
+    }
+    let found = u32_at(header, 8);
+    if found != version {
+        return Err(invalid_data(format!(
+            "unsupported {} version {}",
+            what, found
+        )));
+    }
+    Ok(())
+}
+
+pub(crate) fn u32_at(buf: &[u8], at: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&buf[at..at + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+pub(crate) fn u64_at(buf: &[u8], at: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&buf[at..at + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+pub(crate) fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg)
+}
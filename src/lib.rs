@@ -7,14 +7,47 @@
 //! [`ioctl_userfaultfd(2)`](http://man7.org/linux/man-pages/man2/ioctl_userfaultfd.2.html) for more
 //! details.
 
+mod addr;
+pub mod backend;
 mod builder;
+#[cfg(feature = "dedup")]
+pub mod dedup;
+#[cfg(feature = "emulation")]
+pub mod emulation;
+#[cfg(feature = "encryption")]
+pub mod encrypted;
 mod error;
 mod event;
+#[cfg(feature = "linux5_7")]
+pub mod eviction;
+pub mod fake;
+pub mod fault_injection;
+pub mod fault_trace;
+mod format;
+pub mod migration;
+pub mod page_source;
+pub mod page_store;
+#[cfg(feature = "linux5_7")]
+pub mod precopy;
+pub mod prefetch;
 mod raw;
+#[cfg(feature = "serde")]
+mod serde_impls;
+#[cfg(feature = "linux4_14")]
+pub mod sigbus;
+#[cfg(any(feature = "emulation", feature = "linux4_14"))]
+mod signal;
+pub mod stats;
+pub mod swap;
+#[cfg(test)]
+mod test_util;
 
-pub use crate::builder::{FeatureFlags, UffdBuilder};
-pub use crate::error::{Error, Result};
-pub use crate::event::{Event, FaultKind, ReadWrite};
+pub use crate::addr::UffdAddr;
+pub use crate::builder::{
+    BackendPolicy, DescriptorBackend, FeatureFlags, UffdBuilder, UffdFactory, CAP_SYS_PTRACE,
+};
+pub use crate::error::{Error, PermissionReason, Result};
+pub use crate::event::{Event, FaultKind, PagefaultFlags, ReadWrite, UFFD_MSG_SIZE};
 
 use bitflags::bitflags;
 use libc::{self, c_void};
@@ -93,6 +126,84 @@ bitflags! {
     }
 }
 
+bitflags! {
+    /// The mode used when resolving a fault with [`Uffd::copy_with_mode`].
+    #[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+    pub struct CopyMode: u64 {
+        /// Do not wake up the thread waiting for page fault resolution.
+        const DONTWAKE = raw::UFFDIO_COPY_MODE_DONTWAKE;
+        /// Map the copied pages write-protected.
+        ///
+        /// This is only available if `IoctlFlags::WRITE_PROTECT` was returned when registering
+        /// the range.
+        #[cfg(feature = "linux5_7")]
+        const WRITE_PROTECT = raw::UFFDIO_COPY_MODE_WP;
+
+        /// Unknown mode flags are allowed to be robust to future kernel changes.
+        const _ = !0;
+    }
+}
+
+bitflags! {
+    /// The mode used when resolving a fault with [`Uffd::zeropage_with_mode`].
+    #[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+    pub struct ZeropageMode: u64 {
+        /// Do not wake up the thread waiting for page fault resolution.
+        const DONTWAKE = raw::UFFDIO_ZEROPAGE_MODE_DONTWAKE;
+
+        /// Unknown mode flags are allowed to be robust to future kernel changes.
+        const _ = !0;
+    }
+}
+
+#[cfg(feature = "linux5_7")]
+bitflags! {
+    /// The mode used when changing the write protection of a range with
+    /// [`Uffd::write_protect_with_mode`].
+    #[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+    pub struct WriteProtectMode: u64 {
+        /// Write-protect the range. If this is not set, write protection is removed instead.
+        const WRITE_PROTECT = raw::UFFDIO_WRITEPROTECT_MODE_WP;
+        /// Do not wake up the thread waiting for page fault resolution when removing write
+        /// protection.
+        const DONTWAKE = raw::UFFDIO_WRITEPROTECT_MODE_DONTWAKE;
+
+        /// Unknown mode flags are allowed to be robust to future kernel changes.
+        const _ = !0;
+    }
+}
+
+#[cfg(feature = "linux5_13")]
+bitflags! {
+    /// The mode used when resolving a minor fault with [`Uffd::continue_with_mode`].
+    #[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+    pub struct ContinueMode: u64 {
+        /// Do not wake up the thread waiting for page fault resolution.
+        const DONTWAKE = raw::UFFDIO_CONTINUE_MODE_DONTWAKE;
+        /// Map the pages write-protected.
+        ///
+        /// This requires Linux 6.3 and the `linux6_3` feature.
+        #[cfg(feature = "linux6_3")]
+        const WRITE_PROTECT = raw::UFFDIO_CONTINUE_MODE_WP;
+
+        /// Unknown mode flags are allowed to be robust to future kernel changes.
+        const _ = !0;
+    }
+}
+
+#[cfg(feature = "linux6_6")]
+bitflags! {
+    /// The mode used when poisoning a range with [`Uffd::poison_with_mode`].
+    #[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+    pub struct PoisonMode: u64 {
+        /// Do not wake up the thread waiting for page fault resolution.
+        const DONTWAKE = raw::UFFDIO_POISON_MODE_DONTWAKE;
+
+        /// Unknown mode flags are allowed to be robust to future kernel changes.
+        const _ = !0;
+    }
+}
+
 impl Uffd {
     /// Register a memory address range with the userfaultfd object, and returns the `IoctlFlags`
     /// that are available for the selected range.
@@ -104,6 +215,10 @@ impl Uffd {
 
     /// Register a memory address range with the userfaultfd object for the given mode and
     /// returns the `IoctlFlags` that are available for the selected range.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip(self), fields(fd = self.fd), err(Debug), ret)
+    )]
     pub fn register_with_mode(
         &self,
         start: *mut c_void,
@@ -125,6 +240,10 @@ impl Uffd {
     }
 
     /// Unregister a memory address range from the userfaultfd object.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip(self), fields(fd = self.fd), err(Debug), ret)
+    )]
     pub fn unregister(&self, start: *mut c_void, len: usize) -> Result<()> {
         let mut range = raw::uffdio_range {
             start: start as u64,
@@ -141,29 +260,60 @@ impl Uffd {
     ///
     /// If `wake` is `true`, wake up the thread waiting for page fault resolution on the memory
     /// range.
+    ///
+    /// # Safety
+    ///
+    /// `src` must be valid for reads of `len` bytes, and `dst` must point into a range registered
+    /// with this object.
     pub unsafe fn copy(
         &self,
         src: *const c_void,
         dst: *mut c_void,
         len: usize,
         wake: bool,
+    ) -> Result<usize> {
+        let mode = if wake {
+            CopyMode::empty()
+        } else {
+            CopyMode::DONTWAKE
+        };
+        self.copy_with_mode(src, dst, len, mode)
+    }
+
+    /// Atomically copy a continuous memory chunk into the userfaultfd-registered range using the
+    /// given mode, and return the number of bytes that were successfully copied.
+    ///
+    /// Unless `mode` contains `CopyMode::DONTWAKE`, wake up the thread waiting for page fault
+    /// resolution on the memory range.
+    ///
+    /// # Safety
+    ///
+    /// `src` must be valid for reads of `len` bytes, and `dst` must point into a range registered
+    /// with this object.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(self), fields(fd = self.fd), err(Debug), ret)
+    )]
+    pub unsafe fn copy_with_mode(
+        &self,
+        src: *const c_void,
+        dst: *mut c_void,
+        len: usize,
+        mode: CopyMode,
     ) -> Result<usize> {
         let mut copy = raw::uffdio_copy {
             src: src as u64,
             dst: dst as u64,
             len: len as u64,
-            mode: if wake {
-                0
-            } else {
-                raw::UFFDIO_COPY_MODE_DONTWAKE
-            },
+            mode: mode.bits(),
             copy: 0,
         };
 
         let _ =
             raw::copy(self.as_raw_fd(), &mut copy as *mut raw::uffdio_copy).map_err(|errno| {
                 match errno {
-                    Errno::EAGAIN => Error::PartiallyCopied(copy.copy as usize),
+                    // When nothing was copied, the kernel sets `copy` to `-EAGAIN`.
+                    Errno::EAGAIN => Error::PartiallyCopied(copy.copy.max(0) as usize),
                     _ => Error::CopyFailed(errno),
                 }
             })?;
@@ -180,17 +330,44 @@ impl Uffd {
     ///
     /// If `wake` is `true`, wake up the thread waiting for page fault resolution on the memory
     /// address range.
+    ///
+    /// # Safety
+    ///
+    /// `start` must point into a range registered with this object.
     pub unsafe fn zeropage(&self, start: *mut c_void, len: usize, wake: bool) -> Result<usize> {
+        let mode = if wake {
+            ZeropageMode::empty()
+        } else {
+            ZeropageMode::DONTWAKE
+        };
+        self.zeropage_with_mode(start, len, mode)
+    }
+
+    /// Zero out a memory address range registered with userfaultfd using the given mode, and
+    /// return the number of bytes that were successfully zeroed.
+    ///
+    /// Unless `mode` contains `ZeropageMode::DONTWAKE`, wake up the thread waiting for page fault
+    /// resolution on the memory address range.
+    ///
+    /// # Safety
+    ///
+    /// `start` must point into a range registered with this object.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(self), fields(fd = self.fd), err(Debug), ret)
+    )]
+    pub unsafe fn zeropage_with_mode(
+        &self,
+        start: *mut c_void,
+        len: usize,
+        mode: ZeropageMode,
+    ) -> Result<usize> {
         let mut zeropage = raw::uffdio_zeropage {
             range: raw::uffdio_range {
                 start: start as u64,
                 len: len as u64,
             },
-            mode: if wake {
-                0
-            } else {
-                raw::UFFDIO_ZEROPAGE_MODE_DONTWAKE
-            },
+            mode: mode.bits(),
             zeropage: 0,
         };
 
@@ -207,6 +384,10 @@ impl Uffd {
     }
 
     /// Wake up the thread waiting for page fault resolution on the specified memory address range.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(self), fields(fd = self.fd), err(Debug), ret)
+    )]
     pub fn wake(&self, start: *mut c_void, len: usize) -> Result<()> {
         let mut range = raw::uffdio_range {
             start: start as u64,
@@ -221,22 +402,7 @@ impl Uffd {
     /// Makes a range write-protected.
     #[cfg(feature = "linux5_7")]
     pub fn write_protect(&self, start: *mut c_void, len: usize) -> Result<()> {
-        let mut ioctl = raw::uffdio_writeprotect {
-            range: raw::uffdio_range {
-                start: start as u64,
-                len: len as u64,
-            },
-            mode: raw::UFFDIO_WRITEPROTECT_MODE_WP,
-        };
-
-        unsafe {
-            raw::write_protect(
-                self.as_raw_fd(),
-                &mut ioctl as *mut raw::uffdio_writeprotect,
-            )?;
-        }
-
-        Ok(())
+        self.write_protect_with_mode(start, len, WriteProtectMode::WRITE_PROTECT)
     }
 
     /// Removes the write-protection for a range.
@@ -249,17 +415,38 @@ impl Uffd {
         start: *mut c_void,
         len: usize,
         wake: bool,
+    ) -> Result<()> {
+        let mode = if wake {
+            WriteProtectMode::empty()
+        } else {
+            WriteProtectMode::DONTWAKE
+        };
+        self.write_protect_with_mode(start, len, mode)
+    }
+
+    /// Changes the write protection of a range using the given mode.
+    ///
+    /// If `mode` contains `WriteProtectMode::WRITE_PROTECT` the range is write-protected,
+    /// otherwise the protection is removed and, unless `mode` contains
+    /// `WriteProtectMode::DONTWAKE`, the thread waiting for page fault resolution on the memory
+    /// address range is woken up.
+    #[cfg(feature = "linux5_7")]
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(self), fields(fd = self.fd), err(Debug), ret)
+    )]
+    pub fn write_protect_with_mode(
+        &self,
+        start: *mut c_void,
+        len: usize,
+        mode: WriteProtectMode,
     ) -> Result<()> {
         let mut ioctl = raw::uffdio_writeprotect {
             range: raw::uffdio_range {
                 start: start as u64,
                 len: len as u64,
             },
-            mode: if wake {
-                0
-            } else {
-                raw::UFFDIO_WRITEPROTECT_MODE_DONTWAKE
-            },
+            mode: mode.bits(),
         };
 
         unsafe {
@@ -281,16 +468,38 @@ impl Uffd {
     /// returned EAGAIN.
     #[cfg(feature = "linux5_13")]
     pub fn r#continue(&self, start: *mut c_void, len: usize, wake: bool) -> Result<u64> {
+        let mode = if wake {
+            ContinueMode::empty()
+        } else {
+            ContinueMode::DONTWAKE
+        };
+        self.continue_with_mode(start, len, mode)
+    }
+
+    /// Resolves minor faults for a range using the given mode.
+    ///
+    /// Unless `mode` contains `ContinueMode::DONTWAKE`, wake up the thread waiting for page fault
+    /// resolution on the memory address range.
+    ///
+    /// Returns the number of bytes actually mapped. If this differs from `len`, then the ioctl
+    /// returned EAGAIN.
+    #[cfg(feature = "linux5_13")]
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(self), fields(fd = self.fd), err(Debug), ret)
+    )]
+    pub fn continue_with_mode(
+        &self,
+        start: *mut c_void,
+        len: usize,
+        mode: ContinueMode,
+    ) -> Result<u64> {
         let mut ioctl = raw::uffdio_continue {
             range: raw::uffdio_range {
                 start: start as u64,
                 len: len as u64,
             },
-            mode: if wake {
-                0
-            } else {
-                raw::UFFDIO_CONTINUE_MODE_DONTWAKE
-            },
+            mode: mode.bits(),
             mapped: 0,
         };
 
@@ -304,6 +513,63 @@ impl Uffd {
         }
     }
 
+    /// Resolves missing faults for a range by marking the pages as poisoned.
+    ///
+    /// Accessing a poisoned page raises `SIGBUS`, as if the page had a hardware memory error.
+    /// This is useful when the contents of a page cannot be provided.
+    ///
+    /// If `wake` is `true`, wake up the thread waiting for page fault resolution on the memory
+    /// address range.
+    ///
+    /// Returns the number of bytes actually poisoned. If this differs from `len`, then the ioctl
+    /// returned EAGAIN.
+    #[cfg(feature = "linux6_6")]
+    pub fn poison(&self, start: *mut c_void, len: usize, wake: bool) -> Result<u64> {
+        let mode = if wake {
+            PoisonMode::empty()
+        } else {
+            PoisonMode::DONTWAKE
+        };
+        self.poison_with_mode(start, len, mode)
+    }
+
+    /// Resolves missing faults for a range by marking the pages as poisoned, using the given
+    /// mode.
+    ///
+    /// Unless `mode` contains `PoisonMode::DONTWAKE`, wake up the thread waiting for page fault
+    /// resolution on the memory address range.
+    ///
+    /// Returns the number of bytes actually poisoned. If this differs from `len`, then the ioctl
+    /// returned EAGAIN.
+    #[cfg(feature = "linux6_6")]
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip(self), fields(fd = self.fd), err(Debug), ret)
+    )]
+    pub fn poison_with_mode(
+        &self,
+        start: *mut c_void,
+        len: usize,
+        mode: PoisonMode,
+    ) -> Result<u64> {
+        let mut ioctl = raw::uffdio_poison {
+            range: raw::uffdio_range {
+                start: start as u64,
+                len: len as u64,
+            },
+            mode: mode.bits(),
+            updated: 0,
+        };
+
+        let r = unsafe { raw::poison(self.as_raw_fd(), &mut ioctl as *mut raw::uffdio_poison) };
+
+        match r {
+            Err(Errno::EAGAIN) if ioctl.updated > 0 => Ok(ioctl.updated as u64),
+            Err(err) => Err(err.into()),
+            Ok(_) => Ok(ioctl.updated as u64),
+        }
+    }
+
     /// Read an `Event` from the userfaultfd object.
     ///
     /// If the `Uffd` object was created with `non_blocking` set to `false`, this will block until
@@ -375,28 +641,47 @@ impl Uffd {
     ) -> Result<impl Iterator<Item = Result<Event>> + 'a> {
         const MSG_SIZE: usize = std::mem::size_of::<raw::uffd_msg>();
 
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!("read_events", fd = self.fd, max = msgs.len()).entered();
+
         let buf = unsafe {
             std::slice::from_raw_parts_mut(msgs.as_mut_ptr() as _, msgs.len() * MSG_SIZE)
         };
 
         let count = match read(self.as_raw_fd(), buf) {
-            Err(e) if e == Errno::EAGAIN => 0,
-            Err(e) => return Err(Error::SystemError(e)),
-            Ok(0) => return Err(Error::ReadEof),
+            Err(Errno::EAGAIN) => Ok(0),
+            Err(e) => Err(Error::SystemError(e)),
+            Ok(0) => Err(Error::ReadEof),
             Ok(bytes_read) => {
                 let remainder = bytes_read % MSG_SIZE;
                 if remainder != 0 {
-                    return Err(Error::IncompleteMsg {
+                    Err(Error::IncompleteMsg {
                         read: remainder,
                         expected: MSG_SIZE,
-                    });
+                    })
+                } else {
+                    Ok(bytes_read / MSG_SIZE)
                 }
-
-                bytes_read / MSG_SIZE
             }
         };
 
-        Ok(msgs.iter().take(count).map(|msg| Event::from_uffd_msg(msg)))
+        #[cfg(feature = "tracing")]
+        match &count {
+            Ok(count) => tracing::trace!(count, "read events"),
+            Err(error) => tracing::trace!(?error, "failed to read events"),
+        }
+
+        let count = count?;
+
+        #[cfg(feature = "tracing")]
+        let fd = self.fd;
+
+        Ok(msgs.iter().take(count).map(move |msg| {
+            let event = Event::from_uffd_msg(msg);
+            #[cfg(feature = "tracing")]
+            tracing::trace!(fd, ?event, "event");
+            event
+        }))
     }
 }
 
@@ -411,6 +696,8 @@ bitflags! {
         const ZEROPAGE = 1 << raw::_UFFDIO_ZEROPAGE;
         #[cfg(feature = "linux5_7")]
         const WRITE_PROTECT = 1 << raw::_UFFDIO_WRITEPROTECT;
+        #[cfg(feature = "linux6_6")]
+        const POISON = 1 << raw::_UFFDIO_POISON;
         const API = 1 << raw::_UFFDIO_API;
 
         /// Unknown ioctls flags are allowed to be robust to future kernel changes.
@@ -424,6 +711,41 @@ mod test {
     use std::ptr;
     use std::thread;
 
+    #[test]
+    fn test_pagefault_flags() {
+        let flags = PagefaultFlags::WRITE | PagefaultFlags::from_bits_retain(1 << 40);
+        assert_eq!(flags.kind(), FaultKind::Missing);
+        assert_eq!(flags.rw(), ReadWrite::Write);
+        assert_eq!(flags.bits(), raw::UFFD_PAGEFAULT_FLAG_WRITE | 1 << 40);
+        assert_eq!(PagefaultFlags::empty().rw(), ReadWrite::Read);
+
+        #[cfg(all(feature = "linux5_7", feature = "linux5_13"))]
+        {
+            let both = PagefaultFlags::WRITE_PROTECT | PagefaultFlags::MINOR;
+            assert_eq!(both.kind(), FaultKind::Minor);
+            assert!(both.contains(PagefaultFlags::WRITE_PROTECT));
+        }
+    }
+
+    #[test]
+    fn test_unknown_event() -> Result<()> {
+        let mut msg: raw::uffd_msg = unsafe { std::mem::zeroed() };
+        msg.event = 0x7f;
+        msg.arg.remap.from = 0x1234;
+
+        let event = Event::from_uffd_msg(&msg)?;
+        assert_eq!(event.event_code(), 0x7f);
+        let raw = event.raw_msg().unwrap();
+        assert_eq!(raw[0], 0x7f);
+        assert_eq!(raw[8..16], 0x1234u64.to_ne_bytes());
+
+        msg.event = raw::UFFD_EVENT_REMAP;
+        let event = Event::from_uffd_msg(&msg)?;
+        assert_eq!(event.event_code(), raw::UFFD_EVENT_REMAP);
+        assert!(event.raw_msg().is_none());
+        Ok(())
+    }
+
     #[test]
     fn test_read_event() -> Result<()> {
         const PAGE_SIZE: usize = 4096;
@@ -456,8 +778,8 @@ mod test {
                     addr,
                     ..
                 }) => {
-                    assert_eq!(addr, mapping);
-                    uffd.zeropage(addr, PAGE_SIZE, true)?;
+                    assert_eq!(addr.as_ptr(), mapping);
+                    uffd.zeropage(addr.as_ptr(), PAGE_SIZE, true)?;
                 }
                 _ => panic!("unexpected event"),
             }
@@ -510,8 +832,8 @@ mod test {
                         addr,
                         ..
                     }) => {
-                        assert_eq!(addr, mapping);
-                        uffd.zeropage(addr, PAGE_SIZE, true)?;
+                        assert_eq!(addr.as_ptr(), mapping);
+                        uffd.zeropage(addr.as_ptr(), PAGE_SIZE, true)?;
                         break;
                     }
                     Some(_) => panic!("unexpected event"),
@@ -598,10 +920,10 @@ mod test {
                         addr,
                         ..
                     } => {
-                        let index = (addr as usize - mapping as usize) / PAGE_SIZE;
-                        assert_eq!(seen[index], false);
+                        let index = (addr.as_usize() - mapping as usize) / PAGE_SIZE;
+                        assert!(!seen[index]);
                         seen[index] = true;
-                        uffd.zeropage(addr, PAGE_SIZE, true)?;
+                        uffd.zeropage(addr.as_ptr(), PAGE_SIZE, true)?;
                     }
                     _ => panic!("unexpected event"),
                 }
@@ -672,14 +994,14 @@ mod test {
                         ..
                     }) => match kind {
                         FaultKind::WriteProtected => {
-                            assert_eq!(addr, mapping);
-                            assert_eq!(*(addr as *const u8), 0);
+                            assert_eq!(addr.as_ptr(), mapping);
+                            assert_eq!(*(addr.as_ptr() as *const u8), 0);
                             // Remove the protection and wake the page
                             uffd.remove_write_protection(mapping, PAGE_SIZE, true)?;
                             break;
                         }
                         FaultKind::Missing => {
-                            assert_eq!(addr, mapping);
+                            assert_eq!(addr.as_ptr(), mapping);
                             uffd.zeropage(mapping, PAGE_SIZE, false)?;
 
                             // Technically, we already know it was a write that triggered
@@ -688,10 +1010,12 @@ mod test {
                             // world, a missing fault with `rw` being `ReadWrite::Write` would
                             // be enough to mark the page as "dirty". For this test, however,
                             // we do it this way to ensure a write-protected fault is read.
-                            assert_eq!(*(addr as *const u8), 0);
+                            assert_eq!(*(addr.as_ptr() as *const u8), 0);
                             uffd.write_protect(mapping, PAGE_SIZE)?;
                             uffd.wake(mapping, PAGE_SIZE)?;
                         }
+                        #[cfg(feature = "linux5_13")]
+                        FaultKind::Minor => panic!("unexpected minor fault"),
                     },
                     _ => panic!("unexpected event"),
                 }
@@ -708,4 +1032,143 @@ mod test {
 
         Ok(())
     }
+
+    #[cfg(feature = "linux5_7")]
+    #[test]
+    fn test_copy_write_protect() -> Result<()> {
+        const PAGE_SIZE: usize = 4096;
+
+        unsafe {
+            let uffd = UffdBuilder::new()
+                .require_features(FeatureFlags::PAGEFAULT_FLAG_WP)
+                .close_on_exec(true)
+                .create()?;
+
+            let mapping = libc::mmap(
+                ptr::null_mut(),
+                PAGE_SIZE,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANON,
+                -1,
+                0,
+            );
+
+            assert!(!mapping.is_null());
+
+            assert!(uffd
+                .register_with_mode(
+                    mapping,
+                    PAGE_SIZE,
+                    RegisterMode::MISSING | RegisterMode::WRITE_PROTECT
+                )?
+                .contains(IoctlFlags::WRITE_PROTECT));
+
+            let src = [7u8; PAGE_SIZE];
+
+            let ptr = mapping as usize;
+            let thread = thread::spawn(move || {
+                let ptr = ptr as *mut u8;
+                *ptr = 1;
+            });
+
+            // The first fault is the missing page. Installing it write-protected must cause the
+            // retried write to fault again, this time as a write-protect fault.
+            match uffd.read_event()? {
+                Some(Event::Pagefault {
+                    kind: FaultKind::Missing,
+                    addr,
+                    ..
+                }) => {
+                    assert_eq!(addr.as_ptr(), mapping);
+                    let copied = uffd.copy_with_mode(
+                        src.as_ptr() as *const c_void,
+                        mapping,
+                        PAGE_SIZE,
+                        CopyMode::WRITE_PROTECT,
+                    )?;
+                    assert_eq!(copied, PAGE_SIZE);
+                }
+                _ => panic!("unexpected event"),
+            }
+
+            match uffd.read_event()? {
+                Some(Event::Pagefault {
+                    kind: FaultKind::WriteProtected,
+                    rw: ReadWrite::Write,
+                    addr,
+                    ..
+                }) => {
+                    assert_eq!(addr.as_ptr(), mapping);
+                    assert_eq!(*(addr.as_ptr() as *const u8), 7);
+                    uffd.remove_write_protection(mapping, PAGE_SIZE, true)?;
+                }
+                _ => panic!("unexpected event"),
+            }
+
+            thread.join().expect("failed to join thread");
+
+            assert_eq!(*(mapping as *const u8), 1);
+
+            uffd.unregister(mapping, PAGE_SIZE)?;
+
+            assert_eq!(libc::munmap(mapping, PAGE_SIZE), 0);
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "linux6_6")]
+    #[test]
+    fn test_poison() -> Result<()> {
+        const PAGE_SIZE: usize = 4096;
+        const PAGES: usize = 2;
+
+        unsafe {
+            let uffd = UffdBuilder::new()
+                .close_on_exec(true)
+                .non_blocking(true)
+                .create()?;
+
+            let mapping = libc::mmap(
+                ptr::null_mut(),
+                PAGE_SIZE * PAGES,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANON,
+                -1,
+                0,
+            );
+
+            assert!(!mapping.is_null());
+
+            let ioctls = uffd.register(mapping, PAGE_SIZE * PAGES)?;
+            assert!(ioctls.contains(IoctlFlags::POISON));
+
+            assert_eq!(uffd.poison(mapping, PAGE_SIZE, true)?, PAGE_SIZE as u64);
+
+            // Accessing the poisoned page raises SIGBUS. Do it in a child process, which inherits
+            // the poisoned page table entry.
+            let pid = libc::fork();
+            if pid == 0 {
+                ptr::read_volatile(mapping as *const u8);
+                libc::_exit(0);
+            }
+            let mut status = 0;
+            assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+            assert!(libc::WIFSIGNALED(status));
+            assert_eq!(libc::WTERMSIG(status), libc::SIGBUS);
+
+            let page = [0x2a_u8; PAGE_SIZE];
+            let second = (mapping as *mut u8).add(PAGE_SIZE) as *mut c_void;
+            assert_eq!(
+                uffd.copy(page.as_ptr() as *const c_void, second, PAGE_SIZE, true)?,
+                PAGE_SIZE
+            );
+
+            uffd.unregister(mapping, PAGE_SIZE * PAGES)?;
+
+            assert_eq!(libc::munmap(mapping, PAGE_SIZE * PAGES), 0);
+        }
+
+        Ok(())
+    }
 }
@@ -0,0 +1,598 @@
+//! Post-copy live migration over a stream.
+//!
+//! With post-copy migration, the destination resumes the workload before its memory has been
+//! transferred. [`MigrationSource`] pushes every page of the region over the stream in the
+//! background, and [`MigrationDestination`] installs them with `UFFDIO_COPY` or
+//! `UFFDIO_ZEROPAGE`. When the workload faults on a page that hasn't arrived yet, the destination
+//! requests it from the source, which sends it ahead of the remaining background pages.
+//!
+//! Any stream that can be polled works as a transport, such as a `UnixStream` or a `TcpStream`.
+//!
+//! The same stream can first carry the rounds of a [pre-copy](crate::precopy) migration, which
+//! send pages while the workload still runs on the source. The destination installs them with
+//! [`MigrationDestination::precopy`] until the hand-off, and then resumes the workload and
+//! switches to post-copy with [`MigrationDestination::run`].
+//!
+//! # Wire format
+//!
+//! All integers are little-endian. The source starts with a 24-byte header: [`MAGIC`], the
+//! [`VERSION`] as a `u32`, the page size as a `u32` and the page count as a `u64`. Every message
+//! after that starts with a tag byte:
+//!
+//! | Tag | Direction              | Payload                       |
+//! |-----|------------------------|-------------------------------|
+//! | 1   | destination to source  | `Request`: page index (`u64`) |
+//! | 2   | destination to source  | `Done`: none                  |
+//! | 3   | source to destination  | `Page`: page index (`u64`), page data |
+//! | 4   | source to destination  | `Zero`: page index (`u64`)    |
+//! | 5   | source to destination  | `Complete`: none              |
+//! | 6   | source to destination  | `Invalidate`: page index (`u64`) |
+//! | 7   | source to destination  | `Handoff`: none               |
+//!
+//! A page may be sent more than once before `Handoff`, in which case the destination replaces
+//! it. `Invalidate` drops a page that was modified on the source after it was sent, so that it is
+//! requested again. After `Handoff`, the source sends every page at most once. It sends
+//! `Complete` once every page was sent, and the destination acknowledges with `Done` once every
+//! page was installed.
+
+use crate::backend::UffdBackend;
+use crate::error::{Error, Result};
+use crate::page_source::{install_page, PageBuffer, PageContents, PageSource};
+use crate::{CopyMode, Event, EventBuffer, UffdAddr};
+use libc::c_void;
+use nix::errno::Errno;
+use std::convert::TryInto;
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::unix::io::AsRawFd;
+
+/// The magic bytes at the start of every migration stream.
+pub const MAGIC: [u8; 8] = *b"UFFDMIGR";
+
+/// The protocol version spoken by this module.
+pub const VERSION: u32 = 1;
+
+const REQUEST: u8 = 1;
+const DONE: u8 = 2;
+pub(crate) const PAGE: u8 = 3;
+pub(crate) const ZERO: u8 = 4;
+const COMPLETE: u8 = 5;
+pub(crate) const INVALIDATE: u8 = 6;
+pub(crate) const HANDOFF: u8 = 7;
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+fn read_tag<T: Read>(stream: &mut T) -> io::Result<u8> {
+    let mut tag = [0; 1];
+    stream.read_exact(&mut tag)?;
+    Ok(tag[0])
+}
+
+fn read_u64<T: Read>(stream: &mut T) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    stream.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Send the stream header for `page_count` pages of `page_size` bytes.
+pub(crate) fn write_header<T: Write>(
+    stream: &mut T,
+    page_size: usize,
+    page_count: u64,
+) -> io::Result<()> {
+    let mut header = [0; 24];
+    header[0..8].copy_from_slice(&MAGIC);
+    header[8..12].copy_from_slice(&VERSION.to_le_bytes());
+    header[12..16].copy_from_slice(&(page_size as u32).to_le_bytes());
+    header[16..24].copy_from_slice(&page_count.to_le_bytes());
+    stream.write_all(&header)
+}
+
+/// The index of the page containing `addr` among the `count` pages at `base`, or `EFAULT` if the
+/// address is outside of them, for example because a larger range was registered.
+pub(crate) fn page_index(
+    addr: UffdAddr,
+    base: *mut c_void,
+    page_size: usize,
+    count: usize,
+) -> Result<usize> {
+    addr.checked_offset_from(UffdAddr::from(base))
+        .map(|offset| offset / page_size)
+        .filter(|&index| index < count)
+        .ok_or_else(|| Errno::EFAULT.into())
+}
+
+/// Wait up to `timeout` milliseconds for `fds` to become readable, and return which are.
+pub(crate) fn poll_readable<const N: usize>(fds: [i32; N], timeout: i32) -> Result<[bool; N]> {
+    let mut pollfds = fds.map(|fd| libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    });
+    match Errno::result(unsafe { libc::poll(pollfds.as_mut_ptr(), N as libc::nfds_t, timeout) }) {
+        Ok(_) => Ok(pollfds.map(|fd| fd.revents & (libc::POLLIN | libc::POLLHUP) != 0)),
+        Err(Errno::EINTR) => Ok([false; N]),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Sends the pages of a region to a [`MigrationDestination`].
+pub struct MigrationSource<T, S> {
+    stream: T,
+    source: S,
+    sent: Vec<bool>,
+    next: usize,
+    buf: Vec<u8>,
+    demand_requests: usize,
+}
+
+impl<T: Read + Write + AsRawFd, S: PageSource> MigrationSource<T, S> {
+    /// Create the source side of a migration of `page_count` pages of `page_size` bytes, read
+    /// from `source`, and send the stream header.
+    pub fn new(mut stream: T, source: S, page_size: usize, page_count: u64) -> io::Result<Self> {
+        write_header(&mut stream, page_size, page_count)?;
+        Ok(Self::resume(
+            stream,
+            source,
+            page_size,
+            vec![false; page_count as usize],
+        ))
+    }
+
+    /// Continue a migration whose header was already sent, where the destination already holds
+    /// the current contents of the pages marked in `sent`.
+    pub(crate) fn resume(stream: T, source: S, page_size: usize, sent: Vec<bool>) -> Self {
+        MigrationSource {
+            stream,
+            source,
+            sent,
+            next: 0,
+            buf: vec![0; 9 + page_size],
+            demand_requests: 0,
+        }
+    }
+
+    /// The number of pages that were sent because the destination requested them.
+    pub fn demand_requests(&self) -> usize {
+        self.demand_requests
+    }
+
+    /// The number of pages the destination holds so far.
+    pub fn sent_pages(&self) -> usize {
+        self.sent.iter().filter(|&&sent| sent).count()
+    }
+
+    /// Send every page, serving requests from the destination ahead of the background push, and
+    /// return once the destination acknowledged the migration.
+    pub fn run(&mut self) -> Result<()> {
+        loop {
+            // Requests are checked before every background page, so a faulting thread on the
+            // destination waits for at most one page ahead of its own.
+            let done = if poll_readable([self.stream.as_raw_fd()], 0)?[0] {
+                self.serve_request()?
+            } else if let Some(page) = (self.next..self.sent.len()).find(|&i| !self.sent[i]) {
+                self.next = page + 1;
+                self.send_page(page as u64)?;
+                false
+            } else {
+                break;
+            };
+            if done {
+                return Err(invalid_data("migration ended before completion".to_string()).into());
+            }
+        }
+
+        self.stream.write_all(&[COMPLETE])?;
+        // Requests for pages that were in flight may still arrive before the acknowledgement.
+        while !self.serve_request()? {}
+        Ok(())
+    }
+
+    /// Read one message from the destination and serve it, returning whether it was `Done`.
+    fn serve_request(&mut self) -> Result<bool> {
+        match read_tag(&mut self.stream)? {
+            REQUEST => {
+                let page = read_u64(&mut self.stream)?;
+                if page >= self.sent.len() as u64 {
+                    return Err(
+                        invalid_data(format!("request for page {} out of range", page)).into(),
+                    );
+                }
+                if !self.sent[page as usize] {
+                    self.demand_requests += 1;
+                    self.send_page(page)?;
+                }
+                Ok(false)
+            }
+            DONE => Ok(true),
+            tag => Err(invalid_data(format!("unexpected message tag {}", tag)).into()),
+        }
+    }
+
+    fn send_page(&mut self, page: u64) -> Result<()> {
+        let contents = self.source.read_page(page, &mut self.buf[9..])?;
+        self.buf[1..9].copy_from_slice(&page.to_le_bytes());
+        match contents {
+            PageContents::Data => {
+                self.buf[0] = PAGE;
+                self.stream.write_all(&self.buf)?;
+            }
+            PageContents::Zero => {
+                self.buf[0] = ZERO;
+                self.stream.write_all(&self.buf[..9])?;
+            }
+        }
+        self.sent[page as usize] = true;
+        Ok(())
+    }
+}
+
+/// Installs the pages sent by a [`MigrationSource`] into a registered region.
+///
+/// The `Uffd` object must be non-blocking, since faults and incoming pages are polled for on the
+/// same thread.
+pub struct MigrationDestination<'a, U: UffdBackend + ?Sized, T> {
+    uffd: &'a U,
+    base: *mut c_void,
+    page_size: usize,
+    stream: T,
+    received: Vec<bool>,
+    requested: Vec<bool>,
+    buf: PageBuffer,
+    events: EventBuffer,
+    demand_faults: usize,
+    complete: bool,
+}
+
+/// A message received by a [`MigrationDestination`].
+enum Received {
+    Page,
+    Handoff,
+    Complete,
+}
+
+impl<'a, U: UffdBackend + ?Sized, T: Read + Write> MigrationDestination<'a, U, T> {
+    /// Read the stream header and create the destination side of a migration into the range of
+    /// `len` bytes at `base`, which must be registered with `uffd` in missing mode.
+    pub fn new(uffd: &'a U, base: *mut c_void, len: usize, mut stream: T) -> Result<Self> {
+        let mut header = [0; 24];
+        stream.read_exact(&mut header)?;
+        if header[0..8] != MAGIC {
+            return Err(invalid_data("not a migration stream".to_string()).into());
+        }
+        let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        if version != VERSION {
+            return Err(invalid_data(format!("unsupported migration version {}", version)).into());
+        }
+        let page_size = u32::from_le_bytes(header[12..16].try_into().unwrap()) as usize;
+        let page_count = u64::from_le_bytes(header[16..24].try_into().unwrap());
+        if page_size == 0 || page_count.checked_mul(page_size as u64) != Some(len as u64) {
+            return Err(invalid_data(format!(
+                "{} pages of {} bytes don't match a region of {} bytes",
+                page_count, page_size, len
+            ))
+            .into());
+        }
+        Ok(MigrationDestination {
+            uffd,
+            base,
+            page_size,
+            stream,
+            received: vec![false; page_count as usize],
+            requested: vec![false; page_count as usize],
+            buf: PageBuffer::new(page_size, page_size),
+            events: EventBuffer::new(16),
+            demand_faults: 0,
+            complete: false,
+        })
+    }
+
+    /// The number of faults on pages that had not arrived yet.
+    pub fn demand_faults(&self) -> usize {
+        self.demand_faults
+    }
+
+    /// The number of pages installed so far.
+    pub fn received_pages(&self) -> usize {
+        self.received.iter().filter(|&&received| received).count()
+    }
+
+    /// Install the pages of pre-copy rounds until the source hands off the migration.
+    ///
+    /// The workload must not run on the destination yet. Pages that are sent again are dropped
+    /// with `MADV_DONTNEED` and reinstalled, so the `Uffd` object must not be created with
+    /// `FeatureFlags::EVENT_REMOVE`.
+    pub fn precopy(&mut self) -> Result<()> {
+        while !self.complete {
+            match self.receive()? {
+                Received::Page => {}
+                Received::Handoff => break,
+                Received::Complete => self.complete = true,
+            }
+        }
+        Ok(())
+    }
+
+    fn serve_faults(&mut self) -> Result<()> {
+        for event in self.uffd.read_events(&mut self.events)? {
+            let addr = match event {
+                Event::Pagefault { addr, .. } => addr,
+                _ => continue,
+            };
+            let index = page_index(addr, self.base, self.page_size, self.received.len())?;
+            if self.received[index] {
+                // The page was installed after the fault was queued.
+                self.uffd.wake(self.page_addr(index), self.page_size)?;
+            } else if !self.requested[index] {
+                self.requested[index] = true;
+                self.demand_faults += 1;
+                let mut request = [REQUEST; 9];
+                request[1..9].copy_from_slice(&(index as u64).to_le_bytes());
+                self.stream.write_all(&request)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Read one message from the source and handle it.
+    fn receive(&mut self) -> Result<Received> {
+        let contents = match read_tag(&mut self.stream)? {
+            PAGE => Some(PageContents::Data),
+            ZERO => Some(PageContents::Zero),
+            INVALIDATE => None,
+            HANDOFF => return Ok(Received::Handoff),
+            COMPLETE => return Ok(Received::Complete),
+            tag => return Err(invalid_data(format!("unexpected message tag {}", tag)).into()),
+        };
+        let page = read_u64(&mut self.stream)?;
+        if page >= self.received.len() as u64 {
+            return Err(invalid_data(format!("page {} out of range", page)).into());
+        }
+        if let Some(PageContents::Data) = contents {
+            self.stream.read_exact(&mut self.buf)?;
+        }
+
+        let dst = self.page_addr(page as usize);
+        if self.received[page as usize] {
+            // The page was modified on the source since it was sent.
+            if unsafe { libc::madvise(dst, self.page_size, libc::MADV_DONTNEED) } != 0 {
+                return Err(Errno::last().into());
+            }
+            self.received[page as usize] = false;
+        }
+        let contents = match contents {
+            Some(contents) => contents,
+            None => return Ok(Received::Page),
+        };
+        match unsafe {
+            install_page(
+                self.uffd,
+                dst,
+                self.page_size,
+                contents,
+                &self.buf,
+                CopyMode::empty(),
+            )
+        } {
+            Ok(_) => {}
+            Err(Error::CopyFailed(Errno::EEXIST)) | Err(Error::ZeropageFailed(Errno::EEXIST)) => {
+                self.uffd.wake(dst, self.page_size)?
+            }
+            Err(e) => return Err(e),
+        }
+        self.received[page as usize] = true;
+        Ok(Received::Page)
+    }
+
+    fn page_addr(&self, index: usize) -> *mut c_void {
+        (self.base as usize + index * self.page_size) as *mut c_void
+    }
+}
+
+impl<'a, U: UffdBackend + AsRawFd + ?Sized, T: Read + Write + AsRawFd>
+    MigrationDestination<'a, U, T>
+{
+    /// Serve faults and install pages until the source completes the migration.
+    pub fn run(&mut self) -> Result<()> {
+        while !self.complete {
+            let [faults, pages] =
+                poll_readable([self.uffd.as_raw_fd(), self.stream.as_raw_fd()], -1)?;
+            if faults {
+                self.serve_faults()?;
+            }
+            if pages {
+                if let Received::Complete = self.receive()? {
+                    self.complete = true;
+                }
+            }
+        }
+
+        if let Some(page) = self.received.iter().position(|&received| !received) {
+            return Err(invalid_data(format!("migration completed without page {}", page)).into());
+        }
+        self.stream.write_all(&[DONE])?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fake::{Call, FakeUffd, Op};
+    use crate::{UffdBuilder, ZeropageMode};
+    use std::io::Cursor;
+    use std::os::unix::net::UnixStream;
+    use std::ptr;
+    use std::thread;
+
+    const PAGE_SIZE: usize = 4096;
+
+    fn page_source(page: u64, buf: &mut [u8]) -> io::Result<PageContents> {
+        if page % 4 == 3 {
+            return Ok(PageContents::Zero);
+        }
+        buf.fill(page as u8 + 1);
+        Ok(PageContents::Data)
+    }
+
+    #[test]
+    fn test_page_index() {
+        let base = 0x10_0000 as *mut c_void;
+        let addr = |offset: usize| UffdAddr::new(0x10_0000 + offset);
+        assert_eq!(
+            page_index(addr(PAGE_SIZE + 1), base, PAGE_SIZE, 2).unwrap(),
+            1
+        );
+        assert!(page_index(addr(2 * PAGE_SIZE), base, PAGE_SIZE, 2).is_err());
+        assert!(page_index(UffdAddr::new(0xf_ffff), base, PAGE_SIZE, 2).is_err());
+    }
+
+    #[test]
+    fn test_precopy_present_page() -> Result<()> {
+        const BASE: usize = 0x10_0000;
+
+        let mut stream = Vec::new();
+        write_header(&mut stream, PAGE_SIZE, 2)?;
+        stream.push(PAGE);
+        stream.extend_from_slice(&1u64.to_le_bytes());
+        stream.extend_from_slice(&[5; PAGE_SIZE]);
+        stream.push(ZERO);
+        stream.extend_from_slice(&0u64.to_le_bytes());
+        stream.push(HANDOFF);
+
+        // Page 0 was populated behind the destination's back, so it is only woken.
+        let uffd = FakeUffd::new();
+        uffd.inject(Op::Zeropage, Err(Error::ZeropageFailed(Errno::EEXIST)));
+        let mut destination = MigrationDestination::new(
+            &uffd,
+            BASE as *mut c_void,
+            PAGE_SIZE * 2,
+            Cursor::new(stream),
+        )?;
+        destination.precopy()?;
+        assert_eq!(destination.received_pages(), 2);
+
+        assert_eq!(
+            uffd.calls(),
+            vec![
+                Call::Copy {
+                    dst: BASE + PAGE_SIZE,
+                    data: vec![5; PAGE_SIZE],
+                    mode: CopyMode::empty(),
+                },
+                Call::Zeropage {
+                    start: BASE,
+                    len: PAGE_SIZE,
+                    mode: ZeropageMode::empty(),
+                },
+                Call::Wake {
+                    start: BASE,
+                    len: PAGE_SIZE,
+                },
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_requests_preempt_push() -> Result<()> {
+        let (source_end, mut destination_end) = UnixStream::pair()?;
+        destination_end.write_all(&[REQUEST, 10, 0, 0, 0, 0, 0, 0, 0])?;
+
+        let mut source = MigrationSource::new(source_end, page_source, PAGE_SIZE, 16)?;
+        let thread = thread::spawn(move || {
+            let result = source.run();
+            (result, source.demand_requests(), source.sent_pages())
+        });
+
+        let mut header = [0; 24];
+        destination_end.read_exact(&mut header)?;
+        assert_eq!(header[0..8], MAGIC);
+
+        // The requested page is sent before the background push starts.
+        let mut pages = Vec::new();
+        loop {
+            match read_tag(&mut destination_end)? {
+                PAGE => {
+                    pages.push(read_u64(&mut destination_end)?);
+                    destination_end.read_exact(&mut [0; PAGE_SIZE])?;
+                }
+                ZERO => pages.push(read_u64(&mut destination_end)?),
+                tag => {
+                    assert_eq!(tag, COMPLETE);
+                    break;
+                }
+            }
+        }
+        assert_eq!(pages[0], 10);
+        assert_eq!(pages.len(), 16);
+        destination_end.write_all(&[DONE])?;
+
+        let (result, demand_requests, sent_pages) = thread.join().unwrap();
+        result?;
+        assert_eq!(demand_requests, 1);
+        assert_eq!(sent_pages, 16);
+        Ok(())
+    }
+
+    #[test]
+    fn test_post_copy_migration() -> Result<()> {
+        const PAGES: usize = 64;
+
+        let (source_end, destination_end) = UnixStream::pair()?;
+        let source = thread::spawn(move || {
+            MigrationSource::new(source_end, page_source, PAGE_SIZE, PAGES as u64)?.run()
+        });
+
+        unsafe {
+            let uffd = UffdBuilder::new()
+                .close_on_exec(true)
+                .non_blocking(true)
+                .create()?;
+
+            let mapping = libc::mmap(
+                ptr::null_mut(),
+                PAGE_SIZE * PAGES,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANON,
+                -1,
+                0,
+            );
+
+            assert!(!mapping.is_null());
+
+            uffd.register(mapping, PAGE_SIZE * PAGES)?;
+
+            let mut destination =
+                MigrationDestination::new(&uffd, mapping, PAGE_SIZE * PAGES, destination_end)?;
+
+            // The workload runs right away, touching pages from the end of the region.
+            let ptr = mapping as usize;
+            let workload = thread::spawn(move || {
+                let ptr = ptr as *const u8;
+                (0..PAGES)
+                    .rev()
+                    .map(|i| ptr::read_volatile(ptr.add(i * PAGE_SIZE + 1)))
+                    .collect::<Vec<_>>()
+            });
+
+            destination.run()?;
+            source.join().unwrap()?;
+            assert_eq!(destination.received_pages(), PAGES);
+
+            let expected = (0..PAGES)
+                .rev()
+                .map(|i| if i % 4 == 3 { 0 } else { i as u8 + 1 })
+                .collect::<Vec<_>>();
+            assert_eq!(workload.join().unwrap(), expected);
+
+            uffd.unregister(mapping, PAGE_SIZE * PAGES)?;
+
+            assert_eq!(libc::munmap(mapping, PAGE_SIZE * PAGES), 0);
+        }
+
+        Ok(())
+    }
+}
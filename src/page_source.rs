@@ -0,0 +1,148 @@
+//! Sources of page contents for resolving faults.
+//!
+//! A [`PageSource`] provides the contents of the pages of a registered range, typically read from
+//! a snapshot. Fault handlers read a page into a [`PageBuffer`] and install it with
+//! [`install_page`], which uses `UFFDIO_ZEROPAGE` for pages the source reports as all zeros and
+//! `UFFDIO_COPY` otherwise.
+
+use crate::backend::UffdBackend;
+use crate::error::{Error, Result};
+use crate::{CopyMode, ZeropageMode};
+use libc::c_void;
+use nix::errno::Errno;
+use std::alloc::{self, Layout};
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
+
+/// What a [`PageSource`] produced for a page.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PageContents {
+    /// The buffer was filled with the contents of the page.
+    Data,
+    /// The page is all zeros; the buffer was left untouched.
+    Zero,
+}
+
+/// Provides the contents of pages by their index in a registered range.
+pub trait PageSource {
+    /// Read page `index` into `buf`, whose length is the page size.
+    fn read_page(&mut self, index: u64, buf: &mut [u8]) -> io::Result<PageContents>;
+}
+
+impl<F> PageSource for F
+where
+    F: FnMut(u64, &mut [u8]) -> io::Result<PageContents>,
+{
+    fn read_page(&mut self, index: u64, buf: &mut [u8]) -> io::Result<PageContents> {
+        self(index, buf)
+    }
+}
+
+/// A zero-initialized, page-aligned buffer.
+pub struct PageBuffer {
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+impl PageBuffer {
+    /// Allocate a buffer of `len` bytes aligned to `align`, which must be a power of two.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `len` is zero or `align` is not a power of two.
+    pub fn new(len: usize, align: usize) -> Self {
+        let layout = Layout::from_size_align(len, align).expect("invalid page buffer layout");
+        assert!(len > 0, "page buffer must not be empty");
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        let ptr = NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(layout));
+        PageBuffer { ptr, layout }
+    }
+
+    /// A pointer to the start of the buffer, suitable as the source of
+    /// [`Uffd::copy`](crate::Uffd::copy).
+    pub fn as_ptr(&self) -> *const c_void {
+        self.ptr.as_ptr() as *const c_void
+    }
+}
+
+impl Deref for PageBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl DerefMut for PageBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl Drop for PageBuffer {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), self.layout) };
+    }
+}
+
+unsafe impl Send for PageBuffer {}
+
+/// Install `len` bytes at `dst` that were produced by a [`PageSource`], and return the number of
+/// bytes installed.
+///
+/// Pages with `PageContents::Zero` are installed with `UFFDIO_ZEROPAGE`, and only
+/// `CopyMode::DONTWAKE` of `mode` is honored for them. Other pages are copied from `buf`.
+///
+/// # Safety
+///
+/// `buf` must hold at least `len` bytes, and `dst` must point into a range registered with
+/// `uffd`.
+pub unsafe fn install_page<U: UffdBackend + ?Sized>(
+    uffd: &U,
+    dst: *mut c_void,
+    len: usize,
+    contents: PageContents,
+    buf: &PageBuffer,
+    mode: CopyMode,
+) -> Result<usize> {
+    match contents {
+        PageContents::Data => uffd.copy_with_mode(buf.as_ptr(), dst, len, mode),
+        PageContents::Zero => {
+            let mode = if mode.contains(CopyMode::DONTWAKE) {
+                ZeropageMode::DONTWAKE
+            } else {
+                ZeropageMode::empty()
+            };
+            uffd.zeropage_with_mode(dst, len, mode)
+        }
+    }
+}
+
+/// Resolve a missing fault at `addr` in a range that starts at `base`, with the page read from
+/// `source` into `buf`, whose length is the page size.
+///
+/// If another thread installed the page concurrently, the faulting thread is woken instead.
+///
+/// # Safety
+///
+/// `base` must be the start of a range registered with `uffd`, and `addr` must lie within it.
+pub unsafe fn resolve_fault<U: UffdBackend + ?Sized, S: PageSource + ?Sized>(
+    uffd: &U,
+    source: &mut S,
+    base: *mut c_void,
+    addr: *mut c_void,
+    buf: &mut PageBuffer,
+) -> Result<()> {
+    let page_size = buf.len();
+    let index = (addr as usize - base as usize) / page_size;
+    let dst = (base as usize + index * page_size) as *mut c_void;
+    let contents = source.read_page(index as u64, buf)?;
+    match install_page(uffd, dst, page_size, contents, buf, CopyMode::empty()) {
+        Ok(_) => Ok(()),
+        Err(Error::CopyFailed(Errno::EEXIST)) | Err(Error::ZeropageFailed(Errno::EEXIST)) => {
+            uffd.wake(dst, page_size)
+        }
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,664 @@
+//! A compressed snapshot format with random access to individual pages.
+//!
+//! [`PageStoreWriter`] compresses every page of a snapshot independently and records where it
+//! ended up in an index, so that [`PageStore`] can later read back any single page without
+//! decompressing its neighbours. Pages that are all zeros are not stored at all; they are
+//! installed with [`Uffd::zeropage`](crate::Uffd::zeropage) instead of being copied.
+//!
+//! Pages are compressed with lz4 with the `lz4` feature, or zstd with the `zstd` feature.
+//! `Compression::None` is always available.
+//!
+//! # Format
+//!
+//! All integers are little-endian. A page store starts with a header:
+//!
+//! | Size | Field                                           |
+//! |------|-------------------------------------------------|
+//! | 8    | magic, `b"UFFDPAGE"`                            |
+//! | 4    | format version, currently 1                     |
+//! | 4    | page size in bytes                              |
+//! | 1    | compression: 0 none, 1 lz4, 2 zstd              |
+//! | 3    | reserved, zero                                  |
+//!
+//! followed by the page data, then an index with one entry per page:
+//!
+//! | Size | Field                                                                 |
+//! |------|-----------------------------------------------------------------------|
+//! | 8    | offset of the page data from the start of the store                   |
+//! | 4    | length of the page data: 0 for a zero page, the page size if uncompressed |
+//!
+//! and finally a footer:
+//!
+//! | Size | Field                           |
+//! |------|---------------------------------|
+//! | 8    | offset of the index             |
+//! | 8    | number of pages                 |
+
+use crate::backend::UffdBackend;
+use crate::error::{Error, Result};
+use crate::format::{self, invalid_data, u32_at, u64_at};
+use crate::page_source::{install_page, PageBuffer, PageContents, PageSource};
+use crate::CopyMode;
+use libc::c_void;
+use nix::errno::Errno;
+use std::convert::TryFrom;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+
+/// The magic bytes at the start of every page store.
+pub const MAGIC: [u8; 8] = *b"UFFDPAGE";
+
+/// The page store format version written by [`PageStoreWriter`].
+pub const VERSION: u32 = 1;
+
+const HEADER_SIZE: usize = 20;
+const INDEX_ENTRY_SIZE: usize = 12;
+const FOOTER_SIZE: usize = 16;
+
+/// The algorithm pages are compressed with.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Compression {
+    /// Pages are stored uncompressed.
+    None,
+    /// Pages are compressed with lz4. Requires the `lz4` feature.
+    Lz4,
+    /// Pages are compressed with zstd. Requires the `zstd` feature.
+    Zstd,
+}
+
+impl Compression {
+    fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+            Compression::Zstd => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> io::Result<Self> {
+        match id {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Lz4),
+            2 => Ok(Compression::Zstd),
+            id => Err(invalid_data(format!("unknown compression {}", id))),
+        }
+    }
+
+    // Returns `None` if the page does not compress, in which case it is stored as is.
+    fn compress(self, page: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let compressed = match self {
+            Compression::None => return Ok(None),
+            Compression::Lz4 => lz4_codec::compress(page)?,
+            Compression::Zstd => zstd_codec::compress(page)?,
+        };
+        Ok(Some(compressed).filter(|data| data.len() < page.len()))
+    }
+
+    fn decompress(self, data: &[u8], page: &mut [u8]) -> io::Result<()> {
+        let len = match self {
+            Compression::None => {
+                return Err(invalid_data(
+                    "compressed page in an uncompressed store".to_string(),
+                ))
+            }
+            Compression::Lz4 => lz4_codec::decompress(data, page)?,
+            Compression::Zstd => zstd_codec::decompress(data, page)?,
+        };
+        if len != page.len() {
+            return Err(invalid_data(format!(
+                "page decompressed to {} bytes instead of {}",
+                len,
+                page.len()
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "lz4")]
+mod lz4_codec {
+    use std::io;
+
+    pub fn compress(page: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(lz4_flex::block::compress(page))
+    }
+
+    pub fn decompress(data: &[u8], page: &mut [u8]) -> io::Result<usize> {
+        lz4_flex::block::decompress_into(data, page)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+#[cfg(not(feature = "lz4"))]
+mod lz4_codec {
+    use std::io;
+
+    pub fn compress(_: &[u8]) -> io::Result<Vec<u8>> {
+        Err(super::unsupported("lz4"))
+    }
+
+    pub fn decompress(_: &[u8], _: &mut [u8]) -> io::Result<usize> {
+        Err(super::unsupported("lz4"))
+    }
+}
+
+#[cfg(feature = "zstd")]
+mod zstd_codec {
+    use std::io;
+
+    pub fn compress(page: &[u8]) -> io::Result<Vec<u8>> {
+        zstd::bulk::compress(page, zstd::DEFAULT_COMPRESSION_LEVEL)
+    }
+
+    pub fn decompress(data: &[u8], page: &mut [u8]) -> io::Result<usize> {
+        zstd::bulk::decompress_to_buffer(data, page)
+    }
+}
+
+#[cfg(not(feature = "zstd"))]
+mod zstd_codec {
+    use std::io;
+
+    pub fn compress(_: &[u8]) -> io::Result<Vec<u8>> {
+        Err(super::unsupported("zstd"))
+    }
+
+    pub fn decompress(_: &[u8], _: &mut [u8]) -> io::Result<usize> {
+        Err(super::unsupported("zstd"))
+    }
+}
+
+#[allow(dead_code)]
+fn unsupported(feature: &str) -> io::Error {
+    io::Error::new(
+        ErrorKind::Unsupported,
+        format!("{} compression requires the `{}` feature", feature, feature),
+    )
+}
+
+#[derive(Clone, Copy, Debug)]
+struct IndexEntry {
+    offset: u64,
+    len: u32,
+}
+
+/// Writes a page store to an underlying writer.
+///
+/// Pages are numbered in the order they are written, starting at zero. The index is only written
+/// by [`finish`](PageStoreWriter::finish), so a store that was not finished cannot be read.
+pub struct PageStoreWriter<W: Write> {
+    inner: W,
+    page_size: usize,
+    compression: Compression,
+    offset: u64,
+    index: Vec<IndexEntry>,
+}
+
+impl<W: Write> PageStoreWriter<W> {
+    /// Write the header of a store with pages of `page_size` bytes, compressed with `compression`.
+    pub fn new(mut inner: W, page_size: usize, compression: Compression) -> io::Result<Self> {
+        let size = u32::try_from(page_size)
+            .ok()
+            .filter(|size| size.is_power_of_two())
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "invalid page size"))?;
+        // Fail early rather than on the first page that is not all zeros.
+        compression.compress(&[])?;
+
+        let mut header = [0; HEADER_SIZE];
+        header[..format::PREAMBLE_SIZE].copy_from_slice(&format::preamble(&MAGIC, VERSION));
+        header[12..16].copy_from_slice(&size.to_le_bytes());
+        header[16] = compression.id();
+        inner.write_all(&header)?;
+
+        Ok(PageStoreWriter {
+            inner,
+            page_size,
+            compression,
+            offset: HEADER_SIZE as u64,
+            index: Vec::new(),
+        })
+    }
+
+    /// Append the next page, which must be exactly one page long.
+    pub fn write_page(&mut self, page: &[u8]) -> io::Result<()> {
+        if page.len() != self.page_size {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("page is {} bytes instead of {}", page.len(), self.page_size),
+            ));
+        }
+
+        if page.iter().all(|&b| b == 0) {
+            self.index.push(IndexEntry {
+                offset: self.offset,
+                len: 0,
+            });
+            return Ok(());
+        }
+
+        let compressed = self.compression.compress(page)?;
+        let data = compressed.as_deref().unwrap_or(page);
+        self.inner.write_all(data)?;
+        self.index.push(IndexEntry {
+            offset: self.offset,
+            len: data.len() as u32,
+        });
+        self.offset += data.len() as u64;
+        Ok(())
+    }
+
+    /// Append every page of `data`, whose length must be a multiple of the page size.
+    pub fn write_pages(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut pages = data.chunks_exact(self.page_size);
+        if !pages.remainder().is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "data is not a multiple of the page size",
+            ));
+        }
+        pages.try_for_each(|page| self.write_page(page))
+    }
+
+    /// Write the index and footer, flush the underlying writer and return it.
+    pub fn finish(mut self) -> io::Result<W> {
+        for entry in &self.index {
+            let mut buf = [0; INDEX_ENTRY_SIZE];
+            buf[0..8].copy_from_slice(&entry.offset.to_le_bytes());
+            buf[8..12].copy_from_slice(&entry.len.to_le_bytes());
+            self.inner.write_all(&buf)?;
+        }
+        self.inner.write_all(&self.offset.to_le_bytes())?;
+        self.inner
+            .write_all(&(self.index.len() as u64).to_le_bytes())?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+/// Random access to the pages of a store, for resolving faults.
+///
+/// `PageStore` implements [`PageSource`], and can resolve faults directly with
+/// [`resolve_fault`](PageStore::resolve_fault), which decompresses only the faulting page and
+/// the pages in its readahead window.
+pub struct PageStore<R: Read + Seek> {
+    pages: Pages<R>,
+    buf: PageBuffer,
+    readahead: usize,
+}
+
+struct Pages<R> {
+    inner: R,
+    page_size: usize,
+    compression: Compression,
+    index: Vec<IndexEntry>,
+    data: Vec<u8>,
+}
+
+impl<R: Read + Seek> Pages<R> {
+    fn read(&mut self, index: u64, buf: &mut [u8]) -> io::Result<PageContents> {
+        let entry = *self.index.get(index as usize).ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("page {} is out of bounds", index),
+            )
+        })?;
+        if entry.len == 0 {
+            return Ok(PageContents::Zero);
+        }
+
+        self.inner.seek(SeekFrom::Start(entry.offset))?;
+        if entry.len as usize == self.page_size {
+            self.inner.read_exact(&mut buf[..self.page_size])?;
+        } else {
+            self.data.resize(entry.len as usize, 0);
+            self.inner.read_exact(&mut self.data)?;
+            self.compression
+                .decompress(&self.data, &mut buf[..self.page_size])?;
+        }
+        Ok(PageContents::Data)
+    }
+}
+
+impl<R: Read + Seek> PageStore<R> {
+    /// Read and validate the header and index of a store.
+    pub fn open(mut inner: R) -> io::Result<Self> {
+        let mut header = [0; HEADER_SIZE];
+        inner.seek(SeekFrom::Start(0))?;
+        inner.read_exact(&mut header)?;
+        format::check_preamble(&header, &MAGIC, VERSION, "page store")?;
+        let page_size = u32_at(&header, 12) as usize;
+        if !page_size.is_power_of_two() {
+            return Err(invalid_data(format!("invalid page size {}", page_size)));
+        }
+        let compression = Compression::from_id(header[16])?;
+
+        let footer_offset = inner
+            .seek(SeekFrom::End(0))?
+            .checked_sub(FOOTER_SIZE as u64)
+            .filter(|&offset| offset >= HEADER_SIZE as u64)
+            .ok_or_else(|| invalid_data("truncated page store".to_string()))?;
+        let mut footer = [0; FOOTER_SIZE];
+        inner.seek(SeekFrom::Start(footer_offset))?;
+        inner.read_exact(&mut footer)?;
+        let index_offset = u64_at(&footer, 0);
+        let count = u64_at(&footer, 8);
+        // The index sits between the pages and the footer.
+        let index_end = count
+            .checked_mul(INDEX_ENTRY_SIZE as u64)
+            .and_then(|len| len.checked_add(index_offset));
+        if index_offset < HEADER_SIZE as u64 || index_end != Some(footer_offset) {
+            return Err(invalid_data("index out of bounds".to_string()));
+        }
+
+        let mut index = Vec::new();
+        let mut entry = [0; INDEX_ENTRY_SIZE];
+        inner.seek(SeekFrom::Start(index_offset))?;
+        for _ in 0..count {
+            inner.read_exact(&mut entry)?;
+            let entry = IndexEntry {
+                offset: u64_at(&entry, 0),
+                len: u32_at(&entry, 8),
+            };
+            let end = entry.offset.checked_add(entry.len as u64);
+            if entry.len as usize > page_size
+                || entry.offset < HEADER_SIZE as u64
+                || end.filter(|&end| end <= index_offset).is_none()
+            {
+                return Err(invalid_data("index entry out of bounds".to_string()));
+            }
+            index.push(entry);
+        }
+
+        Ok(PageStore {
+            pages: Pages {
+                inner,
+                page_size,
+                compression,
+                index,
+                data: Vec::with_capacity(page_size),
+            },
+            buf: PageBuffer::new(page_size, page_size),
+            readahead: 1,
+        })
+    }
+
+    /// The size of the pages in this store.
+    pub fn page_size(&self) -> usize {
+        self.pages.page_size
+    }
+
+    /// The number of pages in this store.
+    pub fn page_count(&self) -> u64 {
+        self.pages.index.len() as u64
+    }
+
+    /// The algorithm the pages of this store are compressed with.
+    pub fn compression(&self) -> Compression {
+        self.pages.compression
+    }
+
+    /// Whether page `index` is all zeros.
+    pub fn is_zero(&self, index: u64) -> bool {
+        self.pages
+            .index
+            .get(index as usize)
+            .map_or(false, |entry| entry.len == 0)
+    }
+
+    /// Set how many pages [`resolve_fault`](PageStore::resolve_fault) installs per fault,
+    /// starting at the faulting page. The default is 1.
+    pub fn readahead(&mut self, pages: usize) -> &mut Self {
+        self.readahead = pages.max(1);
+        self
+    }
+
+    /// Resolve a fault at `addr` in a region that starts at `base` and holds the pages of this
+    /// store, and return the number of pages installed.
+    ///
+    /// The faulting page is installed and the faulting thread woken first. Then the following
+    /// pages of the readahead window are installed with `CopyMode::DONTWAKE`; pages that are
+    /// already present are skipped. A thread that faults on one of those pages concurrently is
+    /// woken when its own fault is resolved, which then finds the page present.
+    ///
+    /// If the region is longer than the store, faults beyond its last page are resolved with a
+    /// zero page.
+    ///
+    /// # Safety
+    ///
+    /// `base` must be the start of a range registered with `uffd`, and `addr` must lie within it.
+    pub unsafe fn resolve_fault<U: UffdBackend + ?Sized>(
+        &mut self,
+        uffd: &U,
+        base: *mut c_void,
+        addr: *mut c_void,
+    ) -> Result<usize> {
+        let page_size = self.pages.page_size;
+        let first = ((addr as usize - base as usize) / page_size) as u64;
+        if first >= self.page_count() {
+            let dst = (base as usize + first as usize * page_size) as *mut c_void;
+            return match uffd.zeropage(dst, page_size, true) {
+                Ok(_) => Ok(1),
+                Err(Error::ZeropageFailed(Errno::EEXIST)) => uffd.wake(dst, page_size).map(|()| 0),
+                Err(e) => Err(e),
+            };
+        }
+        let last = (first + self.readahead as u64).min(self.page_count());
+        let mut installed = 0;
+
+        for page in first..last {
+            let dst = (base as usize + page as usize * page_size) as *mut c_void;
+            let mode = if page == first {
+                CopyMode::empty()
+            } else {
+                CopyMode::DONTWAKE
+            };
+            let contents = self.pages.read(page, &mut self.buf)?;
+            match install_page(uffd, dst, page_size, contents, &self.buf, mode) {
+                Ok(_) => installed += 1,
+                Err(Error::CopyFailed(Errno::EEXIST))
+                | Err(Error::ZeropageFailed(Errno::EEXIST)) => {
+                    if page == first {
+                        uffd.wake(dst, page_size)?;
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(installed)
+    }
+}
+
+impl<R: Read + Seek> PageSource for PageStore<R> {
+    fn read_page(&mut self, index: u64, buf: &mut [u8]) -> io::Result<PageContents> {
+        self.pages.read(index, buf)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fake::{Call, FakeUffd, Op};
+    use crate::test_util::Registered;
+    use std::io::Cursor;
+    use std::ptr;
+
+    const PAGE_SIZE: usize = 4096;
+
+    fn snapshot() -> Vec<u8> {
+        let mut data = vec![0; PAGE_SIZE * 4];
+        // Page 0 compresses well, page 1 is zero, page 2 is random-ish, page 3 is mostly zero.
+        data[..PAGE_SIZE].fill(0x2a);
+        let mut x: u32 = 0x1234_5678;
+        for b in &mut data[2 * PAGE_SIZE..3 * PAGE_SIZE] {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            *b = x as u8;
+        }
+        data[3 * PAGE_SIZE + 100] = 7;
+        data
+    }
+
+    fn round_trip(compression: Compression) {
+        let data = snapshot();
+        let mut writer = PageStoreWriter::new(Vec::new(), PAGE_SIZE, compression).unwrap();
+        writer.write_pages(&data).unwrap();
+        let file = writer.finish().unwrap();
+
+        let mut store = PageStore::open(Cursor::new(file)).unwrap();
+        assert_eq!(store.page_count(), 4);
+        assert_eq!(store.compression(), compression);
+        assert!(store.is_zero(1));
+
+        let mut buf = vec![0xff; PAGE_SIZE];
+        for page in [3, 0, 2].iter().copied() {
+            assert_eq!(store.read_page(page, &mut buf).unwrap(), PageContents::Data);
+            let start = page as usize * PAGE_SIZE;
+            assert_eq!(&buf[..], &data[start..start + PAGE_SIZE]);
+        }
+        assert_eq!(store.read_page(1, &mut buf).unwrap(), PageContents::Zero);
+        assert!(store.read_page(4, &mut buf).is_err());
+    }
+
+    #[test]
+    fn test_round_trip_uncompressed() {
+        round_trip(Compression::None);
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn test_round_trip_lz4() {
+        round_trip(Compression::Lz4);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_round_trip_zstd() {
+        round_trip(Compression::Zstd);
+    }
+
+    #[test]
+    fn test_resolve_fault() -> Result<()> {
+        let data = snapshot();
+        let mut writer = PageStoreWriter::new(Vec::new(), PAGE_SIZE, Compression::None)?;
+        writer.write_pages(&data)?;
+        let mut store = PageStore::open(Cursor::new(writer.finish()?))?;
+        store.readahead(2);
+
+        let registered = Registered::new(data.len())?;
+        let mapping = registered.mapping;
+        let byte = registered.serve(
+            1,
+            |ptr| unsafe { ptr::read_volatile(ptr.add(PAGE_SIZE * 2 + 10)) },
+            |uffd, addr| {
+                assert_eq!(
+                    unsafe { store.resolve_fault(uffd, mapping, addr.as_ptr())? },
+                    2
+                );
+                Ok(())
+            },
+        )?;
+        assert_eq!(byte, data[PAGE_SIZE * 2 + 10]);
+
+        // Page 3 was read ahead, so this doesn't fault.
+        let page = unsafe {
+            std::slice::from_raw_parts((mapping as *const u8).add(PAGE_SIZE * 3), PAGE_SIZE)
+        };
+        assert_eq!(page, &data[PAGE_SIZE * 3..]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_fault_present() -> Result<()> {
+        const BASE: usize = 0x10_0000;
+
+        let data = snapshot();
+        let mut writer = PageStoreWriter::new(Vec::new(), PAGE_SIZE, Compression::None)?;
+        writer.write_pages(&data)?;
+        let mut store = PageStore::open(Cursor::new(writer.finish()?))?;
+        store.readahead(2);
+
+        // Another thread installed the faulting page first, so its thread is only woken.
+        let uffd = FakeUffd::new();
+        uffd.inject(Op::Copy, Err(Error::CopyFailed(Errno::EEXIST)));
+        let addr = (BASE + PAGE_SIZE * 2 + 10) as *mut c_void;
+        assert_eq!(
+            unsafe { store.resolve_fault(&uffd, BASE as *mut c_void, addr)? },
+            1
+        );
+        assert_eq!(
+            uffd.calls(),
+            vec![
+                Call::Copy {
+                    dst: BASE + PAGE_SIZE * 2,
+                    data: data[PAGE_SIZE * 2..PAGE_SIZE * 3].to_vec(),
+                    mode: CopyMode::empty(),
+                },
+                Call::Wake {
+                    start: BASE + PAGE_SIZE * 2,
+                    len: PAGE_SIZE,
+                },
+                Call::Copy {
+                    dst: BASE + PAGE_SIZE * 3,
+                    data: data[PAGE_SIZE * 3..].to_vec(),
+                    mode: CopyMode::DONTWAKE,
+                },
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_fault_past_end() -> Result<()> {
+        let data = snapshot();
+        let mut writer = PageStoreWriter::new(Vec::new(), PAGE_SIZE, Compression::None)?;
+        writer.write_pages(&data)?;
+        let mut store = PageStore::open(Cursor::new(writer.finish()?))?;
+
+        // The region has one more page than the store.
+        let registered = Registered::new(data.len() + PAGE_SIZE)?;
+        let mapping = registered.mapping;
+        let byte = registered.serve(
+            1,
+            |ptr| unsafe { ptr::read_volatile(ptr.add(PAGE_SIZE * 4 + 10)) },
+            |uffd, addr| {
+                assert_eq!(
+                    unsafe { store.resolve_fault(uffd, mapping, addr.as_ptr())? },
+                    1
+                );
+                Ok(())
+            },
+        )?;
+        assert_eq!(byte, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupt_store() {
+        let mut writer = PageStoreWriter::new(Vec::new(), PAGE_SIZE, Compression::None).unwrap();
+        writer.write_pages(&snapshot()).unwrap();
+        let file = writer.finish().unwrap();
+        let open = |file: &[u8]| {
+            PageStore::open(Cursor::new(file.to_vec()))
+                .err()
+                .unwrap()
+                .kind()
+        };
+
+        assert_eq!(open(&file[..HEADER_SIZE + 4]), ErrorKind::InvalidData);
+
+        // A page count that doesn't fit before the footer.
+        let mut bad = file.clone();
+        let count = bad.len() - 8;
+        bad[count..].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(open(&bad), ErrorKind::InvalidData);
+
+        // An index entry whose end overflows.
+        let mut bad = file.clone();
+        let index_offset = u64_at(&file, file.len() - FOOTER_SIZE) as usize;
+        bad[index_offset..index_offset + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(open(&bad), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,390 @@
+//! Pre-copy live migration driven by write-protect tracking.
+//!
+//! With pre-copy migration, the memory of a running workload is sent in rounds. The first round
+//! sends every page, and each later round sends the pages that were modified during the previous
+//! one, until few enough pages are dirty or a round limit is reached. [`PreCopy`] tracks the dirty
+//! pages of a range registered with `RegisterMode::WRITE_PROTECT`: pages are write-protected
+//! before they are sent, and the first write to a page raises a write-protect fault that marks it
+//! dirty again and lifts the protection.
+//!
+//! Once the rounds are over, the workload is paused, and [`PreCopy::handoff`] switches to
+//! [post-copy](crate::migration): the pages that are still dirty are invalidated on the
+//! destination, which resumes right away and requests them on demand.
+//!
+//! Pages that were never populated can't be write-protected, so the range must be populated
+//! before the migration starts, for example with `MAP_POPULATE`.
+//!
+//! This module requires the `linux5_7` feature.
+
+use crate::backend::UffdBackend;
+use crate::error::Result;
+use crate::migration::{
+    page_index, poll_readable, write_header, MigrationSource, HANDOFF, INVALIDATE, PAGE, ZERO,
+};
+use crate::page_source::{PageContents, PageSource};
+use crate::{Event, EventBuffer, FaultKind};
+use libc::c_void;
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+
+/// Reads the pages of a migrated range from memory.
+///
+/// This is the page source of the post-copy phase returned by [`PreCopy::handoff`].
+pub struct RegionSource {
+    base: *const u8,
+    page_size: usize,
+}
+
+impl PageSource for RegionSource {
+    fn read_page(&mut self, index: u64, buf: &mut [u8]) -> io::Result<PageContents> {
+        let page = unsafe { self.base.add(index as usize * self.page_size) };
+        unsafe { ptr::copy_nonoverlapping(page, buf.as_mut_ptr(), self.page_size) };
+        if buf.iter().all(|&byte| byte == 0) {
+            Ok(PageContents::Zero)
+        } else {
+            Ok(PageContents::Data)
+        }
+    }
+}
+
+/// Sends the pages of a running workload in rounds until the dirty set converges.
+///
+/// The `Uffd` object must be non-blocking, since write-protect faults are served between the
+/// pages that are sent.
+pub struct PreCopy<'a, U: UffdBackend + ?Sized, T> {
+    uffd: &'a U,
+    base: *mut c_void,
+    page_size: usize,
+    stream: T,
+    region: RegionSource,
+    dirty: Vec<bool>,
+    buf: Vec<u8>,
+    events: EventBuffer,
+    rounds: usize,
+    max_rounds: usize,
+    dirty_threshold: usize,
+}
+
+impl<'a, U: UffdBackend + AsRawFd + ?Sized, T: Read + Write + AsRawFd> PreCopy<'a, U, T> {
+    /// Start a migration of the range of `len` bytes at `base`, which must be populated and
+    /// registered with `uffd` with `RegisterMode::WRITE_PROTECT`, and send the stream header to a
+    /// [`MigrationDestination`](crate::migration::MigrationDestination).
+    ///
+    /// `len` must be a multiple of `page_size`.
+    pub fn new(
+        uffd: &'a U,
+        base: *mut c_void,
+        len: usize,
+        page_size: usize,
+        mut stream: T,
+    ) -> io::Result<Self> {
+        if !page_size.is_power_of_two() || len & (page_size - 1) != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "length is not a multiple of the page size",
+            ));
+        }
+        let page_count = len / page_size;
+        write_header(&mut stream, page_size, page_count as u64)?;
+        Ok(PreCopy {
+            uffd,
+            base,
+            page_size,
+            stream,
+            region: RegionSource {
+                base: base as *const u8,
+                page_size,
+            },
+            dirty: vec![true; page_count],
+            buf: vec![0; 9 + page_size],
+            events: EventBuffer::new(16),
+            rounds: 0,
+            max_rounds: 8,
+            dirty_threshold: 0,
+        })
+    }
+
+    /// Set the maximum number of rounds run by [`run`](PreCopy::run). The default is 8.
+    pub fn max_rounds(&mut self, rounds: usize) -> &mut Self {
+        self.max_rounds = rounds;
+        self
+    }
+
+    /// Set the number of dirty pages at or below which the migration is considered converged.
+    /// The default is 0.
+    pub fn dirty_threshold(&mut self, pages: usize) -> &mut Self {
+        self.dirty_threshold = pages;
+        self
+    }
+
+    /// The number of rounds sent so far.
+    pub fn rounds(&self) -> usize {
+        self.rounds
+    }
+
+    /// The number of pages that were modified since they were last sent.
+    pub fn dirty_pages(&self) -> usize {
+        self.dirty.iter().filter(|&&dirty| dirty).count()
+    }
+
+    fn page_addr(&self, index: usize) -> *mut c_void {
+        (self.base as usize + index * self.page_size) as *mut c_void
+    }
+
+    /// Send rounds until the dirty set converges or the round limit is reached, and return whether
+    /// it converged.
+    pub fn run(&mut self) -> Result<bool> {
+        loop {
+            self.round()?;
+            if self.dirty_pages() <= self.dirty_threshold {
+                return Ok(true);
+            }
+            if self.rounds >= self.max_rounds {
+                return Ok(false);
+            }
+        }
+    }
+
+    /// Write-protect and send every dirty page, and return the number of pages sent.
+    pub fn round(&mut self) -> Result<usize> {
+        let pages = (0..self.dirty.len())
+            .filter(|&index| self.dirty[index])
+            .collect::<Vec<_>>();
+
+        // Protect runs of pages with a single call. The pages must be protected before they are
+        // read, so that a concurrent write marks them dirty again.
+        let mut start = 0;
+        while start < pages.len() {
+            let mut end = start + 1;
+            while end < pages.len() && pages[end] == pages[end - 1] + 1 {
+                end += 1;
+            }
+            self.uffd
+                .write_protect(self.page_addr(pages[start]), (end - start) * self.page_size)?;
+            for &index in &pages[start..end] {
+                self.dirty[index] = false;
+            }
+            start = end;
+        }
+
+        for &index in &pages {
+            self.serve_faults()?;
+            self.send_page(index as u64)?;
+        }
+        self.serve_faults()?;
+        self.rounds += 1;
+        Ok(pages.len())
+    }
+
+    /// Mark the pages of pending write-protect faults dirty, and let the writers continue.
+    fn serve_faults(&mut self) -> Result<()> {
+        while poll_readable([self.uffd.as_raw_fd()], 0)?[0] {
+            for index in self.read_faults()? {
+                self.dirty[index] = true;
+                self.uffd
+                    .remove_write_protection(self.page_addr(index), self.page_size, true)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_faults(&mut self) -> Result<Vec<usize>> {
+        self.uffd
+            .read_events(&mut self.events)?
+            .into_iter()
+            .filter_map(|event| match event {
+                Event::Pagefault {
+                    kind: FaultKind::WriteProtected,
+                    addr,
+                    ..
+                } => Some(page_index(
+                    addr,
+                    self.base,
+                    self.page_size,
+                    self.dirty.len(),
+                )),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn send_page(&mut self, page: u64) -> Result<()> {
+        let contents = self.region.read_page(page, &mut self.buf[9..])?;
+        self.buf[1..9].copy_from_slice(&page.to_le_bytes());
+        match contents {
+            PageContents::Data => {
+                self.buf[0] = PAGE;
+                self.stream.write_all(&self.buf)?;
+            }
+            PageContents::Zero => {
+                self.buf[0] = ZERO;
+                self.stream.write_all(&self.buf[..9])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Hand the migration off to post-copy, and return the source that serves the remaining
+    /// pages.
+    ///
+    /// The workload must be paused before calling this. Pages that are still dirty are
+    /// invalidated on the destination, to be requested on demand once it resumes. Threads that
+    /// are blocked on a write-protect fault are not woken, since their writes would race with the
+    /// transfer of the page.
+    pub fn handoff(mut self) -> Result<MigrationSource<T, RegionSource>> {
+        while poll_readable([self.uffd.as_raw_fd()], 0)?[0] {
+            for index in self.read_faults()? {
+                self.dirty[index] = true;
+            }
+        }
+
+        let mut message = [INVALIDATE; 9];
+        for (index, _) in self.dirty.iter().enumerate().filter(|(_, &dirty)| dirty) {
+            message[1..9].copy_from_slice(&(index as u64).to_le_bytes());
+            self.stream.write_all(&message)?;
+        }
+        self.stream.write_all(&[HANDOFF])?;
+
+        let sent = self.dirty.iter().map(|&dirty| !dirty).collect();
+        Ok(MigrationSource::resume(
+            self.stream,
+            self.region,
+            self.page_size,
+            sent,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::migration::MigrationDestination;
+    use crate::{FeatureFlags, RegisterMode, Uffd, UffdBuilder};
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    const PAGE_SIZE: usize = 4096;
+    const PAGES: usize = 16;
+
+    fn write_pages(uffd: &Uffd, precopy: &mut PreCopy<Uffd, UnixStream>, writes: Vec<(usize, u8)>) {
+        let ptr = precopy.base as usize;
+        let writer = thread::spawn(move || {
+            for (index, value) in writes {
+                unsafe { ptr::write_volatile((ptr as *mut u8).add(index * PAGE_SIZE), value) };
+            }
+        });
+        while !writer.is_finished() {
+            if poll_readable([uffd.as_raw_fd()], 10).unwrap()[0] {
+                precopy.serve_faults().unwrap();
+            }
+        }
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn test_precopy_handoff() -> Result<()> {
+        let (source_end, destination_end) = UnixStream::pair()?;
+
+        let destination = thread::spawn(move || -> Result<Vec<u8>> {
+            let uffd = UffdBuilder::new()
+                .close_on_exec(true)
+                .non_blocking(true)
+                .create()?;
+            let mapping = unsafe {
+                libc::mmap(
+                    ptr::null_mut(),
+                    PAGE_SIZE * PAGES,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_PRIVATE | libc::MAP_ANON,
+                    -1,
+                    0,
+                )
+            };
+            assert!(!mapping.is_null());
+            uffd.register(mapping, PAGE_SIZE * PAGES)?;
+
+            let mut destination =
+                MigrationDestination::new(&uffd, mapping, PAGE_SIZE * PAGES, destination_end)?;
+            destination.precopy()?;
+
+            // The workload resumes on the destination after the hand-off.
+            let ptr = mapping as usize;
+            let workload = thread::spawn(move || {
+                (0..PAGES)
+                    .map(|i| unsafe { ptr::read_volatile((ptr as *const u8).add(i * PAGE_SIZE)) })
+                    .collect()
+            });
+            destination.run()?;
+            let values = workload.join().unwrap();
+
+            uffd.unregister(mapping, PAGE_SIZE * PAGES)?;
+            assert_eq!(unsafe { libc::munmap(mapping, PAGE_SIZE * PAGES) }, 0);
+            Ok(values)
+        });
+
+        unsafe {
+            let uffd = UffdBuilder::new()
+                .require_features(FeatureFlags::PAGEFAULT_FLAG_WP)
+                .close_on_exec(true)
+                .non_blocking(true)
+                .create()?;
+
+            let mapping = libc::mmap(
+                ptr::null_mut(),
+                PAGE_SIZE * PAGES,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANON | libc::MAP_POPULATE,
+                -1,
+                0,
+            );
+
+            assert!(!mapping.is_null());
+
+            for i in 0..PAGES {
+                *(mapping as *mut u8).add(i * PAGE_SIZE) = i as u8 + 1;
+            }
+
+            uffd.register_with_mode(mapping, PAGE_SIZE * PAGES, RegisterMode::WRITE_PROTECT)?;
+
+            let mut precopy =
+                PreCopy::new(&uffd, mapping, PAGE_SIZE * PAGES, PAGE_SIZE, source_end)?;
+            assert_eq!(precopy.round()?, PAGES);
+            assert_eq!(precopy.dirty_pages(), 0);
+
+            write_pages(&uffd, &mut precopy, vec![(3, 42), (5, 43)]);
+            assert_eq!(precopy.dirty_pages(), 2);
+            assert!(precopy.run()?);
+            assert_eq!(precopy.rounds(), 2);
+
+            // Page 7 is still dirty when the workload is paused.
+            write_pages(&uffd, &mut precopy, vec![(7, 44)]);
+            let mut postcopy = precopy.handoff()?;
+            postcopy.run()?;
+            assert_eq!(postcopy.sent_pages(), PAGES);
+
+            let mut expected = (1..=PAGES as u8).collect::<Vec<_>>();
+            expected[3] = 42;
+            expected[5] = 43;
+            expected[7] = 44;
+            assert_eq!(destination.join().unwrap()?, expected);
+
+            uffd.unregister(mapping, PAGE_SIZE * PAGES)?;
+
+            assert_eq!(libc::munmap(mapping, PAGE_SIZE * PAGES), 0);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_partial_page() -> Result<()> {
+        let uffd = UffdBuilder::new().close_on_exec(true).create()?;
+        let (stream, _) = UnixStream::pair()?;
+        let err = PreCopy::new(&uffd, ptr::null_mut(), PAGE_SIZE + 1, PAGE_SIZE, stream)
+            .err()
+            .expect("partial page accepted");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        Ok(())
+    }
+}
@@ -0,0 +1,533 @@
+//! Prefetching a recorded working set on restore.
+//!
+//! Restoring a snapshot lazily means every page the workload touches costs a round trip through
+//! the fault handler. When a [fault trace](crate::fault_trace) of an earlier run is available,
+//! [`Prefetcher`] installs the pages of the recorded [`WorkingSet`] eagerly, in the order they
+//! were first touched, while still serving demand faults first.
+//!
+//! Prefetched pages are installed in batches with `CopyMode::DONTWAKE`, since no thread is waiting
+//! for them. Pages of a batch that are adjacent both in the working set and in memory are installed
+//! with a single `UFFDIO_COPY` or `UFFDIO_ZEROPAGE`. A demand fault on a page that is being
+//! prefetched at the same time is detected by `EEXIST`, and the faulting thread is then woken
+//! explicitly.
+
+use crate::backend::UffdBackend;
+use crate::error::{Error, Result};
+use crate::fault_trace::TraceReader;
+use crate::page_source::{install_page, PageBuffer, PageContents, PageSource};
+use crate::{CopyMode, Event, EventBuffer};
+use libc::c_void;
+use nix::errno::Errno;
+use std::io::{self, Read};
+
+/// The pages of a region in the order they were first touched.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct WorkingSet {
+    pages: Vec<u64>,
+}
+
+impl WorkingSet {
+    /// Create a working set from page indices, keeping only the first occurrence of each page.
+    pub fn new(pages: impl IntoIterator<Item = u64>) -> Self {
+        let mut seen = std::collections::HashSet::new();
+        WorkingSet {
+            pages: pages
+                .into_iter()
+                .filter(|&page| seen.insert(page))
+                .collect(),
+        }
+    }
+
+    /// Build the working set of `region` from a fault trace, with pages of `page_size` bytes.
+    pub fn from_trace<R: Read>(
+        trace: TraceReader<R>,
+        region: u32,
+        page_size: usize,
+    ) -> io::Result<Self> {
+        let mut pages = Vec::new();
+        for record in trace {
+            let record = record?;
+            if record.region == region {
+                pages.push(record.offset / page_size as u64);
+            }
+        }
+        Ok(WorkingSet::new(pages))
+    }
+
+    /// The page indices, in first-touch order.
+    pub fn pages(&self) -> &[u64] {
+        &self.pages
+    }
+
+    /// The number of pages in the working set.
+    pub fn len(&self) -> usize {
+        self.pages.len()
+    }
+
+    /// Whether the working set is empty.
+    pub fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+}
+
+/// Installs a working set into a registered region while serving demand faults.
+///
+/// The `Uffd` object should be non-blocking, so that pending demand faults can be checked for
+/// between prefetch batches without stalling.
+pub struct Prefetcher<'a, U: UffdBackend + ?Sized, S: PageSource> {
+    uffd: &'a U,
+    base: *mut c_void,
+    page_size: usize,
+    source: S,
+    working_set: WorkingSet,
+    next: usize,
+    batch: usize,
+    installed: Vec<bool>,
+    buf: PageBuffer,
+    batch_buf: PageBuffer,
+    events: EventBuffer,
+    prefetched: usize,
+    demand_faults: usize,
+}
+
+impl<'a, U: UffdBackend + ?Sized, S: PageSource> Prefetcher<'a, U, S> {
+    /// Create a prefetcher for the region of `len` bytes at `base`, which must be registered with
+    /// `uffd` for missing faults.
+    pub fn new(
+        uffd: &'a U,
+        base: *mut c_void,
+        len: usize,
+        page_size: usize,
+        source: S,
+        working_set: WorkingSet,
+    ) -> Self {
+        Prefetcher {
+            uffd,
+            base,
+            page_size,
+            source,
+            working_set,
+            next: 0,
+            batch: 16,
+            installed: vec![false; len / page_size],
+            buf: PageBuffer::new(page_size, page_size),
+            batch_buf: PageBuffer::new(16 * page_size, page_size),
+            events: EventBuffer::new(16),
+            prefetched: 0,
+            demand_faults: 0,
+        }
+    }
+
+    /// Set how many pages are prefetched between checks for demand faults. The default is 16.
+    pub fn batch_size(&mut self, batch: usize) -> &mut Self {
+        self.batch = batch.max(1);
+        self.batch_buf = PageBuffer::new(self.batch * self.page_size, self.page_size);
+        self
+    }
+
+    /// The number of pages installed ahead of a fault so far.
+    pub fn prefetched(&self) -> usize {
+        self.prefetched
+    }
+
+    /// The number of demand faults served so far.
+    pub fn demand_faults(&self) -> usize {
+        self.demand_faults
+    }
+
+    /// Whether every page of the working set has been considered for prefetching.
+    pub fn is_done(&self) -> bool {
+        self.next >= self.working_set.len()
+    }
+
+    /// Prefetch the whole working set, serving any pending demand faults before each batch.
+    pub fn prefetch(&mut self) -> Result<()> {
+        loop {
+            self.serve_pending()?;
+            if self.prefetch_batch()? == 0 && self.is_done() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Install up to one batch of pages from the working set, and return how many were installed.
+    pub fn prefetch_batch(&mut self) -> Result<usize> {
+        let mut installed = 0;
+        while installed < self.batch && !self.is_done() {
+            // Take the longest run of absent pages that are adjacent in both the working set and
+            // the region.
+            let pages = &self.working_set.pages[self.next..];
+            let first = pages[0];
+            let count = pages
+                .iter()
+                .take(self.batch - installed)
+                .enumerate()
+                .take_while(|&(i, &page)| {
+                    page == first + i as u64 && self.installed.get(page as usize) == Some(&false)
+                })
+                .count();
+            self.next += count.max(1);
+            if count > 0 {
+                let run = self.install_run(first, count)?;
+                self.prefetched += run;
+                installed += run;
+            }
+        }
+        Ok(installed)
+    }
+
+    /// Serve all demand faults that are currently pending, and return how many were served.
+    pub fn serve_pending(&mut self) -> Result<usize> {
+        let mut faults = Vec::new();
+        let mut removed = Vec::new();
+        for event in self.uffd.read_events(&mut self.events)? {
+            match event {
+                Event::Pagefault { addr, .. } => faults.push(addr.as_usize()),
+                Event::Remove { start, end } | Event::Unmap { start, end } => {
+                    removed.push((start.as_usize(), end.as_usize()))
+                }
+                _ => {}
+            }
+        }
+
+        for (start, end) in removed {
+            self.forget(start, end);
+        }
+        for &addr in &faults {
+            self.serve_fault(addr as *mut c_void)?;
+        }
+        Ok(faults.len())
+    }
+
+    /// Serve a demand fault at `addr`, installing its page if it is not present yet and waking the
+    /// faulting thread.
+    ///
+    /// Returns `EFAULT` if `addr` is outside of the region of this prefetcher.
+    pub fn serve_fault(&mut self, addr: *mut c_void) -> Result<()> {
+        let page = (addr as usize)
+            .checked_sub(self.base as usize)
+            .map(|offset| offset / self.page_size)
+            .filter(|&page| page < self.installed.len())
+            .ok_or(Errno::EFAULT)?;
+        self.demand_faults += 1;
+        if !self.install(page as u64, CopyMode::empty())? {
+            // Installed by a prefetch batch in the meantime, which did not wake anyone.
+            self.uffd
+                .wake(self.page_addr(page as u64), self.page_size)?;
+        }
+        Ok(())
+    }
+
+    fn page_addr(&self, page: u64) -> *mut c_void {
+        (self.base as usize + page as usize * self.page_size) as *mut c_void
+    }
+
+    fn forget(&mut self, start: usize, end: usize) {
+        let base = self.base as usize;
+        let first = start.saturating_sub(base) / self.page_size;
+        let last = (end.saturating_sub(base) / self.page_size).min(self.installed.len());
+        for installed in self.installed.iter_mut().take(last).skip(first) {
+            *installed = false;
+        }
+    }
+
+    // Installs `count` pages starting at `first` with a single copy or zeropage, and returns how
+    // many of them were installed.
+    fn install_run(&mut self, first: u64, count: usize) -> Result<usize> {
+        let page_size = self.page_size;
+        let mut contents = PageContents::Zero;
+        for (i, page) in self
+            .batch_buf
+            .chunks_exact_mut(page_size)
+            .take(count)
+            .enumerate()
+        {
+            match self.source.read_page(first + i as u64, page)? {
+                PageContents::Data => contents = PageContents::Data,
+                PageContents::Zero => page.fill(0),
+            }
+        }
+
+        let dst = self.page_addr(first);
+        let len = count * page_size;
+        let result = unsafe {
+            install_page(
+                self.uffd,
+                dst,
+                len,
+                contents,
+                &self.batch_buf,
+                CopyMode::DONTWAKE,
+            )
+        };
+        let done = match result {
+            Ok(_) => count,
+            // Some page of the run was present already, or the memory layout changed; the pages
+            // before it were installed.
+            Err(Error::PartiallyCopied(bytes)) => (bytes / page_size).min(count),
+            Err(Error::CopyFailed(Errno::EEXIST))
+            | Err(Error::ZeropageFailed(Errno::EEXIST))
+            | Err(Error::ZeropageFailed(Errno::EAGAIN)) => 0,
+            Err(e) => return Err(e),
+        };
+        for installed in &mut self.installed[first as usize..first as usize + done] {
+            *installed = true;
+        }
+
+        // Install the rest of the run one page at a time, skipping those that are present.
+        let mut installed = done;
+        for page in first + done as u64..first + count as u64 {
+            if self.install(page, CopyMode::DONTWAKE)? {
+                installed += 1;
+            }
+        }
+        Ok(installed)
+    }
+
+    // Returns `false` if the page was already present, or couldn't be installed because the memory
+    // layout changed. In the latter case, a fault on the page is retried when it is woken.
+    fn install(&mut self, page: u64, mode: CopyMode) -> Result<bool> {
+        match self.installed.get(page as usize) {
+            None | Some(true) => return Ok(false),
+            Some(false) => {}
+        }
+
+        let contents = self.source.read_page(page, &mut self.buf)?;
+        let dst = self.page_addr(page);
+        let result =
+            unsafe { install_page(self.uffd, dst, self.page_size, contents, &self.buf, mode) };
+        self.installed[page as usize] = true;
+        match result {
+            Ok(_) => Ok(true),
+            Err(Error::CopyFailed(Errno::EEXIST)) | Err(Error::ZeropageFailed(Errno::EEXIST)) => {
+                Ok(false)
+            }
+            Err(Error::PartiallyCopied(_)) | Err(Error::ZeropageFailed(Errno::EAGAIN)) => {
+                self.installed[page as usize] = false;
+                Ok(false)
+            }
+            Err(e) => {
+                self.installed[page as usize] = false;
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fake::{Call, FakeUffd};
+    use crate::fault_injection::{FaultInjector, ResolveFault};
+    use crate::fault_trace::{TraceRecord, TraceRegion, TraceWriter};
+    use crate::{FaultKind, ReadWrite, UffdBuilder, ZeropageMode};
+    use std::ptr;
+    use std::thread;
+    use std::time::Duration;
+
+    const PAGE_SIZE: usize = 4096;
+
+    #[test]
+    fn test_working_set_from_trace() {
+        let region = TraceRegion {
+            start: 0,
+            len: 16 * PAGE_SIZE as u64,
+        };
+        let mut writer = TraceWriter::new(Vec::new(), &[region, region]).unwrap();
+        for &(region, offset) in &[(0, 5), (1, 3), (0, 2), (0, 5), (0, 7)] {
+            writer
+                .write_record(&TraceRecord {
+                    timestamp: Duration::from_nanos(0),
+                    region,
+                    offset: offset * PAGE_SIZE as u64 + 12,
+                    kind: FaultKind::Missing,
+                    rw: ReadWrite::Read,
+                    thread_id: 0,
+                })
+                .unwrap();
+        }
+        let trace = writer.finish().unwrap();
+
+        let working_set =
+            WorkingSet::from_trace(TraceReader::new(&trace[..]).unwrap(), 0, PAGE_SIZE).unwrap();
+        assert_eq!(working_set.pages(), &[5, 2, 7]);
+    }
+
+    #[test]
+    fn test_adjacent_pages_are_batched() {
+        const BASE: usize = 0x10_0000;
+
+        let uffd = FakeUffd::new();
+        let source = |index: u64, buf: &mut [u8]| {
+            if index == 4 {
+                return Ok(PageContents::Zero);
+            }
+            buf.fill(index as u8);
+            Ok(PageContents::Data)
+        };
+        let mut prefetcher = Prefetcher::new(
+            &uffd,
+            BASE as *mut c_void,
+            PAGE_SIZE * 16,
+            PAGE_SIZE,
+            source,
+            WorkingSet::new(vec![3, 4, 5, 9, 1, 2]),
+        );
+        assert_eq!(prefetcher.prefetch_batch().unwrap(), 6);
+
+        let copies = uffd
+            .calls()
+            .into_iter()
+            .map(|call| match call {
+                Call::Copy { dst, data, mode } => {
+                    assert_eq!(mode, CopyMode::DONTWAKE);
+                    ((dst - BASE) / PAGE_SIZE, data)
+                }
+                call => panic!("unexpected call: {:?}", call),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(copies.len(), 3);
+        assert_eq!(copies[0].0, 3);
+        assert_eq!(copies[0].1.len(), 3 * PAGE_SIZE);
+        assert!(copies[0].1[PAGE_SIZE..2 * PAGE_SIZE]
+            .iter()
+            .all(|&b| b == 0));
+        assert!(copies[0].1[2 * PAGE_SIZE..].iter().all(|&b| b == 5));
+        assert_eq!((copies[1].0, copies[1].1.len()), (9, PAGE_SIZE));
+        assert_eq!((copies[2].0, copies[2].1.len()), (1, 2 * PAGE_SIZE));
+
+        // Faults outside of the region are rejected rather than resolved.
+        for &addr in &[BASE - 1, BASE + 16 * PAGE_SIZE] {
+            assert!(matches!(
+                prefetcher.serve_fault(addr as *mut c_void),
+                Err(Error::SystemError(Errno::EFAULT))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_again_leaves_pages_to_demand_faults() {
+        const BASE: usize = 0x10_0000;
+
+        let uffd = FaultInjector::new(FakeUffd::new(), PAGE_SIZE)
+            .inject_copy(ResolveFault::Again, 1.0)
+            .inject_zeropage(ResolveFault::Again, 1.0);
+        let source = |index: u64, buf: &mut [u8]| {
+            if index >= 8 {
+                return Ok(PageContents::Zero);
+            }
+            buf.fill(index as u8);
+            Ok(PageContents::Data)
+        };
+        let mut prefetcher = Prefetcher::new(
+            &uffd,
+            BASE as *mut c_void,
+            PAGE_SIZE * 16,
+            PAGE_SIZE,
+            source,
+            WorkingSet::new(vec![3, 4, 5, 8, 9]),
+        );
+
+        // Only the first half of the copy of pages 3 to 5 succeeds, and single pages and zero
+        // pages all fail with `EAGAIN`.
+        assert_eq!(prefetcher.prefetch_batch().unwrap(), 1);
+        assert!(prefetcher.is_done());
+
+        // A demand fault on a page that wasn't installed is woken to be retried.
+        prefetcher
+            .serve_fault((BASE + 4 * PAGE_SIZE) as *mut c_void)
+            .unwrap();
+        assert_eq!(
+            uffd.inner().calls(),
+            vec![
+                Call::Copy {
+                    dst: BASE + 3 * PAGE_SIZE,
+                    data: vec![3; PAGE_SIZE],
+                    mode: CopyMode::DONTWAKE,
+                },
+                Call::Zeropage {
+                    start: BASE + 8 * PAGE_SIZE,
+                    len: PAGE_SIZE,
+                    mode: ZeropageMode::DONTWAKE,
+                },
+                Call::Wake {
+                    start: BASE + 4 * PAGE_SIZE,
+                    len: PAGE_SIZE,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_prefetch() -> Result<()> {
+        const PAGES: usize = 8;
+
+        unsafe {
+            let uffd = UffdBuilder::new()
+                .close_on_exec(true)
+                .non_blocking(true)
+                .create()?;
+
+            let mapping = libc::mmap(
+                ptr::null_mut(),
+                PAGE_SIZE * PAGES,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANON,
+                -1,
+                0,
+            );
+
+            assert!(!mapping.is_null());
+
+            uffd.register(mapping, PAGE_SIZE * PAGES)?;
+
+            let source = |index: u64, buf: &mut [u8]| {
+                if index == 2 {
+                    return Ok(PageContents::Zero);
+                }
+                buf.fill(index as u8 + 1);
+                Ok(PageContents::Data)
+            };
+            let mut prefetcher = Prefetcher::new(
+                &uffd,
+                mapping,
+                PAGE_SIZE * PAGES,
+                PAGE_SIZE,
+                source,
+                WorkingSet::new(vec![5, 2, 7]),
+            );
+            prefetcher.batch_size(2);
+
+            // Page 0 is not in the working set, so it can only be served on demand.
+            let ptr = mapping as usize;
+            let thread = thread::spawn(move || {
+                let ptr = ptr as *const u8;
+                ptr::read_volatile(ptr)
+            });
+
+            prefetcher.prefetch()?;
+            assert!(prefetcher.is_done());
+            assert_eq!(prefetcher.prefetched(), 3);
+
+            while !thread.is_finished() {
+                prefetcher.serve_pending()?;
+                thread::sleep(Duration::from_millis(10));
+            }
+            assert_eq!(thread.join().expect("failed to join thread"), 1);
+            assert_eq!(prefetcher.demand_faults(), 1);
+
+            // The prefetched pages are present, so reading them doesn't fault.
+            let ptr = mapping as *const u8;
+            assert_eq!(*ptr.add(5 * PAGE_SIZE), 6);
+            assert_eq!(*ptr.add(2 * PAGE_SIZE), 0);
+            assert_eq!(*ptr.add(7 * PAGE_SIZE + 100), 8);
+            assert!(uffd.read_event()?.is_none());
+
+            uffd.unregister(mapping, PAGE_SIZE * PAGES)?;
+
+            assert_eq!(libc::munmap(mapping, PAGE_SIZE * PAGES), 0);
+        }
+
+        Ok(())
+    }
+}
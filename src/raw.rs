@@ -1,9 +1,9 @@
-use libc::{c_int, c_long, syscall, SYS_userfaultfd, INT_MAX};
+use libc::{c_int, c_long, syscall, SYS_userfaultfd};
 pub use userfaultfd_sys::*;
 
 pub unsafe fn userfaultfd(flags: c_int) -> c_int {
     let fd = syscall(SYS_userfaultfd, flags as c_long);
-    if fd > INT_MAX as c_long {
+    if fd > c_int::MAX as c_long {
         panic!("fd doesn't fit in a c_int");
     } else {
         fd as c_int
@@ -25,6 +25,8 @@ nix::ioctl_readwrite!(
 );
 #[cfg(feature = "linux5_13")]
 nix::ioctl_readwrite!(r#continue, UFFDIO, _UFFDIO_CONTINUE, uffdio_continue);
+#[cfg(feature = "linux6_6")]
+nix::ioctl_readwrite!(poison, UFFDIO, _UFFDIO_POISON, uffdio_poison);
 
 // ioctls for /dev/userfaultfd
 
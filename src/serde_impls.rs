@@ -0,0 +1,161 @@
+//! `Serialize` and `Deserialize` for flag types, and helpers for the derived implementations.
+//!
+//! Flags serialize as a list of flag names. Bits without a name, such as those of a newer kernel,
+//! follow as a single hexadecimal string like `"0x8000"`, so that they survive a round trip.
+
+use crate::{FeatureFlags, IoctlFlags, PagefaultFlags, RegisterMode};
+use bitflags::Flags;
+use serde::de::{self, Deserializer, SeqAccess, Visitor};
+use serde::ser::{SerializeSeq, Serializer};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::marker::PhantomData;
+
+fn serialize_flags<F, S>(flags: &F, serializer: S) -> Result<S::Ok, S::Error>
+where
+    F: Flags<Bits = u64>,
+    S: Serializer,
+{
+    let mut unnamed = flags.bits();
+    for (_, flag) in flags.iter_names() {
+        unnamed &= !flag.bits();
+    }
+    // Formats such as bincode need the length up front.
+    let len = flags.iter_names().count() + (unnamed != 0) as usize;
+    let mut seq = serializer.serialize_seq(Some(len))?;
+    for (name, _) in flags.iter_names() {
+        seq.serialize_element(name)?;
+    }
+    if unnamed != 0 {
+        seq.serialize_element(&format!("{:#x}", unnamed))?;
+    }
+    seq.end()
+}
+
+struct FlagsVisitor<F>(PhantomData<F>);
+
+impl<'de, F: Flags<Bits = u64>> Visitor<'de> for FlagsVisitor<F> {
+    type Value = F;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a list of flag names and hexadecimal bits")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<F, A::Error> {
+        let mut bits = 0;
+        while let Some(name) = seq.next_element::<String>()? {
+            bits |= match name.strip_prefix("0x") {
+                Some(hex) => u64::from_str_radix(hex, 16)
+                    .map_err(|_| de::Error::invalid_value(de::Unexpected::Str(&name), &self))?,
+                None => F::from_name(&name)
+                    .ok_or_else(|| de::Error::custom(format!("unknown flag {}", name)))?
+                    .bits(),
+            };
+        }
+        Ok(F::from_bits_retain(bits))
+    }
+}
+
+macro_rules! impl_flags_serde {
+    ($($flags:ty),*) => {
+        $(
+            impl Serialize for $flags {
+                fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                    serialize_flags(self, serializer)
+                }
+            }
+
+            impl<'de> Deserialize<'de> for $flags {
+                fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                    deserializer.deserialize_seq(FlagsVisitor(PhantomData))
+                }
+            }
+        )*
+    };
+}
+
+impl_flags_serde!(FeatureFlags, IoctlFlags, PagefaultFlags, RegisterMode);
+
+/// Serializes a thread ID as its raw value.
+#[cfg(feature = "linux4_14")]
+pub(crate) mod pid {
+    use nix::unistd::Pid;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub(crate) fn serialize<S: Serializer>(pid: &Pid, serializer: S) -> Result<S::Ok, S::Error> {
+        pid.as_raw().serialize(serializer)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Pid, D::Error> {
+        i32::deserialize(deserializer).map(Pid::from_raw)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Event, FaultKind, ReadWrite, Uffd, UffdAddr};
+    use std::fs::File;
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+
+    #[test]
+    fn test_flags() {
+        let ioctls = IoctlFlags::COPY | IoctlFlags::WAKE | IoctlFlags::from_bits_retain(1 << 40);
+        let json = serde_json::to_string(&ioctls).unwrap();
+        assert_eq!(json, r#"["WAKE","COPY","0x10000000000"]"#);
+        assert_eq!(serde_json::from_str::<IoctlFlags>(&json).unwrap(), ioctls);
+
+        let mode = RegisterMode::MISSING;
+        let json = serde_json::to_string(&mode).unwrap();
+        assert_eq!(json, r#"["MISSING"]"#);
+        assert_eq!(serde_json::from_str::<RegisterMode>(&json).unwrap(), mode);
+
+        let features = FeatureFlags::EVENT_REMOVE | FeatureFlags::from_bits_retain(1 << 20);
+        let json = serde_json::to_string(&features).unwrap();
+        assert_eq!(
+            serde_json::from_str::<FeatureFlags>(&json).unwrap(),
+            features
+        );
+
+        assert!(serde_json::from_str::<IoctlFlags>(r#"["BOGUS"]"#).is_err());
+        assert!(serde_json::from_str::<IoctlFlags>(r#"["0xZZ"]"#).is_err());
+    }
+
+    #[test]
+    fn test_events() {
+        let events = [
+            Event::Pagefault {
+                kind: FaultKind::Missing,
+                rw: ReadWrite::Write,
+                flags: PagefaultFlags::WRITE | PagefaultFlags::from_bits_retain(1 << 8),
+                addr: UffdAddr::new(0x10_0000),
+                #[cfg(feature = "linux4_14")]
+                thread_id: nix::unistd::Pid::from_raw(42),
+            },
+            Event::Remove {
+                start: UffdAddr::new(0x10_0000),
+                end: UffdAddr::new(0x20_0000),
+            },
+            Event::Unknown {
+                event: 0x7f,
+                raw: [0x7f; crate::UFFD_MSG_SIZE],
+            },
+        ];
+        for event in &events {
+            let json = serde_json::to_string(event).unwrap();
+            let decoded = serde_json::from_str::<Event>(&json).unwrap();
+            assert_eq!(format!("{:?}", decoded), format!("{:?}", event));
+        }
+
+        // The descriptor of a fork event can't be serialized. Any descriptor will do to build one.
+        let null = File::open("/dev/null").unwrap();
+        let uffd = unsafe { Uffd::from_raw_fd(null.into_raw_fd()) };
+        assert!(serde_json::to_string(&Event::Fork { uffd }).is_err());
+
+        #[cfg(not(feature = "linux4_14"))]
+        assert_eq!(
+            serde_json::to_string(&events[0]).unwrap(),
+            r#"{"Pagefault":{"kind":"Missing","rw":"Write","flags":["WRITE","0x100"],"addr":1048576}}"#
+        );
+    }
+}
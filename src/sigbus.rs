@@ -0,0 +1,270 @@
+//! Resolving faults in-thread with [`FeatureFlags::SIGBUS`](crate::FeatureFlags::SIGBUS).
+//!
+//! When a `Uffd` is created with `FeatureFlags::SIGBUS`, the kernel does not queue a page fault
+//! event for a registered range. Instead, it sends `SIGBUS` to the faulting thread. This module
+//! installs a process-wide `SIGBUS` handler that looks up the faulting address among the ranges
+//! registered with [`register`], and calls the resolver that was given for that range. The
+//! resolver is expected to populate the page, usually with [`Uffd::copy`] or [`Uffd::zeropage`],
+//! before the faulting access is retried.
+//!
+//! Signals for addresses outside of any registered range are forwarded to the handler that was
+//! installed before [`install`] was called, or to the default action if there was none.
+//!
+//! ```no_run
+//! # use userfaultfd::{sigbus, FeatureFlags, Uffd, UffdBuilder};
+//! # use libc::c_void;
+//! static PAGE: [u8; 4096] = [0x2a; 4096];
+//!
+//! fn resolve(uffd: &Uffd, fault: &sigbus::SigbusFault) -> bool {
+//!     let page = (fault.addr as usize & !(PAGE.len() - 1)) as *mut c_void;
+//!     unsafe { uffd.copy(PAGE.as_ptr() as *const c_void, page, PAGE.len(), true).is_ok() }
+//! }
+//!
+//! # fn main() -> userfaultfd::Result<()> {
+//! # let (mapping, len) = (std::ptr::null_mut(), 4096);
+//! let uffd = UffdBuilder::new()
+//!     .require_features(FeatureFlags::SIGBUS)
+//!     .create()?;
+//! sigbus::install()?;
+//! let _region = unsafe { sigbus::register(&uffd, mapping, len, resolve)? };
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::error::Result;
+use crate::signal::{self, ErrnoGuard};
+use crate::Uffd;
+use libc::{self, c_int, c_void};
+use nix::errno::Errno;
+use std::mem::{self, ManuallyDrop, MaybeUninit};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::ptr;
+use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+use std::sync::{Mutex, Once};
+
+/// The maximum number of ranges that can be registered at the same time.
+pub const MAX_REGIONS: usize = 64;
+
+/// A fault delivered to a [`Resolver`].
+#[derive(Debug)]
+pub struct SigbusFault {
+    /// The address that triggered the fault.
+    pub addr: *mut c_void,
+    /// The start of the registered range containing `addr`.
+    pub region_start: *mut c_void,
+    /// The length of the registered range containing `addr`.
+    pub region_len: usize,
+}
+
+/// A function that resolves a fault in a range registered with [`register`].
+///
+/// The resolver runs inside the signal handler, so it must be async-signal-safe: it must not
+/// allocate, take locks, or call anything that might. The ioctls of [`Uffd`] are safe to call,
+/// except with the `tracing` feature, which runs the subscriber inside them; don't enable it when
+/// using this module. `errno` is restored when the handler returns.
+///
+/// The resolver returns `true` if the page was populated and the faulting access should be
+/// retried. Note that another thread may have resolved the same page concurrently, in which case
+/// [`Uffd::copy`] fails with `EEXIST`; this should be treated as success. If the resolver returns
+/// `false`, the signal is forwarded as if the address was not registered.
+pub type Resolver = fn(uffd: &Uffd, fault: &SigbusFault) -> bool;
+
+struct Slot {
+    start: AtomicUsize,
+    // A length of zero marks the slot as free. It is written last on registration and first on
+    // unregistration, so the signal handler never observes a partially written slot.
+    len: AtomicUsize,
+    fd: AtomicI32,
+    resolver: AtomicUsize,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_SLOT: Slot = Slot {
+    start: AtomicUsize::new(0),
+    len: AtomicUsize::new(0),
+    fd: AtomicI32::new(-1),
+    resolver: AtomicUsize::new(0),
+};
+
+static SLOTS: [Slot; MAX_REGIONS] = [EMPTY_SLOT; MAX_REGIONS];
+
+// Serializes registration; the signal handler only ever reads the slots.
+static REGISTRY_LOCK: Mutex<()> = Mutex::new(());
+
+static INSTALL: Once = Once::new();
+static mut INSTALL_RESULT: c_int = 0;
+static mut PREVIOUS: MaybeUninit<libc::sigaction> = MaybeUninit::uninit();
+
+/// Install the process-wide `SIGBUS` handler.
+///
+/// This is idempotent; only the first call installs the handler, and later calls return the
+/// result of the first one. The handler that was installed before is remembered and receives
+/// all signals that are not resolved by this module.
+pub fn install() -> Result<()> {
+    INSTALL.call_once(|| unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = handler as extern "C" fn(_, _, _) as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_NODEFER | libc::SA_ONSTACK;
+        libc::sigemptyset(&mut action.sa_mask);
+
+        let previous = ptr::addr_of_mut!(PREVIOUS) as *mut libc::sigaction;
+        if libc::sigaction(libc::SIGBUS, &action, previous) != 0 {
+            INSTALL_RESULT = Errno::last() as c_int;
+        }
+    });
+
+    match unsafe { INSTALL_RESULT } {
+        0 => Ok(()),
+        errno => Err(Errno::from_i32(errno).into()),
+    }
+}
+
+/// A range registered for in-thread fault resolution.
+///
+/// Dropping this unregisters the range, both from this module and from the `Uffd` object.
+#[derive(Debug)]
+pub struct SigbusRegion<'a> {
+    slot: usize,
+    start: *mut c_void,
+    len: usize,
+    uffd: &'a Uffd,
+}
+
+/// Register a memory range with `uffd` and resolve its faults in-thread with `resolver`.
+///
+/// The `Uffd` object must have been created with
+/// [`FeatureFlags::SIGBUS`](crate::FeatureFlags::SIGBUS), and [`install`] must have been called
+/// for faults to reach the resolver.
+///
+/// Returns `ENOSPC` if [`MAX_REGIONS`] ranges are already registered.
+///
+/// # Safety
+///
+/// `start` and `len` must describe a mapping that stays valid until the returned
+/// [`SigbusRegion`] is dropped, and no fault in the range may still be in progress when it is.
+pub unsafe fn register<'a>(
+    uffd: &'a Uffd,
+    start: *mut c_void,
+    len: usize,
+    resolver: Resolver,
+) -> Result<SigbusRegion<'a>> {
+    let _guard = REGISTRY_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    let slot = SLOTS
+        .iter()
+        .position(|slot| slot.len.load(Ordering::Acquire) == 0)
+        .ok_or(Errno::ENOSPC)?;
+
+    uffd.register(start, len)?;
+
+    let entry = &SLOTS[slot];
+    entry.start.store(start as usize, Ordering::Relaxed);
+    entry.fd.store(uffd.as_raw_fd(), Ordering::Relaxed);
+    entry.resolver.store(resolver as usize, Ordering::Relaxed);
+    entry.len.store(len, Ordering::Release);
+
+    Ok(SigbusRegion {
+        slot,
+        start,
+        len,
+        uffd,
+    })
+}
+
+impl Drop for SigbusRegion<'_> {
+    fn drop(&mut self) {
+        let _guard = REGISTRY_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+        SLOTS[self.slot].len.store(0, Ordering::Release);
+
+        // The range may already be unmapped, in which case the kernel has unregistered it.
+        let _ = self.uffd.unregister(self.start, self.len);
+    }
+}
+
+extern "C" fn handler(signum: c_int, info: *mut libc::siginfo_t, context: *mut c_void) {
+    let _errno = ErrnoGuard::new();
+    let addr = unsafe { (*info).si_addr() } as usize;
+
+    for entry in SLOTS.iter() {
+        let len = entry.len.load(Ordering::Acquire);
+        if len == 0 {
+            continue;
+        }
+        let start = entry.start.load(Ordering::Relaxed);
+        if addr < start || addr - start >= len {
+            continue;
+        }
+
+        let resolver: Resolver = unsafe { mem::transmute(entry.resolver.load(Ordering::Relaxed)) };
+        // The descriptor is owned by the registered `Uffd` object; never close it from here.
+        let uffd =
+            ManuallyDrop::new(unsafe { Uffd::from_raw_fd(entry.fd.load(Ordering::Relaxed)) });
+        let fault = SigbusFault {
+            addr: addr as *mut c_void,
+            region_start: start as *mut c_void,
+            region_len: len,
+        };
+        if resolver(&uffd, &fault) {
+            return;
+        }
+        break;
+    }
+
+    unsafe {
+        let previous = &*(ptr::addr_of!(PREVIOUS) as *const libc::sigaction);
+        signal::forward(previous, signum, info, context)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{FeatureFlags, UffdBuilder};
+
+    const PAGE_SIZE: usize = 4096;
+
+    static PAGE: [u8; PAGE_SIZE] = [0x2a; PAGE_SIZE];
+
+    fn resolve(uffd: &Uffd, fault: &SigbusFault) -> bool {
+        let page = (fault.addr as usize & !(PAGE_SIZE - 1)) as *mut c_void;
+        unsafe { uffd.copy(PAGE.as_ptr() as *const c_void, page, PAGE_SIZE, true) }.is_ok()
+    }
+
+    #[test]
+    fn test_sigbus_resolve() -> Result<()> {
+        const PAGES: usize = 4;
+
+        unsafe {
+            let uffd = UffdBuilder::new()
+                .require_features(FeatureFlags::SIGBUS)
+                .close_on_exec(true)
+                .create()?;
+
+            let mapping = libc::mmap(
+                ptr::null_mut(),
+                PAGE_SIZE * PAGES,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANON,
+                -1,
+                0,
+            );
+
+            assert!(!mapping.is_null());
+
+            install()?;
+            let region = register(&uffd, mapping, PAGE_SIZE * PAGES, resolve)?;
+
+            for i in 0..PAGES {
+                let ptr = (mapping as *const u8).add(PAGE_SIZE * i + 7);
+                assert_eq!(ptr::read_volatile(ptr), 0x2a);
+            }
+
+            drop(region);
+
+            assert_eq!(libc::munmap(mapping, PAGE_SIZE * PAGES), 0);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,57 @@
+//! Helpers shared by the signal handlers of this crate.
+
+use libc::{self, c_int, c_void};
+use std::mem;
+use std::ptr;
+
+/// Saves `errno` when created and restores it when dropped, so that a signal handler doesn't
+/// change the `errno` seen by the code it interrupted.
+pub(crate) struct ErrnoGuard(c_int);
+
+impl ErrnoGuard {
+    pub(crate) fn new() -> Self {
+        ErrnoGuard(unsafe { *libc::__errno_location() })
+    }
+}
+
+impl Drop for ErrnoGuard {
+    fn drop(&mut self) {
+        unsafe { *libc::__errno_location() = self.0 };
+    }
+}
+
+/// Forward a signal that wasn't handled by this crate to `previous`, the action that was
+/// installed before.
+///
+/// # Safety
+///
+/// Must be called from a signal handler, with the arguments it received.
+pub(crate) unsafe fn forward(
+    previous: &libc::sigaction,
+    signum: c_int,
+    info: *mut libc::siginfo_t,
+    context: *mut c_void,
+) {
+    match previous.sa_sigaction {
+        libc::SIG_IGN => {}
+        libc::SIG_DFL => {
+            // The default action of `SIGSEGV` and `SIGBUS` terminates the process. Re-raise the
+            // signal with that action rather than returning, since a signal sent with `kill(2)`
+            // is not raised again by re-executing the interrupted instruction.
+            libc::signal(signum, libc::SIG_DFL);
+            let mut set = mem::zeroed();
+            libc::sigemptyset(&mut set);
+            libc::sigaddset(&mut set, signum);
+            libc::pthread_sigmask(libc::SIG_UNBLOCK, &set, ptr::null_mut());
+            libc::raise(signum);
+        }
+        f if previous.sa_flags & libc::SA_SIGINFO != 0 => {
+            let f: extern "C" fn(c_int, *mut libc::siginfo_t, *mut c_void) = mem::transmute(f);
+            f(signum, info, context);
+        }
+        f => {
+            let f: extern "C" fn(c_int) = mem::transmute(f);
+            f(signum);
+        }
+    }
+}
@@ -0,0 +1,678 @@
+//! Opt-in fault statistics and latency metrics.
+//!
+//! [`InstrumentedUffd`] wraps a [`Uffd`] and records, for every call made through it, how many
+//! events of each type were read, how long each resolving ioctl took, how many bytes were copied
+//! and zeroed, and how often an ioctl had to be retried because of `EAGAIN` or `EEXIST`. A
+//! consistent view of the counters is available at any time with [`InstrumentedUffd::stats`].
+//!
+//! With the `metrics` Cargo feature enabled, [`StatsSnapshot::export`] publishes a snapshot
+//! through the [`metrics`](https://docs.rs/metrics) facade.
+
+use crate::error::{Error, Result};
+#[cfg(feature = "linux5_13")]
+use crate::ContinueMode;
+#[cfg(feature = "linux5_7")]
+use crate::WriteProtectMode;
+use crate::{
+    CopyMode, Event, EventBuffer, FaultKind, IoctlFlags, ReadWrite, RegisterMode, Uffd,
+    ZeropageMode,
+};
+use libc::c_void;
+use nix::errno::Errno;
+use std::convert::TryFrom;
+use std::os::fd::{AsFd, BorrowedFd};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// The number of buckets in a [`LatencyHistogram`].
+///
+/// Bucket `i` counts the samples that took less than `2^i` nanoseconds (and at least `2^(i-1)`),
+/// with the last bucket also counting everything slower.
+pub const LATENCY_BUCKETS: usize = 32;
+
+/// The resolving operations for which latency is recorded.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Operation {
+    /// `UFFDIO_COPY`.
+    Copy,
+    /// `UFFDIO_ZEROPAGE`.
+    Zeropage,
+    /// `UFFDIO_WAKE`.
+    Wake,
+    /// `UFFDIO_WRITEPROTECT`.
+    WriteProtect,
+    /// `UFFDIO_CONTINUE`.
+    Continue,
+}
+
+impl Operation {
+    /// All operations, in the order used for indexing.
+    pub const ALL: [Operation; 5] = [
+        Operation::Copy,
+        Operation::Zeropage,
+        Operation::Wake,
+        Operation::WriteProtect,
+        Operation::Continue,
+    ];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    /// A short lowercase name for the operation, suitable as a metric label.
+    pub fn name(self) -> &'static str {
+        match self {
+            Operation::Copy => "copy",
+            Operation::Zeropage => "zeropage",
+            Operation::Wake => "wake",
+            Operation::WriteProtect => "write_protect",
+            Operation::Continue => "continue",
+        }
+    }
+}
+
+/// A snapshot of the latency distribution of one [`Operation`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LatencyHistogram {
+    /// Sample counts per power-of-two bucket; see [`LATENCY_BUCKETS`].
+    pub buckets: [u64; LATENCY_BUCKETS],
+    /// The total number of samples.
+    pub count: u64,
+    /// The sum of all samples, in nanoseconds.
+    pub sum_ns: u64,
+}
+
+impl LatencyHistogram {
+    /// The mean latency, or `None` if there are no samples.
+    pub fn mean(&self) -> Option<Duration> {
+        self.sum_ns
+            .checked_div(self.count)
+            .map(Duration::from_nanos)
+    }
+
+    /// An upper bound for the latency at quantile `q` (between `0.0` and `1.0`), or `None` if
+    /// there are no samples.
+    ///
+    /// The result is the upper edge of the bucket the quantile falls into, so it is accurate to
+    /// within a factor of two.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((self.count as f64 * q.clamp(0.0, 1.0)).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, &n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return Some(Duration::from_nanos(1 << i));
+            }
+        }
+        Some(Duration::from_nanos(1 << (LATENCY_BUCKETS - 1)))
+    }
+}
+
+/// A snapshot of the counters of an [`InstrumentedUffd`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StatsSnapshot {
+    /// `Event::Pagefault` events read.
+    pub pagefaults: u64,
+    /// Page faults with `FaultKind::Missing`.
+    pub missing_faults: u64,
+    /// Page faults with `FaultKind::WriteProtected`.
+    pub write_protect_faults: u64,
+    /// Page faults with `FaultKind::Minor`.
+    pub minor_faults: u64,
+    /// Page faults with `ReadWrite::Read`.
+    pub read_faults: u64,
+    /// Page faults with `ReadWrite::Write`.
+    pub write_faults: u64,
+    /// `Event::Fork` events read.
+    pub forks: u64,
+    /// `Event::Remap` events read.
+    pub remaps: u64,
+    /// `Event::Remove` events read.
+    pub removes: u64,
+    /// `Event::Unmap` events read.
+    pub unmaps: u64,
+    /// `Event::Unknown` events read.
+    pub unknown_events: u64,
+    /// Bytes installed with `UFFDIO_COPY`, including partial copies.
+    pub bytes_copied: u64,
+    /// Bytes installed with `UFFDIO_ZEROPAGE`.
+    pub bytes_zeroed: u64,
+    /// Resolving ioctls that failed with or partially completed because of `EAGAIN`.
+    pub eagain: u64,
+    /// Resolving ioctls that failed with `EEXIST`.
+    pub eexist: u64,
+    latencies: [LatencyHistogram; 5],
+}
+
+impl StatsSnapshot {
+    /// The latency distribution of `op`.
+    pub fn latency(&self, op: Operation) -> &LatencyHistogram {
+        &self.latencies[op.index()]
+    }
+
+    /// Publish this snapshot through the `metrics` facade.
+    ///
+    /// Counters are set to their absolute values, so this can be called periodically with fresh
+    /// snapshots. Latencies are exported as the `uffd_op_latency_seconds` histogram as they are
+    /// recorded, not by this method.
+    #[cfg(feature = "metrics")]
+    pub fn export(&self) {
+        use metrics::counter;
+
+        let faults = [
+            ("missing", self.missing_faults),
+            ("write_protected", self.write_protect_faults),
+            ("minor", self.minor_faults),
+        ];
+        for (kind, n) in faults {
+            counter!("uffd_pagefaults_total", "kind" => kind).absolute(n);
+        }
+        counter!("uffd_pagefaults_rw_total", "rw" => "read").absolute(self.read_faults);
+        counter!("uffd_pagefaults_rw_total", "rw" => "write").absolute(self.write_faults);
+
+        let events = [
+            ("pagefault", self.pagefaults),
+            ("fork", self.forks),
+            ("remap", self.remaps),
+            ("remove", self.removes),
+            ("unmap", self.unmaps),
+            ("unknown", self.unknown_events),
+        ];
+        for (event, n) in events {
+            counter!("uffd_events_total", "event" => event).absolute(n);
+        }
+
+        counter!("uffd_bytes_copied_total").absolute(self.bytes_copied);
+        counter!("uffd_bytes_zeroed_total").absolute(self.bytes_zeroed);
+        counter!("uffd_retries_total", "errno" => "EAGAIN").absolute(self.eagain);
+        counter!("uffd_retries_total", "errno" => "EEXIST").absolute(self.eexist);
+
+        for op in Operation::ALL {
+            counter!("uffd_ops_total", "op" => op.name()).absolute(self.latency(op).count);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS],
+    count: AtomicU64,
+    sum_ns: AtomicU64,
+}
+
+impl Histogram {
+    fn record(&self, elapsed: Duration) {
+        let ns = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        let bucket = ((u64::BITS - ns.leading_zeros()) as usize).min(LATENCY_BUCKETS - 1);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_ns.fetch_add(ns, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> LatencyHistogram {
+        let mut buckets = [0; LATENCY_BUCKETS];
+        for (out, bucket) in buckets.iter_mut().zip(self.buckets.iter()) {
+            *out = bucket.load(Ordering::Relaxed);
+        }
+        LatencyHistogram {
+            buckets,
+            count: self.count.load(Ordering::Relaxed),
+            sum_ns: self.sum_ns.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    pagefaults: AtomicU64,
+    missing_faults: AtomicU64,
+    write_protect_faults: AtomicU64,
+    minor_faults: AtomicU64,
+    read_faults: AtomicU64,
+    write_faults: AtomicU64,
+    forks: AtomicU64,
+    remaps: AtomicU64,
+    removes: AtomicU64,
+    unmaps: AtomicU64,
+    unknown_events: AtomicU64,
+    bytes_copied: AtomicU64,
+    bytes_zeroed: AtomicU64,
+    eagain: AtomicU64,
+    eexist: AtomicU64,
+    latencies: [Histogram; 5],
+}
+
+fn bump(counter: &AtomicU64, n: u64) {
+    counter.fetch_add(n, Ordering::Relaxed);
+}
+
+/// A [`Uffd`] that records statistics about the calls made through it.
+///
+/// The wrapper exposes the same methods as `Uffd`. Calls made directly on the inner object, for
+/// example through [`InstrumentedUffd::inner`], are not recorded.
+pub struct InstrumentedUffd {
+    uffd: Uffd,
+    counters: Counters,
+}
+
+impl InstrumentedUffd {
+    /// Wrap `uffd`, starting with all counters at zero.
+    pub fn new(uffd: Uffd) -> Self {
+        InstrumentedUffd {
+            uffd,
+            counters: Counters::default(),
+        }
+    }
+
+    /// The wrapped `Uffd` object.
+    pub fn inner(&self) -> &Uffd {
+        &self.uffd
+    }
+
+    /// Unwrap the `Uffd` object, discarding the statistics.
+    pub fn into_inner(self) -> Uffd {
+        self.uffd
+    }
+
+    /// Take a snapshot of the statistics recorded so far.
+    pub fn stats(&self) -> StatsSnapshot {
+        let c = &self.counters;
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        StatsSnapshot {
+            pagefaults: load(&c.pagefaults),
+            missing_faults: load(&c.missing_faults),
+            write_protect_faults: load(&c.write_protect_faults),
+            minor_faults: load(&c.minor_faults),
+            read_faults: load(&c.read_faults),
+            write_faults: load(&c.write_faults),
+            forks: load(&c.forks),
+            remaps: load(&c.remaps),
+            removes: load(&c.removes),
+            unmaps: load(&c.unmaps),
+            unknown_events: load(&c.unknown_events),
+            bytes_copied: load(&c.bytes_copied),
+            bytes_zeroed: load(&c.bytes_zeroed),
+            eagain: load(&c.eagain),
+            eexist: load(&c.eexist),
+            latencies: [
+                c.latencies[0].snapshot(),
+                c.latencies[1].snapshot(),
+                c.latencies[2].snapshot(),
+                c.latencies[3].snapshot(),
+                c.latencies[4].snapshot(),
+            ],
+        }
+    }
+
+    fn timed<T>(&self, op: Operation, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        let start = Instant::now();
+        let result = f();
+        let elapsed = start.elapsed();
+        self.counters.latencies[op.index()].record(elapsed);
+        #[cfg(feature = "metrics")]
+        metrics::histogram!("uffd_op_latency_seconds", "op" => op.name()).record(elapsed);
+
+        match &result {
+            Err(Error::PartiallyCopied(_)) => bump(&self.counters.eagain, 1),
+            Err(Error::CopyFailed(errno))
+            | Err(Error::ZeropageFailed(errno))
+            | Err(Error::SystemError(errno)) => match *errno {
+                Errno::EAGAIN => bump(&self.counters.eagain, 1),
+                Errno::EEXIST => bump(&self.counters.eexist, 1),
+                _ => {}
+            },
+            _ => {}
+        }
+        result
+    }
+
+    fn count_event(&self, event: &Event) {
+        let c = &self.counters;
+        match event {
+            Event::Pagefault { kind, rw, .. } => {
+                bump(&c.pagefaults, 1);
+                match kind {
+                    FaultKind::Missing => bump(&c.missing_faults, 1),
+                    #[cfg(feature = "linux5_7")]
+                    FaultKind::WriteProtected => bump(&c.write_protect_faults, 1),
+                    #[cfg(feature = "linux5_13")]
+                    FaultKind::Minor => bump(&c.minor_faults, 1),
+                }
+                match rw {
+                    ReadWrite::Read => bump(&c.read_faults, 1),
+                    ReadWrite::Write => bump(&c.write_faults, 1),
+                }
+            }
+            Event::Fork { .. } => bump(&c.forks, 1),
+            Event::Remap { .. } => bump(&c.remaps, 1),
+            Event::Remove { .. } => bump(&c.removes, 1),
+            Event::Unmap { .. } => bump(&c.unmaps, 1),
+            Event::Unknown { .. } => bump(&c.unknown_events, 1),
+        }
+    }
+
+    /// See [`Uffd::register`].
+    pub fn register(&self, start: *mut c_void, len: usize) -> Result<IoctlFlags> {
+        self.uffd.register(start, len)
+    }
+
+    /// See [`Uffd::register_with_mode`].
+    pub fn register_with_mode(
+        &self,
+        start: *mut c_void,
+        len: usize,
+        mode: RegisterMode,
+    ) -> Result<IoctlFlags> {
+        self.uffd.register_with_mode(start, len, mode)
+    }
+
+    /// See [`Uffd::unregister`].
+    pub fn unregister(&self, start: *mut c_void, len: usize) -> Result<()> {
+        self.uffd.unregister(start, len)
+    }
+
+    /// See [`Uffd::copy`].
+    ///
+    /// # Safety
+    ///
+    /// See [`Uffd::copy_with_mode`].
+    pub unsafe fn copy(
+        &self,
+        src: *const c_void,
+        dst: *mut c_void,
+        len: usize,
+        wake: bool,
+    ) -> Result<usize> {
+        let mode = if wake {
+            CopyMode::empty()
+        } else {
+            CopyMode::DONTWAKE
+        };
+        self.copy_with_mode(src, dst, len, mode)
+    }
+
+    /// See [`Uffd::copy_with_mode`].
+    ///
+    /// # Safety
+    ///
+    /// See [`Uffd::copy_with_mode`].
+    pub unsafe fn copy_with_mode(
+        &self,
+        src: *const c_void,
+        dst: *mut c_void,
+        len: usize,
+        mode: CopyMode,
+    ) -> Result<usize> {
+        let result = self.timed(Operation::Copy, || {
+            self.uffd.copy_with_mode(src, dst, len, mode)
+        });
+        match result {
+            Ok(n) | Err(Error::PartiallyCopied(n)) => bump(&self.counters.bytes_copied, n as u64),
+            _ => {}
+        }
+        result
+    }
+
+    /// See [`Uffd::zeropage`].
+    ///
+    /// # Safety
+    ///
+    /// See [`Uffd::zeropage_with_mode`].
+    pub unsafe fn zeropage(&self, start: *mut c_void, len: usize, wake: bool) -> Result<usize> {
+        let mode = if wake {
+            ZeropageMode::empty()
+        } else {
+            ZeropageMode::DONTWAKE
+        };
+        self.zeropage_with_mode(start, len, mode)
+    }
+
+    /// See [`Uffd::zeropage_with_mode`].
+    ///
+    /// # Safety
+    ///
+    /// See [`Uffd::zeropage_with_mode`].
+    pub unsafe fn zeropage_with_mode(
+        &self,
+        start: *mut c_void,
+        len: usize,
+        mode: ZeropageMode,
+    ) -> Result<usize> {
+        let result = self.timed(Operation::Zeropage, || {
+            self.uffd.zeropage_with_mode(start, len, mode)
+        });
+        if let Ok(n) = result {
+            bump(&self.counters.bytes_zeroed, n as u64);
+        }
+        result
+    }
+
+    /// See [`Uffd::wake`].
+    pub fn wake(&self, start: *mut c_void, len: usize) -> Result<()> {
+        self.timed(Operation::Wake, || self.uffd.wake(start, len))
+    }
+
+    /// See [`Uffd::write_protect`].
+    #[cfg(feature = "linux5_7")]
+    pub fn write_protect(&self, start: *mut c_void, len: usize) -> Result<()> {
+        self.write_protect_with_mode(start, len, WriteProtectMode::WRITE_PROTECT)
+    }
+
+    /// See [`Uffd::remove_write_protection`].
+    #[cfg(feature = "linux5_7")]
+    pub fn remove_write_protection(
+        &self,
+        start: *mut c_void,
+        len: usize,
+        wake: bool,
+    ) -> Result<()> {
+        let mode = if wake {
+            WriteProtectMode::empty()
+        } else {
+            WriteProtectMode::DONTWAKE
+        };
+        self.write_protect_with_mode(start, len, mode)
+    }
+
+    /// See [`Uffd::write_protect_with_mode`].
+    #[cfg(feature = "linux5_7")]
+    pub fn write_protect_with_mode(
+        &self,
+        start: *mut c_void,
+        len: usize,
+        mode: WriteProtectMode,
+    ) -> Result<()> {
+        self.timed(Operation::WriteProtect, || {
+            self.uffd.write_protect_with_mode(start, len, mode)
+        })
+    }
+
+    /// See [`Uffd::r#continue`].
+    #[cfg(feature = "linux5_13")]
+    pub fn r#continue(&self, start: *mut c_void, len: usize, wake: bool) -> Result<u64> {
+        let mode = if wake {
+            ContinueMode::empty()
+        } else {
+            ContinueMode::DONTWAKE
+        };
+        self.continue_with_mode(start, len, mode)
+    }
+
+    /// See [`Uffd::continue_with_mode`].
+    #[cfg(feature = "linux5_13")]
+    pub fn continue_with_mode(
+        &self,
+        start: *mut c_void,
+        len: usize,
+        mode: ContinueMode,
+    ) -> Result<u64> {
+        let result = self.timed(Operation::Continue, || {
+            self.uffd.continue_with_mode(start, len, mode)
+        });
+        if let Ok(mapped) = result {
+            if mapped < len as u64 {
+                bump(&self.counters.eagain, 1);
+            }
+        }
+        result
+    }
+
+    /// See [`Uffd::read_event`].
+    pub fn read_event(&self) -> Result<Option<Event>> {
+        let event = self.uffd.read_event()?;
+        if let Some(event) = &event {
+            self.count_event(event);
+        }
+        Ok(event)
+    }
+
+    /// See [`Uffd::read_events`].
+    pub fn read_events<'a>(
+        &'a self,
+        buf: &'a mut EventBuffer,
+    ) -> Result<impl Iterator<Item = Result<Event>> + 'a> {
+        Ok(self.uffd.read_events(buf)?.inspect(move |event| {
+            if let Ok(event) = event {
+                self.count_event(event);
+            }
+        }))
+    }
+}
+
+impl AsFd for InstrumentedUffd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.uffd.as_fd()
+    }
+}
+
+impl AsRawFd for InstrumentedUffd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.uffd.as_raw_fd()
+    }
+}
+
+impl From<Uffd> for InstrumentedUffd {
+    fn from(uffd: Uffd) -> Self {
+        InstrumentedUffd::new(uffd)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::UffdBuilder;
+    use std::ptr;
+    use std::thread;
+
+    #[test]
+    fn test_histogram_buckets() {
+        let histogram = Histogram::default();
+        histogram.record(Duration::from_nanos(0));
+        histogram.record(Duration::from_nanos(1));
+        histogram.record(Duration::from_nanos(1000));
+        histogram.record(Duration::from_secs(100));
+
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count, 4);
+        assert_eq!(snapshot.buckets[0], 1);
+        assert_eq!(snapshot.buckets[1], 1);
+        assert_eq!(snapshot.buckets[10], 1);
+        assert_eq!(snapshot.buckets[LATENCY_BUCKETS - 1], 1);
+        assert_eq!(snapshot.quantile(0.5), Some(Duration::from_nanos(2)));
+        assert_eq!(snapshot.quantile(0.75), Some(Duration::from_nanos(1024)));
+        assert_eq!(
+            LatencyHistogram {
+                buckets: [0; LATENCY_BUCKETS],
+                count: 0,
+                sum_ns: 0
+            }
+            .mean(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_instrumented_faults() -> Result<()> {
+        const PAGE_SIZE: usize = 4096;
+        const PAGES: usize = 3;
+
+        unsafe {
+            let uffd = InstrumentedUffd::new(UffdBuilder::new().close_on_exec(true).create()?);
+
+            let mapping = libc::mmap(
+                ptr::null_mut(),
+                PAGE_SIZE * PAGES,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANON,
+                -1,
+                0,
+            );
+
+            assert!(!mapping.is_null());
+
+            uffd.register(mapping, PAGE_SIZE * PAGES)?;
+
+            let src = [1u8; PAGE_SIZE];
+            let ptr = mapping as usize;
+            let thread = thread::spawn(move || {
+                let ptr = ptr as *mut u8;
+                for i in 0..PAGES {
+                    assert_eq!(*ptr.add(i * PAGE_SIZE), if i == 0 { 1 } else { 0 });
+                }
+            });
+
+            for i in 0..PAGES {
+                match uffd.read_event()? {
+                    Some(Event::Pagefault { addr, .. }) => {
+                        assert_eq!(addr.as_usize(), mapping as usize + i * PAGE_SIZE);
+                        if i == 0 {
+                            uffd.copy(
+                                src.as_ptr() as *const c_void,
+                                addr.as_ptr(),
+                                PAGE_SIZE,
+                                true,
+                            )?;
+                            // The page is already present now.
+                            match uffd.copy(
+                                src.as_ptr() as *const c_void,
+                                addr.as_ptr(),
+                                PAGE_SIZE,
+                                true,
+                            ) {
+                                Err(Error::CopyFailed(Errno::EEXIST)) => {}
+                                r => panic!("unexpected result {:?}", r),
+                            }
+                        } else {
+                            uffd.zeropage(addr.as_ptr(), PAGE_SIZE, true)?;
+                        }
+                    }
+                    _ => panic!("unexpected event"),
+                }
+            }
+
+            thread.join().expect("failed to join thread");
+
+            let stats = uffd.stats();
+            assert_eq!(stats.pagefaults, PAGES as u64);
+            assert_eq!(stats.missing_faults, PAGES as u64);
+            assert_eq!(stats.read_faults, PAGES as u64);
+            assert_eq!(stats.write_faults, 0);
+            assert_eq!(stats.bytes_copied, PAGE_SIZE as u64);
+            assert_eq!(stats.bytes_zeroed, (PAGE_SIZE * (PAGES - 1)) as u64);
+            assert_eq!(stats.eexist, 1);
+            assert_eq!(stats.latency(Operation::Copy).count, 2);
+            assert_eq!(stats.latency(Operation::Zeropage).count, (PAGES - 1) as u64);
+            assert_eq!(stats.latency(Operation::Wake).count, 0);
+
+            uffd.unregister(mapping, PAGE_SIZE * PAGES)?;
+
+            assert_eq!(libc::munmap(mapping, PAGE_SIZE * PAGES), 0);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,566 @@
+//! Userspace swap for ranges registered in missing mode.
+//!
+//! [`Swap`] tracks where every page of a registered range lives. Cold pages are written to a swap
+//! file and dropped with `MADV_DONTNEED`; the next access then raises a missing fault, which
+//! [`Swap::resolve_fault`] serves by reading the page back and installing it with
+//! [`Uffd::copy`](crate::Uffd::copy). Pages that were never swapped out are installed with
+//! [`Uffd::zeropage`](crate::Uffd::zeropage).
+//!
+//! Pages are considered cold in the order they were last faulted in, unless the application
+//! reports accesses with [`Swap::touch`].
+//!
+//! If the `Uffd` object was created with `FeatureFlags::EVENT_REMOVE`, every `MADV_DONTNEED` on
+//! the range, including the one issued by [`Swap::evict`], blocks until the event has been read.
+//! Eviction must then run on a different thread than the one reading events. Events passed to
+//! [`Swap::handle_event`] keep the location table consistent: a page that the application
+//! discards or unmaps no longer has its swapped contents restored, and its slot is freed.
+
+use crate::backend::UffdBackend;
+use crate::error::{Error, Result};
+use crate::page_source::PageBuffer;
+use crate::{CopyMode, Event, UffdAddr, ZeropageMode};
+use libc::c_void;
+use nix::errno::Errno;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::sync::{Mutex, MutexGuard};
+
+/// Allocates page-sized slots in a swap file.
+#[derive(Debug, Default)]
+pub struct SlotAllocator {
+    next: u64,
+    free: Vec<u64>,
+}
+
+impl SlotAllocator {
+    /// Create an allocator for an empty swap file.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allocate a slot, reusing freed slots first.
+    pub fn allocate(&mut self) -> u64 {
+        self.free.pop().unwrap_or_else(|| {
+            self.next += 1;
+            self.next - 1
+        })
+    }
+
+    /// Return a slot to the allocator.
+    pub fn free(&mut self, slot: u64) {
+        self.free.push(slot);
+    }
+
+    /// The number of slots currently allocated.
+    pub fn allocated(&self) -> u64 {
+        self.next - self.free.len() as u64
+    }
+}
+
+/// Where the contents of a page are.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Location {
+    /// The page was never populated, or was discarded by the application.
+    Absent,
+    /// The page is in memory; the value is when it was last used.
+    Resident(u64),
+    /// The page was written to a slot and dropped from memory, and the `Event::Remove` of our own
+    /// `MADV_DONTNEED` may still be pending. The page stays in this state if `EVENT_REMOVE` is
+    /// not enabled.
+    Evicting(u64),
+    /// The page is in a slot of the swap file.
+    Swapped(u64),
+}
+
+struct SwapState {
+    file: File,
+    slots: SlotAllocator,
+    pages: Vec<Location>,
+    clock: u64,
+    buf: PageBuffer,
+}
+
+impl SwapState {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    // Forget the pages in `first..last` after an `Event::Remove`, or an `Event::Unmap` if
+    // `unmapped` is set.
+    fn invalidate(&mut self, first: usize, last: usize, unmapped: bool) {
+        for index in first..last {
+            match self.pages[index] {
+                Location::Swapped(slot) => {
+                    self.slots.free(slot);
+                    self.pages[index] = Location::Absent;
+                }
+                // An unmapped page is gone, even if it was being evicted.
+                Location::Evicting(slot) if unmapped => {
+                    self.slots.free(slot);
+                    self.pages[index] = Location::Absent;
+                }
+                // This is the `MADV_DONTNEED` of our own eviction.
+                Location::Evicting(slot) => self.pages[index] = Location::Swapped(slot),
+                Location::Resident(_) => self.pages[index] = Location::Absent,
+                Location::Absent => {}
+            }
+        }
+    }
+}
+
+/// Swaps the pages of a registered range to a file and faults them back in.
+///
+/// All methods take `&self`, so that eviction can run on another thread than event handling.
+pub struct Swap<'a, U: UffdBackend + ?Sized> {
+    uffd: &'a U,
+    base: usize,
+    page_size: usize,
+    state: Mutex<SwapState>,
+}
+
+impl<'a, U: UffdBackend + ?Sized> Swap<'a, U> {
+    /// Create a swap for the range of `len` bytes at `base`, which must be registered with `uffd`
+    /// in missing mode and not populated yet. Swapped pages are stored in `file`.
+    pub fn new(uffd: &'a U, base: *mut c_void, len: usize, page_size: usize, file: File) -> Self {
+        Swap {
+            uffd,
+            base: base as usize,
+            page_size,
+            state: Mutex::new(SwapState {
+                file,
+                slots: SlotAllocator::new(),
+                pages: vec![Location::Absent; len / page_size],
+                clock: 0,
+                buf: PageBuffer::new(page_size, page_size),
+            }),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, SwapState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn index_of(&self, addr: usize) -> Option<usize> {
+        let offset = addr.checked_sub(self.base)?;
+        Some(offset / self.page_size).filter(|&index| index < self.lock().pages.len())
+    }
+
+    fn page_addr(&self, index: usize) -> *mut c_void {
+        (self.base + index * self.page_size) as *mut c_void
+    }
+
+    /// The number of pages currently in memory.
+    pub fn resident_pages(&self) -> usize {
+        self.lock()
+            .pages
+            .iter()
+            .filter(|page| matches!(page, Location::Resident(_)))
+            .count()
+    }
+
+    /// The number of pages currently in the swap file.
+    pub fn swapped_pages(&self) -> usize {
+        self.lock()
+            .pages
+            .iter()
+            .filter(|page| matches!(page, Location::Swapped(_) | Location::Evicting(_)))
+            .count()
+    }
+
+    /// Handle an event read from the `Uffd` object, and return whether it concerned this range.
+    ///
+    /// Page faults are resolved with [`resolve_fault`](Swap::resolve_fault). `Event::Remove` and
+    /// `Event::Unmap` forget the pages in the affected range and free their slots.
+    pub fn handle_event(&self, event: &Event) -> Result<bool> {
+        match *event {
+            Event::Pagefault { addr, .. } => self.resolve_fault(addr),
+            Event::Remove { start, end } | Event::Unmap { start, end } => {
+                let unmapped = matches!(event, Event::Unmap { .. });
+                let mut state = self.lock();
+                let len = state.pages.len();
+                let first = start.as_usize().saturating_sub(self.base);
+                let first = ((first + self.page_size - 1) / self.page_size).min(len);
+                let last = (end.as_usize().saturating_sub(self.base) / self.page_size).min(len);
+                state.invalidate(first, last.max(first), unmapped);
+                Ok(first < last)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Resolve a missing fault at `addr`, and return whether it was in this range.
+    ///
+    /// Swapped pages are read back from the swap file, and other pages are zero-filled.
+    pub fn resolve_fault(&self, addr: UffdAddr) -> Result<bool> {
+        let index = match self.index_of(addr.as_usize()) {
+            Some(index) => index,
+            None => return Ok(false),
+        };
+        let dst = self.page_addr(index);
+
+        let mut state = self.lock();
+        let result = match state.pages[index] {
+            Location::Swapped(slot) | Location::Evicting(slot) => {
+                let SwapState { file, buf, .. } = &mut *state;
+                file.read_exact_at(buf, slot * self.page_size as u64)?;
+                unsafe {
+                    self.uffd
+                        .copy_with_mode(buf.as_ptr(), dst, self.page_size, CopyMode::empty())
+                }
+            }
+            Location::Absent | Location::Resident(_) => unsafe {
+                self.uffd
+                    .zeropage_with_mode(dst, self.page_size, ZeropageMode::empty())
+            },
+        };
+
+        match result {
+            Ok(_) => {
+                if let Location::Swapped(slot) | Location::Evicting(slot) = state.pages[index] {
+                    state.slots.free(slot);
+                }
+                let now = state.tick();
+                state.pages[index] = Location::Resident(now);
+                Ok(true)
+            }
+            // The page is still present, for example because it is being evicted. Keep its
+            // location, since the page will be dropped shortly after.
+            Err(Error::CopyFailed(Errno::EEXIST)) | Err(Error::ZeropageFailed(Errno::EEXIST)) => {
+                self.uffd.wake(dst, self.page_size)?;
+                Ok(true)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Record that the page at `addr` was used, so that it is evicted later.
+    pub fn touch(&self, addr: *mut c_void) {
+        if let Some(index) = self.index_of(addr as usize) {
+            let mut state = self.lock();
+            if let Location::Resident(_) = state.pages[index] {
+                let now = state.tick();
+                state.pages[index] = Location::Resident(now);
+            }
+        }
+    }
+
+    /// Evict up to `count` of the coldest resident pages, and return how many were evicted.
+    ///
+    /// # Safety
+    ///
+    /// No thread may write to the pages of the range while they are being evicted, or the
+    /// writes may be lost.
+    pub unsafe fn evict(&self, count: usize) -> Result<usize> {
+        let mut cold = self
+            .lock()
+            .pages
+            .iter()
+            .enumerate()
+            .filter_map(|(index, page)| match page {
+                Location::Resident(used) => Some((*used, index)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        cold.sort_unstable();
+
+        let mut evicted = 0;
+        for &(_, index) in cold.iter().take(count) {
+            if self.evict_page(index)? {
+                evicted += 1;
+            }
+        }
+        Ok(evicted)
+    }
+
+    /// Evict the page with the given index, and return whether it was resident.
+    ///
+    /// # Safety
+    ///
+    /// No thread may write to the page while it is being evicted, or the writes may be lost.
+    pub unsafe fn evict_page(&self, index: usize) -> Result<bool> {
+        let addr = self.page_addr(index);
+        {
+            let mut state = self.lock();
+            match state.pages.get(index) {
+                Some(Location::Resident(_)) => {}
+                _ => return Ok(false),
+            }
+            let slot = state.slots.allocate();
+            let page = std::slice::from_raw_parts(addr as *const u8, self.page_size);
+            if let Err(e) = state.file.write_all_at(page, slot * self.page_size as u64) {
+                state.slots.free(slot);
+                return Err(e.into());
+            }
+            state.pages[index] = Location::Evicting(slot);
+        }
+
+        // The lock must not be held here: with `FeatureFlags::EVENT_REMOVE`, this blocks until
+        // the event handler has read the resulting `Event::Remove`.
+        if libc::madvise(addr, self.page_size, libc::MADV_DONTNEED) != 0 {
+            let err = Errno::last();
+            let mut state = self.lock();
+            if let Location::Evicting(slot) = state.pages[index] {
+                state.slots.free(slot);
+                let now = state.tick();
+                state.pages[index] = Location::Resident(now);
+            }
+            return Err(err.into());
+        }
+
+        // The page is left in the `Evicting` state: `madvise` returns as soon as the event is
+        // read, which may be before the handler has processed it.
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fake::{Call, FakeUffd, Op};
+    use crate::{FeatureFlags, Uffd, UffdBuilder};
+    use std::ptr;
+    use std::thread;
+
+    const PAGE_SIZE: usize = 4096;
+    const PAGES: usize = 4;
+
+    fn swap_file(name: &str) -> File {
+        let path =
+            std::env::temp_dir().join(format!("userfaultfd-swap-{}-{}", name, std::process::id()));
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        file
+    }
+
+    fn serve(uffd: &Uffd, swap: &Swap<Uffd>, thread: &thread::JoinHandle<Vec<u8>>) -> Result<()> {
+        while !thread.is_finished() {
+            match uffd.read_event()? {
+                Some(event) => assert!(swap.handle_event(&event)?),
+                None => thread::sleep(std::time::Duration::from_millis(10)),
+            }
+        }
+        Ok(())
+    }
+
+    fn access(mapping: *mut c_void, write: bool) -> thread::JoinHandle<Vec<u8>> {
+        let ptr = mapping as usize;
+        thread::spawn(move || unsafe {
+            let ptr = ptr as *mut u8;
+            (0..PAGES)
+                .map(|i| {
+                    let byte = ptr.add(i * PAGE_SIZE + 1);
+                    if write {
+                        ptr::write_volatile(byte, i as u8 + 1);
+                    }
+                    ptr::read_volatile(byte)
+                })
+                .collect()
+        })
+    }
+
+    #[test]
+    fn test_slot_allocator() {
+        let mut slots = SlotAllocator::new();
+        assert_eq!((slots.allocate(), slots.allocate()), (0, 1));
+        slots.free(0);
+        assert_eq!(slots.allocated(), 1);
+        assert_eq!((slots.allocate(), slots.allocate()), (0, 2));
+    }
+
+    #[test]
+    fn test_unmap_frees_evicting_slots() {
+        let mut state = SwapState {
+            file: swap_file("unmap"),
+            slots: SlotAllocator::new(),
+            pages: vec![Location::Absent; 2],
+            clock: 0,
+            buf: PageBuffer::new(PAGE_SIZE, PAGE_SIZE),
+        };
+        let slots = (state.slots.allocate(), state.slots.allocate());
+        state.pages = vec![Location::Evicting(slots.0), Location::Evicting(slots.1)];
+
+        // Our own `MADV_DONTNEED` keeps the page in its slot, but an unmap frees it.
+        state.invalidate(0, 1, false);
+        state.invalidate(1, 2, true);
+        assert_eq!(state.pages, [Location::Swapped(slots.0), Location::Absent]);
+        assert_eq!(state.slots.allocated(), 1);
+    }
+
+    #[test]
+    fn test_resolve_fault_present() -> Result<()> {
+        const BASE: usize = 0x10_0000;
+
+        let uffd = FakeUffd::new();
+        let swap = Swap::new(
+            &uffd,
+            BASE as *mut c_void,
+            PAGE_SIZE * PAGES,
+            PAGE_SIZE,
+            swap_file("present"),
+        );
+
+        // A page that is still present is only woken, and keeps its location.
+        uffd.inject(Op::Zeropage, Err(Error::ZeropageFailed(Errno::EEXIST)));
+        assert!(swap.resolve_fault(UffdAddr::new(BASE + PAGE_SIZE + 8))?);
+        assert_eq!(swap.resident_pages(), 0);
+        assert!(swap.resolve_fault(UffdAddr::new(BASE))?);
+        assert_eq!(swap.resident_pages(), 1);
+        assert!(!swap.resolve_fault(UffdAddr::new(BASE + PAGE_SIZE * PAGES))?);
+
+        assert_eq!(
+            uffd.calls(),
+            vec![
+                Call::Zeropage {
+                    start: BASE + PAGE_SIZE,
+                    len: PAGE_SIZE,
+                    mode: ZeropageMode::empty(),
+                },
+                Call::Wake {
+                    start: BASE + PAGE_SIZE,
+                    len: PAGE_SIZE,
+                },
+                Call::Zeropage {
+                    start: BASE,
+                    len: PAGE_SIZE,
+                    mode: ZeropageMode::empty(),
+                },
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_swap_out_and_in() -> Result<()> {
+        unsafe {
+            let uffd = UffdBuilder::new()
+                .close_on_exec(true)
+                .non_blocking(true)
+                .create()?;
+
+            let mapping = libc::mmap(
+                ptr::null_mut(),
+                PAGE_SIZE * PAGES,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANON,
+                -1,
+                0,
+            );
+
+            assert!(!mapping.is_null());
+
+            uffd.register(mapping, PAGE_SIZE * PAGES)?;
+            let swap = Swap::new(
+                &uffd,
+                mapping,
+                PAGE_SIZE * PAGES,
+                PAGE_SIZE,
+                swap_file("out-in"),
+            );
+
+            let thread = access(mapping, true);
+            serve(&uffd, &swap, &thread)?;
+            assert_eq!(thread.join().unwrap(), vec![1, 2, 3, 4]);
+            assert_eq!(swap.resident_pages(), PAGES);
+
+            swap.touch(mapping);
+            assert_eq!(swap.evict(2)?, 2);
+            assert_eq!(swap.swapped_pages(), 2);
+            assert_eq!(swap.resident_pages(), 2);
+
+            // Page 0 was touched, so pages 1 and 2 were the coldest ones.
+            let thread = access(mapping, false);
+            serve(&uffd, &swap, &thread)?;
+            assert_eq!(thread.join().unwrap(), vec![1, 2, 3, 4]);
+            assert_eq!(swap.swapped_pages(), 0);
+            assert_eq!(swap.lock().slots.allocated(), 0);
+
+            uffd.unregister(mapping, PAGE_SIZE * PAGES)?;
+
+            assert_eq!(libc::munmap(mapping, PAGE_SIZE * PAGES), 0);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_invalidates_slots() -> Result<()> {
+        unsafe {
+            let uffd = UffdBuilder::new()
+                .require_features(FeatureFlags::EVENT_REMOVE)
+                .close_on_exec(true)
+                .non_blocking(true)
+                .create()?;
+
+            let mapping = libc::mmap(
+                ptr::null_mut(),
+                PAGE_SIZE * PAGES,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANON,
+                -1,
+                0,
+            );
+
+            assert!(!mapping.is_null());
+
+            uffd.register(mapping, PAGE_SIZE * PAGES)?;
+            let swap = Swap::new(
+                &uffd,
+                mapping,
+                PAGE_SIZE * PAGES,
+                PAGE_SIZE,
+                swap_file("remove"),
+            );
+
+            let thread = access(mapping, true);
+            serve(&uffd, &swap, &thread)?;
+            thread.join().unwrap();
+
+            // With EVENT_REMOVE, eviction blocks until the event is read, so it runs on another
+            // thread while this one handles events.
+            let ptr = mapping as usize;
+            thread::scope(|s| -> Result<()> {
+                let evictor = s.spawn(|| swap.evict(PAGES));
+                while !evictor.is_finished() {
+                    match uffd.read_event()? {
+                        Some(event) => assert!(swap.handle_event(&event)?),
+                        None => thread::sleep(std::time::Duration::from_millis(10)),
+                    }
+                }
+                assert_eq!(evictor.join().unwrap()?, PAGES);
+                Ok(())
+            })?;
+            assert_eq!(swap.swapped_pages(), PAGES);
+
+            // The application discards page 3, so its swapped contents must not come back.
+            let discard = thread::spawn(move || {
+                let page = (ptr + 3 * PAGE_SIZE) as *mut c_void;
+                libc::madvise(page, PAGE_SIZE, libc::MADV_DONTNEED)
+            });
+            while !discard.is_finished() {
+                if let Some(event) = uffd.read_event()? {
+                    assert!(swap.handle_event(&event)?);
+                }
+            }
+            assert_eq!(discard.join().unwrap(), 0);
+            assert_eq!(swap.swapped_pages(), PAGES - 1);
+
+            let thread = access(mapping, false);
+            serve(&uffd, &swap, &thread)?;
+            assert_eq!(thread.join().unwrap(), vec![1, 2, 3, 0]);
+
+            uffd.unregister(mapping, PAGE_SIZE * PAGES)?;
+
+            assert_eq!(libc::munmap(mapping, PAGE_SIZE * PAGES), 0);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,69 @@
+//! Fixtures shared by the tests of several modules.
+
+use crate::error::Result;
+use crate::{Event, Uffd, UffdAddr, UffdBuilder};
+use libc::c_void;
+use std::ptr;
+use std::thread;
+
+/// An anonymous mapping registered with a blocking `Uffd` object. Dropping it unregisters and
+/// unmaps the range.
+pub(crate) struct Registered {
+    pub(crate) uffd: Uffd,
+    pub(crate) mapping: *mut c_void,
+    pub(crate) len: usize,
+}
+
+impl Registered {
+    pub(crate) fn new(len: usize) -> Result<Self> {
+        let uffd = UffdBuilder::new()
+            .close_on_exec(true)
+            .non_blocking(false)
+            .create()?;
+
+        let mapping = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANON,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(mapping, libc::MAP_FAILED);
+
+        uffd.register(mapping, len)?;
+        Ok(Registered { uffd, mapping, len })
+    }
+
+    /// Run `access` on another thread with the address of the mapping, and resolve the first
+    /// `faults` page faults with `resolve`. Returns the result of `access`.
+    pub(crate) fn serve<T: Send + 'static>(
+        &self,
+        faults: usize,
+        access: impl FnOnce(*mut u8) -> T + Send + 'static,
+        mut resolve: impl FnMut(&Uffd, UffdAddr) -> Result<()>,
+    ) -> Result<T> {
+        let ptr = self.mapping as usize;
+        let thread = thread::spawn(move || access(ptr as *mut u8));
+
+        for _ in 0..faults {
+            match self.uffd.read_event()? {
+                Some(Event::Pagefault { addr, .. }) => resolve(&self.uffd, addr)?,
+                e => panic!("unexpected event: {:?}", e),
+            }
+        }
+
+        Ok(thread.join().expect("failed to join thread"))
+    }
+}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = self.uffd.unregister(self.mapping, self.len);
+            assert_eq!(libc::munmap(self.mapping, self.len), 0);
+        }
+    }
+}
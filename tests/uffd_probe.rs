@@ -0,0 +1,89 @@
+use serde_json::Value;
+
+/// Check that `value` is the JSON form of a probe: an object with either an `ok` or an `error`
+/// key, and return the value of `ok`.
+fn probe<'a>(name: &str, value: &'a Value) -> Option<&'a Value> {
+    let object = value
+        .as_object()
+        .unwrap_or_else(|| panic!("{} is not an object: {}", name, value));
+    match (object.get("ok"), object.get("error")) {
+        (Some(ok), None) if object.len() == 1 => Some(ok),
+        (None, Some(Value::String(_))) if object.len() == 1 => None,
+        _ => panic!("{} is not a probe: {}", name, value),
+    }
+}
+
+#[test]
+fn run_uffd_probe() {
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_uffd-probe"))
+        .arg("--json")
+        .output()
+        .expect("uffd-probe failed to start");
+    assert!(
+        output.status.success(),
+        "uffd-probe failed with {}: {}",
+        output.status,
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let report: Value = serde_json::from_slice(&output.stdout).expect("invalid JSON report");
+    let keys = report
+        .as_object()
+        .expect("the report is not an object")
+        .keys()
+        .collect::<Vec<_>>();
+    // Keys are sorted when parsed.
+    assert_eq!(
+        keys,
+        [
+            "api",
+            "cap_sys_ptrace",
+            "dev_userfaultfd",
+            "kernel",
+            "register_modes",
+            "seccomp",
+            "unprivileged_userfaultfd",
+        ]
+    );
+
+    for name in ["kernel", "unprivileged_userfaultfd", "seccomp"] {
+        if let Some(value) = probe(name, &report[name]) {
+            assert!(value.is_string(), "{} is not a string: {}", name, value);
+        }
+    }
+    if let Some(value) = probe("cap_sys_ptrace", &report["cap_sys_ptrace"]) {
+        assert!(
+            value.is_boolean(),
+            "cap_sys_ptrace is not a boolean: {}",
+            value
+        );
+    }
+    assert!(report["dev_userfaultfd"]["exists"].is_boolean());
+    if let Some(api) = probe("api", &report["api"]) {
+        assert!(api["features"].is_array() && api["ioctls"].is_array());
+    }
+
+    // Every kind of memory reports a result for each register mode, or why it couldn't be mapped.
+    let memory = report["register_modes"]
+        .as_object()
+        .expect("register_modes is not an object");
+    assert_eq!(
+        memory.keys().collect::<Vec<_>>(),
+        ["anonymous", "hugetlbfs", "shmem"]
+    );
+    for (kind, modes) in memory {
+        if let Some(modes) = probe(kind, modes) {
+            let modes = modes
+                .as_object()
+                .unwrap_or_else(|| panic!("the modes of {} are not an object", kind));
+            assert!(
+                modes.contains_key("MISSING"),
+                "{} has no MISSING mode",
+                kind
+            );
+            for (mode, result) in modes {
+                probe(&format!("{} {}", kind, mode), result);
+            }
+        }
+    }
+}
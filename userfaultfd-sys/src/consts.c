@@ -54,6 +54,14 @@ const __u64 _const_UFFDIO_WRITEPROTECT_MODE_WP = UFFDIO_WRITEPROTECT_MODE_WP;
 const __u64 _const_UFFDIO_CONTINUE_MODE_DONTWAKE = UFFDIO_CONTINUE_MODE_DONTWAKE;
 #endif
 
+#ifdef UFFDIO_CONTINUE_MODE_WP
+const __u64 _const_UFFDIO_CONTINUE_MODE_WP = UFFDIO_CONTINUE_MODE_WP;
+#endif
+
+#ifdef UFFDIO_POISON_MODE_DONTWAKE
+const __u64 _const_UFFDIO_POISON_MODE_DONTWAKE = UFFDIO_POISON_MODE_DONTWAKE;
+#endif
+
 #ifdef UFFDIO_WRITEPROTECT_MODE_DONTWAKE
 const __u64 _const_UFFDIO_WRITEPROTECT_MODE_DONTWAKE = UFFDIO_WRITEPROTECT_MODE_DONTWAKE;
 #endif
@@ -90,6 +98,10 @@ const __u32 _const_UFFDIO_WRITEPROTECT = UFFDIO_WRITEPROTECT;
 const __u32 _const_UFFDIO_CONTINUE = UFFDIO_CONTINUE;
 #endif
 
+#ifdef UFFDIO_POISON
+const __u32 _const_UFFDIO_POISON = UFFDIO_POISON;
+#endif
+
 #ifdef USERFAULTFD_IOC
 const __u32 _const_USERFAULTFD_IOC = USERFAULTFD_IOC;
 #endif
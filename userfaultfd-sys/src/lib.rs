@@ -18,8 +18,18 @@ mod linux5_7;
 #[cfg(feature = "linux5_13")]
 mod linux5_13;
 
+#[cfg(feature = "linux6_3")]
+mod linux6_3;
+
+#[cfg(feature = "linux6_6")]
+mod linux6_6;
+
 cfg_if! {
-    if #[cfg(feature = "linux5_13")] {
+    if #[cfg(feature = "linux6_6")] {
+        pub use crate::linux6_6::*;
+    } else if #[cfg(feature = "linux6_3")] {
+        pub use crate::linux6_3::*;
+    } else if #[cfg(feature = "linux5_13")] {
         pub use crate::linux5_13::*;
     } else if #[cfg(feature = "linux5_7")] {
         pub use crate::linux5_7::*;
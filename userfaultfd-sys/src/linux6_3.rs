@@ -0,0 +1,31 @@
+use super::*;
+
+pub use linux5_13::{
+    UFFDIO_API, UFFDIO_CONTINUE, UFFDIO_CONTINUE_MODE_DONTWAKE, UFFDIO_COPY,
+    UFFDIO_COPY_MODE_DONTWAKE, UFFDIO_COPY_MODE_WP, UFFDIO_REGISTER, UFFDIO_REGISTER_MODE_MINOR,
+    UFFDIO_REGISTER_MODE_MISSING, UFFDIO_REGISTER_MODE_WP, UFFDIO_UNREGISTER, UFFDIO_WAKE,
+    UFFDIO_WRITEPROTECT, UFFDIO_WRITEPROTECT_MODE_DONTWAKE, UFFDIO_WRITEPROTECT_MODE_WP,
+    UFFDIO_ZEROPAGE, UFFDIO_ZEROPAGE_MODE_DONTWAKE, UFFD_API, UFFD_API_FEATURES, UFFD_API_IOCTLS,
+    UFFD_API_RANGE_IOCTLS, UFFD_API_RANGE_IOCTLS_BASIC,
+};
+
+pub const UFFDIO_CONTINUE_MODE_WP: u64 = 1 << 1;
+
+#[cfg(test)]
+mod const_tests {
+    use super::*;
+
+    extern "C" {
+        static _const_UFFDIO_CONTINUE_MODE_WP: u64;
+    }
+
+    #[test]
+    fn consts_correct() {
+        unsafe {
+            assert_eq!(
+                UFFDIO_CONTINUE_MODE_WP, _const_UFFDIO_CONTINUE_MODE_WP,
+                "UFFDIO_CONTINUE_MODE_WP"
+            );
+        }
+    }
+}
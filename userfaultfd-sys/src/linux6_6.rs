@@ -0,0 +1,37 @@
+use super::*;
+
+pub use linux6_3::{
+    UFFDIO_API, UFFDIO_CONTINUE, UFFDIO_CONTINUE_MODE_DONTWAKE, UFFDIO_CONTINUE_MODE_WP,
+    UFFDIO_COPY, UFFDIO_COPY_MODE_DONTWAKE, UFFDIO_COPY_MODE_WP, UFFDIO_REGISTER,
+    UFFDIO_REGISTER_MODE_MINOR, UFFDIO_REGISTER_MODE_MISSING, UFFDIO_REGISTER_MODE_WP,
+    UFFDIO_UNREGISTER, UFFDIO_WAKE, UFFDIO_WRITEPROTECT, UFFDIO_WRITEPROTECT_MODE_DONTWAKE,
+    UFFDIO_WRITEPROTECT_MODE_WP, UFFDIO_ZEROPAGE, UFFDIO_ZEROPAGE_MODE_DONTWAKE, UFFD_API,
+    UFFD_API_FEATURES, UFFD_API_IOCTLS, UFFD_API_RANGE_IOCTLS_BASIC,
+};
+
+pub const UFFD_API_RANGE_IOCTLS: u64 = linux6_3::UFFD_API_RANGE_IOCTLS | 1 << _UFFDIO_POISON;
+
+pub const UFFDIO_POISON_MODE_DONTWAKE: u64 = 1 << 0;
+
+pub const UFFDIO_POISON: u32 = 0xc020aa08;
+
+#[cfg(test)]
+mod const_tests {
+    use super::*;
+
+    extern "C" {
+        static _const_UFFDIO_POISON_MODE_DONTWAKE: u64;
+        static _const_UFFDIO_POISON: u32;
+    }
+
+    #[test]
+    fn consts_correct() {
+        unsafe {
+            assert_eq!(
+                UFFDIO_POISON_MODE_DONTWAKE, _const_UFFDIO_POISON_MODE_DONTWAKE,
+                "UFFDIO_POISON_MODE_DONTWAKE"
+            );
+            assert_eq!(UFFDIO_POISON, _const_UFFDIO_POISON, "UFFDIO_POISON");
+        }
+    }
+}
@@ -10,3 +10,16 @@
 // Similarly, the ioctl() for `/dev/userfaultfd` is introduced with Linux 6.1.
 #define USERFAULTFD_IOC 0xAA
 #endif
+
+#ifndef _UFFDIO_POISON
+// Likewise, `UFFDIO_POISON` is introduced with Linux 6.6. Only what the bindings can't define
+// themselves is provided here; the ioctl number and mode are in `linux6_6.rs`.
+#define _UFFDIO_POISON (0x08)
+#define UFFD_FEATURE_POISON (1 << 14)
+
+struct uffdio_poison {
+	struct uffdio_range range;
+	__u64 mode;
+	__s64 updated;
+};
+#endif